@@ -1,173 +1,836 @@
+use crate::commands::{self, Command};
 use crate::connected_users_widget::ConnectedUsersWidget;
-use crate::input_widget::InputWidget;
-use crossterm::event::{KeyCode, MouseEvent, MouseEventKind};
+use crate::connection::ConnectionState;
+use crate::conversation::{Conversation, ConversationKind};
+use crate::conversations_widget::ConversationsWidget;
+use crate::history::{InputHistory, Recall, Search};
+use crate::input_widget::{InputWidget, inserts_line_break};
+use crate::layout::{visible_rows, wrap_message};
+use crossterm::event::{KeyCode, KeyModifiers, MouseEvent, MouseEventKind};
 use ratatui::{
     DefaultTerminal, Frame,
     layout::{Constraint, Layout, Rect},
     style::{Color, Style, Stylize},
     text::{Line, Span},
-    widgets::{Block, Padding, Paragraph, Wrap},
+    widgets::{Block, Padding, Paragraph},
 };
+use unicode_width::UnicodeWidthStr;
 
-fn calculate_scroll_to_bottom(
-    messages: &[Message],
-    available_height: u16,
-    available_width: u16,
-) -> usize {
-    if messages.is_empty() {
-        return 0;
+const TEXT_SECONDARY: Color = Color::Rgb(128, 128, 128);
+const TEXT_ERROR: Color = Color::Rgb(220, 80, 80);
+
+// The frame that sends `text` to a conversation, numbered with `client_id`
+fn chat_frame(
+    kind: ConversationKind,
+    name: &str,
+    text: &str,
+    client_id: u64,
+    action: bool,
+) -> protocol::Frame {
+    match kind {
+        ConversationKind::Room => protocol::Frame::Say {
+            room: name.to_string(),
+            text: text.to_string(),
+            client_id: Some(client_id),
+            action,
+        },
+        ConversationKind::Direct => protocol::Frame::DirectMessage {
+            to: name.to_string(),
+            text: text.to_string(),
+            client_id: Some(client_id),
+            action,
+        },
     }
+}
 
-    let mut total_lines = 0;
-    let mut messages_from_bottom = 0;
+// Describes a `ShuttingDown` frame, e.g. "Server is shutting down: upgrade. It
+// should be back in about 2 minutes."
+pub fn describe_shutdown(reason: Option<&str>, restart_in_secs: Option<u64>) -> String {
+    let mut text = match reason {
+        Some(reason) => format!("Server is shutting down: {}.", reason.trim_end_matches('.')),
+        None => "Server is shutting down.".to_string(),
+    };
+    if let Some(secs) = restart_in_secs {
+        let (count, unit) = match secs {
+            0..60 => (secs, "second"),
+            60..3600 => (secs.div_ceil(60), "minute"),
+            _ => (secs.div_ceil(3600), "hour"),
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        text.push_str(&format!(
+            " It should be back in about {} {}{}.",
+            count, unit, plural
+        ));
+    }
+    text
+}
 
-    // Work backwards from the last message to find how many fit
-    for message in messages.iter().rev() {
-        if message.author.is_empty() {
-            continue;
-        }
+// How far a message the user sent has got
+#[derive(Clone, PartialEq, Eq)]
+pub enum Delivery {
+    // Waiting to be written to the server, e.g. until the client reconnects
+    Pending,
+    // Written to the server, which hasn't answered yet
+    Sent,
+    // Delivered; the message now has the server's ID
+    Acked,
+    // Rejected by the server, or lost with the connection, for the given reason
+    Failed(String),
+}
 
-        let formatted_text = format!("{}: {}", message.author, message.content);
-        let text_width = formatted_text.len();
-        let message_lines = if text_width == 0 {
-            1
-        } else {
-            (text_width + available_width as usize - 1) / available_width as usize
-        };
+// A message the user sent, numbered so the server's answer can be matched up
+// with it
+pub struct Outgoing {
+    pub client_id: u64,
+    pub delivery: Delivery,
+}
 
-        // Add spacing (except for last message)
-        let lines_with_spacing = if messages_from_bottom > 0 {
-            message_lines + 1
-        } else {
-            message_lines
-        };
+pub struct Message {
+    // Server-assigned ID; `None` for messages that only exist on this side
+    pub id: Option<u64>,
+    // Milliseconds since the Unix epoch, UTC
+    pub timestamp: u64,
+    pub author: String,
+    pub content: String,
+    // Sent with /me, and shown as "* author content"
+    pub action: bool,
+    // Set on messages the user sent
+    pub outgoing: Option<Outgoing>,
+    // Received before the client last had to log in from scratch. The server
+    // may have restarted since, and be handing out the same IDs again.
+    pub earlier_session: bool,
+    // The rows it was last laid out as
+    layout: Option<(LayoutKey, Vec<Line<'static>>)>,
+}
 
-        // Check if adding this message exceeds available height
-        if total_lines + lines_with_spacing > available_height as usize {
-            break;
-        }
+// What a message's rows depend on, besides its text: the width, timestamp and
+// delivery marker
+type LayoutKey = (usize, u64, Option<Delivery>);
 
-        total_lines += lines_with_spacing;
-        messages_from_bottom += 1;
+impl Message {
+    pub fn from_server(id: u64, timestamp: u64, author: String, content: String) -> Self {
+        Self {
+            id: Some(id),
+            timestamp,
+            author,
+            content,
+            action: false,
+            outgoing: None,
+            earlier_session: false,
+            layout: None,
+        }
     }
 
-    // Return scroll offset to show these messages
-    messages.len() - messages_from_bottom
-}
+    // A message created by the client itself, stamped with the local clock
+    pub fn local(author: String, content: String) -> Self {
+        Self {
+            id: None,
+            timestamp: Utc::now().timestamp_millis() as u64,
+            author,
+            content,
+            action: false,
+            outgoing: None,
+            earlier_session: false,
+            layout: None,
+        }
+    }
 
-fn is_near_bottom(
-    messages: &[Message],
-    scroll_offset: usize,
-    available_height: u16,
-    available_width: u16,
-    threshold: usize,
-) -> bool {
-    if messages.is_empty() {
-        return true;
+    // A message the user is sending, not yet written to the server
+    pub fn outgoing(author: String, content: String, client_id: u64) -> Self {
+        Self {
+            outgoing: Some(Outgoing {
+                client_id,
+                delivery: Delivery::Pending,
+            }),
+            ..Self::local(author, content)
+        }
     }
 
-    // Calculate how many lines are visible from current scroll position
-    let mut visible_lines = 0;
-    let max_visible_lines = available_height as usize;
+    // Shown after a sent message's text to say how far it has got
+    pub fn marker(&self) -> Option<String> {
+        let marker = match &self.outgoing.as_ref()?.delivery {
+            Delivery::Pending => " …".to_string(),
+            Delivery::Sent => " ✓".to_string(),
+            Delivery::Acked => " ✓✓".to_string(),
+            Delivery::Failed(reason) => {
+                format!(" ✗ {} (Alt+R to retry, Alt+D to discard)", reason)
+            }
+        };
+        Some(marker)
+    }
 
-    for message in messages.iter().skip(scroll_offset) {
-        if message.author.is_empty() {
-            continue;
+    // The timestamp in the local timezone, formatted with `time_format`
+    pub fn time(&self, time_format: &str) -> String {
+        match Local.timestamp_millis_opt(self.timestamp as i64) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => {
+                time.format(time_format).to_string()
+            }
+            LocalResult::None => String::new(),
         }
+    }
 
-        let formatted_text = format!("{}: {}", message.author, message.content);
-        let text_width = formatted_text.len();
-        let message_lines = if text_width == 0 {
-            1
-        } else {
-            (text_width + available_width as usize - 1) / available_width as usize
-        };
+    // The message as it appears on screen, `width` columns wide. Laying it out
+    // is only redone when something it depends on changes.
+    pub fn lines(&mut self, time_format: &str, width: usize) -> &[Line<'static>] {
+        let key = (
+            width,
+            self.timestamp,
+            self.outgoing
+                .as_ref()
+                .map(|outgoing| outgoing.delivery.clone()),
+        );
+        if self
+            .layout
+            .as_ref()
+            .is_none_or(|(laid_out, _)| *laid_out != key)
+        {
+            self.layout = Some((key, self.wrap(time_format, width)));
+        }
+        self.layout.as_ref().map_or(&[], |(_, rows)| rows)
+    }
 
-        // Add spacing (except for first visible message)
-        let lines_with_spacing = if visible_lines > 0 {
-            message_lines + 1
+    // Rows after the first are indented to line up with the start of the text
+    fn wrap(&self, time_format: &str, width: usize) -> Vec<Line<'static>> {
+        let time = self.time(time_format);
+        let prefix = if self.action {
+            format!(" * {} ", self.author)
         } else {
-            message_lines
+            format!(" {}: ", self.author)
         };
+        let indent = time.width() + prefix.width();
 
-        visible_lines += lines_with_spacing;
-        if visible_lines >= max_visible_lines {
-            break;
+        let mut spans = vec![
+            Span::styled(time, Style::default().fg(TEXT_SECONDARY)),
+            Span::from(prefix),
+            Span::from(self.content.as_str()),
+        ];
+        if let Some(marker) = self.marker() {
+            let failed = self
+                .outgoing
+                .as_ref()
+                .is_some_and(|outgoing| matches!(outgoing.delivery, Delivery::Failed(_)));
+            let color = if failed { TEXT_ERROR } else { TEXT_SECONDARY };
+            spans.push(Span::styled(marker, Style::default().fg(color)));
         }
+        wrap_message(&spans, indent, width)
     }
-
-    // Calculate how many messages are hidden below
-    let messages_hidden_below = messages.len().saturating_sub(scroll_offset);
-    let max_visible_messages = (max_visible_lines / 2).max(1); // Rough estimate
-
-    // Auto-scroll if we're within threshold messages from the bottom
-    messages_hidden_below <= max_visible_messages + threshold
-}
-
-pub struct Message {
-    pub author: String,
-    pub content: String,
 }
 
+use chrono::{Local, LocalResult, TimeZone, Utc};
 use std::{
-    io::{self, Write},
-    net::TcpStream,
+    collections::VecDeque,
+    io,
     sync::{Arc, Mutex, mpsc},
+    time::{Duration, Instant},
 };
+use tcptalk_protocol::{self as protocol, Stream, write_frame};
 
 pub struct App {
     pub running: bool,
     pub input_widget: InputWidget,
-    pub messages: Vec<Message>,
-    pub scroll_offset: usize,
-    pub should_auto_scroll: bool,
+    pub conversations: Vec<Conversation>,
+    pub active_conversation: usize,
+    // Messages that arrive before any conversation is open
+    pub pending_messages: Vec<Message>,
+    // Rows of messages that fit on screen, as of the last draw
+    pub page_height: usize,
     pub username: String,
-    pub server_ip: String,
-    pub write_stream: Arc<Mutex<TcpStream>>,
+    pub server_addr: String,
+    // `None` while disconnected
+    pub write_stream: Arc<Mutex<Option<Stream>>>,
+    pub connection: ConnectionState,
+    // Round trip time of the last ping the server answered
+    pub latency: Option<Duration>,
     pub connected_users_widget: ConnectedUsersWidget,
-    pub has_requested_user_list: bool,
+    // strftime-style format for message timestamps
+    pub time_format: String,
+    // Numbers the user's messages, starting from 0
+    pub next_client_id: u64,
+    // Messages waiting to be written to the server, in the order they were sent
+    pub unsent: VecDeque<(u64, protocol::Frame)>,
+    pub history: InputHistory,
+    pub recall: Option<Recall>,
+    pub search: Option<Search>,
 }
 
 pub enum Event {
     Input(crossterm::event::KeyEvent),
     Mouse(crossterm::event::MouseEvent),
     CursorBlink,
+    ServerFrame(protocol::Frame),
     ServerMessage(String),
-    UserListUpdate(Vec<String>),
+    Connection(ConnectionState),
+    // Logged in from scratch after reconnecting, rather than resuming
+    NewSession,
+    Latency(Duration),
 }
 
 impl App {
-    pub fn new(username: String, server_ip: String, write_stream: Arc<Mutex<TcpStream>>) -> Self {
+    pub fn new(
+        username: String,
+        server_addr: String,
+        write_stream: Arc<Mutex<Option<Stream>>>,
+        time_format: String,
+        history: InputHistory,
+    ) -> Self {
         Self {
             running: true,
             input_widget: InputWidget::new(username.clone()),
-            messages: Vec::new(),
-            scroll_offset: 0,
-            should_auto_scroll: false,
+            conversations: Vec::new(),
+            active_conversation: 0,
+            pending_messages: Vec::new(),
+            page_height: 0,
             username,
-            server_ip,
+            server_addr,
             write_stream,
+            connection: ConnectionState::Connected,
+            latency: None,
             connected_users_widget: ConnectedUsersWidget::new(),
-            has_requested_user_list: false,
+            time_format,
+            next_client_id: 0,
+            unsent: VecDeque::new(),
+            history,
+            recall: None,
+            search: None,
         }
     }
 
+    // Adds a message to the conversation currently on screen
     pub fn add_message(&mut self, author: String, content: String) {
-        self.messages.push(Message { author, content });
+        self.push_message(Message::local(author, content));
     }
 
-    fn scroll_down(&mut self) {
-        // Don't scroll past the end of messages
-        // Maximum scroll offset is when we can still see at least one message
-        if self.scroll_offset > 0 {
-            self.scroll_offset -= 1;
+    fn push_message(&mut self, message: Message) {
+        match self.conversations.get_mut(self.active_conversation) {
+            Some(conversation) => conversation.push(message),
+            None => self.pending_messages.push(message),
         }
     }
 
-    fn scroll_up(&mut self) {
-        // Don't scroll past the beginning (can't skip more messages than we have - 1)
-        if self.scroll_offset < self.messages.len().saturating_sub(1) {
-            self.scroll_offset += 1;
+    // Adds a message to the named conversation, counting it as unread if that
+    // conversation isn't on screen
+    fn add_message_to(&mut self, kind: ConversationKind, name: &str, message: Message) {
+        let Some(index) = self.conversation_index(kind, name) else {
+            self.push_message(message);
+            return;
+        };
+
+        let conversation = &mut self.conversations[index];
+        conversation.push(message);
+        if index != self.active_conversation {
+            conversation.unread += 1;
+        }
+    }
+
+    fn conversation_index(&self, kind: ConversationKind, name: &str) -> Option<usize> {
+        self.conversations
+            .iter()
+            .position(|conversation| conversation.is(kind, name))
+    }
+
+    fn active(&self) -> Option<&Conversation> {
+        self.conversations.get(self.active_conversation)
+    }
+
+    fn open_conversation(&mut self, kind: ConversationKind, name: String) -> usize {
+        if let Some(index) = self.conversation_index(kind, &name) {
+            return index;
+        }
+
+        let mut conversation = Conversation::new(kind, name);
+        if kind == ConversationKind::Direct {
+            conversation.users = vec![conversation.name.clone(), self.username.clone()];
+        }
+        conversation.messages.append(&mut self.pending_messages);
+        self.conversations.push(conversation);
+        self.conversations.len() - 1
+    }
+
+    fn close_conversation(&mut self, kind: ConversationKind, name: &str) {
+        let Some(index) = self.conversation_index(kind, name) else {
+            return;
+        };
+
+        let was_active = index == self.active_conversation;
+        self.conversations.remove(index);
+        if index < self.active_conversation || self.active_conversation >= self.conversations.len()
+        {
+            self.active_conversation = self.active_conversation.saturating_sub(1);
+        }
+        if was_active {
+            // The closed conversation's draft is gone with it
+            self.input_widget.take_text();
+            self.show_active_conversation();
+        }
+    }
+
+    fn switch_conversation(&mut self, index: usize) {
+        if index >= self.conversations.len() || index == self.active_conversation {
+            return;
+        }
+
+        if let Some(conversation) = self.conversations.get_mut(self.active_conversation) {
+            conversation.draft = self.input_widget.take_text();
+        }
+        self.active_conversation = index;
+        self.show_active_conversation();
+    }
+
+    // Loads the active conversation's draft, users and unread state into the widgets
+    fn show_active_conversation(&mut self) {
+        self.recall = None;
+        match self.conversations.get_mut(self.active_conversation) {
+            Some(conversation) => {
+                conversation.unread = 0;
+                self.input_widget
+                    .set_text(std::mem::take(&mut conversation.draft));
+                self.input_widget.room = conversation.title();
+                self.connected_users_widget
+                    .set_users(conversation.title(), conversation.users.clone());
+            }
+            None => {
+                self.input_widget.room = String::new();
+                self.connected_users_widget
+                    .set_users(String::new(), Vec::new());
+            }
+        }
+    }
+
+    fn cycle_conversation(&mut self, forward: bool) {
+        let count = self.conversations.len();
+        if count < 2 {
+            return;
+        }
+        let next = if forward {
+            (self.active_conversation + 1) % count
+        } else {
+            (self.active_conversation + count - 1) % count
+        };
+        self.switch_conversation(next);
+    }
+
+    fn send_frame(&self, frame: &protocol::Frame) -> Result<(), String> {
+        match self.write_stream.lock() {
+            Ok(mut stream) => match &mut *stream {
+                Some(stream) => write_frame(stream, frame)
+                    .map_err(|e| format!("Failed to write to server: {}", e)),
+                None => Err("Not connected to the server.".to_string()),
+            },
+            Err(e) => Err(format!("Failed to lock stream: {}", e)),
+        }
+    }
+
+    fn handle_server_frame(&mut self, frame: protocol::Frame) {
+        match frame {
+            protocol::Frame::Chat {
+                id,
+                timestamp,
+                room,
+                author,
+                text,
+                client_id,
+                action,
+            } => {
+                // The user's own message coming back, in its place among everyone else's
+                if client_id.is_some_and(|client_id| self.reconcile(client_id, id, timestamp)) {
+                    return;
+                }
+                // A message sent while we were joining can arrive both live and
+                // in the history replay
+                let duplicate = self
+                    .conversation_index(ConversationKind::Room, &room)
+                    .is_some_and(|index| self.conversations[index].contains(id));
+                if !duplicate {
+                    let message = Message {
+                        action,
+                        ..Message::from_server(id, timestamp, author, text)
+                    };
+                    self.add_message_to(ConversationKind::Room, &room, message)
+                }
+            }
+            protocol::Frame::DirectChat {
+                id,
+                timestamp,
+                from,
+                text,
+                action,
+            } => {
+                self.open_conversation(ConversationKind::Direct, from.clone());
+                let message = Message {
+                    action,
+                    ..Message::from_server(id, timestamp, from.clone(), text)
+                };
+                self.add_message_to(ConversationKind::Direct, &from, message)
+            }
+            protocol::Frame::Join {
+                id,
+                timestamp,
+                room,
+                username,
+            } => {
+                let text = format!("{} has joined {}", username, room);
+                let message = Message::from_server(id, timestamp, "System".to_string(), text);
+                self.add_message_to(ConversationKind::Room, &room, message)
+            }
+            protocol::Frame::Leave {
+                id,
+                timestamp,
+                room,
+                username,
+            } => {
+                let text = format!("{} has left {}", username, room);
+                let message = Message::from_server(id, timestamp, "System".to_string(), text);
+                self.add_message_to(ConversationKind::Room, &room, message)
+            }
+            protocol::Frame::RoomJoined { room } => {
+                // Rejoined after reconnecting; the conversation never closed
+                if self
+                    .conversation_index(ConversationKind::Room, &room)
+                    .is_some()
+                {
+                    return;
+                }
+                let index = self.open_conversation(ConversationKind::Room, room.clone());
+                self.add_message_to(
+                    ConversationKind::Room,
+                    &room,
+                    Message::local("System".to_string(), format!("You joined {}", room)),
+                );
+                if self.conversations.len() == 1 {
+                    // The first conversation inherits whatever was typed before it opened
+                    self.conversations[0].draft = self.input_widget.take_text();
+                    self.show_active_conversation();
+                } else {
+                    self.switch_conversation(index);
+                }
+            }
+            protocol::Frame::History { room, messages } => {
+                let Some(index) = self.conversation_index(ConversationKind::Room, &room) else {
+                    return;
+                };
+                // Earlier conversation goes above everything received since
+                // joining. Messages missed while reconnecting are newer than
+                // anything shown, so they go below it. What was shown before a
+                // fresh login stays on top: its IDs say nothing about the new
+                // session's.
+                let conversation = &mut self.conversations[index];
+                let newest = conversation
+                    .messages
+                    .iter()
+                    .filter(|message| !message.earlier_session)
+                    .filter_map(|message| message.id)
+                    .max();
+                let messages: Vec<_> = messages
+                    .into_iter()
+                    .filter(|message| !conversation.contains(message.id))
+                    .collect();
+                // After resuming, the user's own messages that got through come
+                // back numbered, and take the place of the local copies the
+                // server never confirmed
+                let sent: Vec<u64> = messages.iter().filter_map(|m| m.client_id).collect();
+                conversation.messages.retain(|message| {
+                    message
+                        .outgoing
+                        .as_ref()
+                        .is_none_or(|outgoing| !sent.contains(&outgoing.client_id))
+                });
+                let (missed, mut earlier): (Vec<Message>, Vec<Message>) = messages
+                    .into_iter()
+                    .map(|message| Message {
+                        action: message.action,
+                        outgoing: message.client_id.map(|client_id| Outgoing {
+                            client_id,
+                            delivery: Delivery::Acked,
+                        }),
+                        ..Message::from_server(
+                            message.id,
+                            message.timestamp,
+                            message.author,
+                            message.text,
+                        )
+                    })
+                    .partition(|message| newest.is_some() && message.id > newest);
+                if !earlier.is_empty() {
+                    earlier.push(Message::local(
+                        "System".to_string(),
+                        "── new since you joined ──".to_string(),
+                    ));
+                }
+
+                let conversation = &mut self.conversations[index];
+                let start = conversation
+                    .messages
+                    .iter()
+                    .rposition(|message| message.earlier_session)
+                    .map_or(0, |position| position + 1);
+                conversation.messages.splice(start..start, earlier);
+                if !missed.is_empty() {
+                    conversation.push(Message::local(
+                        "System".to_string(),
+                        "── missed while disconnected ──".to_string(),
+                    ));
+                    for message in missed {
+                        conversation.push(message);
+                    }
+                }
+            }
+            protocol::Frame::RoomLeft { room } => {
+                self.close_conversation(ConversationKind::Room, &room);
+                self.add_message("System".to_string(), format!("You left {}", room));
+            }
+            protocol::Frame::RoomList { rooms } => {
+                let rooms: Vec<String> = rooms
+                    .iter()
+                    .map(|room| format!("{} ({})", room.name, room.members))
+                    .collect();
+                self.add_message("System".to_string(), format!("Rooms: {}", rooms.join(", ")))
+            }
+            protocol::Frame::System {
+                id,
+                timestamp,
+                room,
+                text,
+            } => {
+                let message = Message::from_server(id, timestamp, "System".to_string(), text);
+                self.add_message_to(ConversationKind::Room, &room, message)
+            }
+            protocol::Frame::Renamed { username } => {
+                for conversation in &mut self.conversations {
+                    if conversation.kind == ConversationKind::Direct {
+                        conversation.users = vec![conversation.name.clone(), username.clone()];
+                    }
+                }
+                if let Some(conversation) = self.active() {
+                    self.connected_users_widget
+                        .set_users(conversation.title(), conversation.users.clone());
+                }
+                self.add_message(
+                    "System".to_string(),
+                    format!("You are now known as {}", username),
+                );
+                self.input_widget.username = username.clone();
+                self.username = username;
+            }
+            protocol::Frame::Notice { text } => self.add_message("System".to_string(), text),
+            protocol::Frame::Disconnect { reason } => {
+                self.add_message("System".to_string(), format!("Disconnected: {}", reason))
+            }
+            protocol::Frame::ShuttingDown {
+                reason,
+                restart_in_secs,
+            } => self.add_message(
+                "System".to_string(),
+                describe_shutdown(reason.as_deref(), restart_in_secs),
+            ),
+            protocol::Frame::Error { message } => {
+                self.add_message("System".to_string(), format!("Error: {}", message))
+            }
+            protocol::Frame::Ack {
+                client_id,
+                id,
+                timestamp,
+            } => {
+                self.reconcile(client_id, id, timestamp);
+            }
+            protocol::Frame::Rejected { client_id, message } => {
+                self.set_delivery(client_id, Delivery::Failed(message))
+            }
+            protocol::Frame::UserList { room, users } => {
+                if let Some(index) = self.conversation_index(ConversationKind::Room, &room) {
+                    if index == self.active_conversation {
+                        self.connected_users_widget.set_users(room, users.clone());
+                    }
+                    self.conversations[index].users = users;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Sends `text` to the conversation on screen: the room, or the other user for
+    // a direct message. It's queued, so it goes out once the client reconnects if
+    // the connection is down. `action` sends it as with /me.
+    fn send_text(&mut self, text: String, action: bool) {
+        let Some(conversation) = self.active() else {
+            self.add_message(
+                "System".to_string(),
+                "Join a room before sending messages.".to_string(),
+            );
+            return;
+        };
+        let client_id = self.next_client_id;
+        let frame = chat_frame(
+            conversation.kind,
+            &conversation.name,
+            &text,
+            client_id,
+            action,
+        );
+        self.next_client_id += 1;
+
+        // Add message to local UI immediately for better UX, and show it even if
+        // the user had scrolled up
+        if let Some(conversation) = self.conversations.get_mut(self.active_conversation) {
+            conversation.scroll_to_bottom();
+        }
+        self.push_message(Message {
+            action,
+            ..Message::outgoing(self.username.clone(), text, client_id)
+        });
+        self.unsent.push_back((client_id, frame));
+        self.send_unsent();
+    }
+
+    // Writes queued messages to the server in order, stopping at the first one
+    // that can't be written. The rest wait until the client reconnects.
+    fn send_unsent(&mut self) {
+        while let Some((client_id, frame)) = self.unsent.front() {
+            if self.send_frame(frame).is_err() {
+                return;
+            }
+            let client_id = *client_id;
+            self.unsent.pop_front();
+            self.set_delivery(client_id, Delivery::Sent);
+        }
+    }
+
+    // The message the user numbered `client_id`, if it's still shown
+    fn outgoing_message(&mut self, client_id: u64) -> Option<&mut Message> {
+        self.conversations
+            .iter_mut()
+            .flat_map(|conversation| conversation.messages.iter_mut().rev())
+            .find(|message| {
+                message
+                    .outgoing
+                    .as_ref()
+                    .is_some_and(|outgoing| outgoing.client_id == client_id)
+            })
+    }
+
+    // Replaces the user's local copy of a message with what the server made of
+    // it: the server's ID and timestamp, and a place after everything the server
+    // delivered first. Returns false if the local copy isn't shown any more.
+    fn reconcile(&mut self, client_id: u64, id: u64, timestamp: u64) -> bool {
+        for conversation in self.conversations.iter_mut() {
+            let position = conversation.messages.iter().rposition(|message| {
+                message
+                    .outgoing
+                    .as_ref()
+                    .is_some_and(|outgoing| outgoing.client_id == client_id)
+            });
+            let Some(position) = position else {
+                continue;
+            };
+
+            let mut message = conversation.messages.remove(position);
+            message.id = Some(id);
+            message.timestamp = timestamp;
+            if let Some(outgoing) = &mut message.outgoing {
+                outgoing.delivery = Delivery::Acked;
+            }
+            conversation.messages.push(message);
+            return true;
+        }
+        false
+    }
+
+    fn set_delivery(&mut self, client_id: u64, delivery: Delivery) {
+        if let Some(outgoing) = self
+            .outgoing_message(client_id)
+            .and_then(|message| message.outgoing.as_mut())
+        {
+            outgoing.delivery = delivery;
+        }
+    }
+
+    // Marks messages the server hasn't answered as failed, along with those
+    // still waiting to be sent if `pending` is set. Used once the client is back
+    // after losing the connection, since any answer was lost with it, and after
+    // a resumed session has replayed the messages that did get through.
+    fn fail_unconfirmed(&mut self, pending: bool, reason: &str) {
+        if pending {
+            self.unsent.clear();
+        }
+        let messages = self
+            .conversations
+            .iter_mut()
+            .flat_map(|conversation| conversation.messages.iter_mut());
+        for outgoing in messages.filter_map(|message| message.outgoing.as_mut()) {
+            if outgoing.delivery == Delivery::Sent
+                || (pending && outgoing.delivery == Delivery::Pending)
+            {
+                outgoing.delivery = Delivery::Failed(reason.to_string());
+            }
+        }
+    }
+
+    // Sets apart everything received so far, since the IDs on it can't be
+    // compared with the new session's
+    fn start_new_session(&mut self) {
+        let messages = self
+            .conversations
+            .iter_mut()
+            .flat_map(|conversation| conversation.messages.iter_mut());
+        for message in messages.filter(|message| message.id.is_some()) {
+            message.earlier_session = true;
+        }
+    }
+
+    fn run_command(&mut self, command: Command) {
+        let frame = match command {
+            Command::Send(frame) => frame,
+            Command::Me(text) => return self.send_text(text, true),
+            Command::Msg { user, text } => {
+                let index = self.open_conversation(ConversationKind::Direct, user);
+                self.switch_conversation(index);
+                if let Some(text) = text {
+                    self.send_text(text, false);
+                }
+                return;
+            }
+            Command::Part(room) => match (room, self.active()) {
+                (Some(room), _) => protocol::Frame::LeaveRoom { room },
+                (None, Some(conversation)) if conversation.kind == ConversationKind::Direct => {
+                    // Direct messages only exist on this side, so just close the view
+                    let name = conversation.name.clone();
+                    self.close_conversation(ConversationKind::Direct, &name);
+                    return;
+                }
+                (None, conversation) => protocol::Frame::LeaveRoom {
+                    room: conversation
+                        .map(|conversation| conversation.name.clone())
+                        .unwrap_or_default(),
+                },
+            },
+            Command::Users => {
+                let text = match self.active() {
+                    Some(conversation) => format!(
+                        "In {}: {}",
+                        conversation.title(),
+                        conversation.users.join(", ")
+                    ),
+                    None => "Join a room to see who's in it.".to_string(),
+                };
+                return self.add_message("System".to_string(), text);
+            }
+            Command::Clear => {
+                if let Some(conversation) = self.conversations.get_mut(self.active_conversation) {
+                    conversation.messages.clear();
+                    conversation.scroll_to_bottom();
+                }
+                return;
+            }
+            Command::Quit => {
+                self.running = false;
+                return;
+            }
+            Command::Help(topic) => {
+                return self.add_message("System".to_string(), commands::help(topic));
+            }
+        };
+
+        if let Err(error_msg) = self.send_frame(&frame) {
+            self.add_message("System".to_string(), error_msg);
         }
     }
 
@@ -178,55 +841,37 @@ impl App {
         _tx: mpsc::Sender<Event>,
     ) -> io::Result<()> {
         while self.running {
-            // Request user list after first iteration
-            if !self.has_requested_user_list {
-                self.has_requested_user_list = true;
-                // Send GET_USERS command to server
-                let message = "GET_USERS\n";
-                let send_result = {
-                    let lock_result = self.write_stream.lock();
-                    match lock_result {
-                        Ok(mut stream) => match stream.write_all(message.as_bytes()) {
-                            Ok(_) => match stream.flush() {
-                                Ok(_) => Ok(()),
-                                Err(e) => Err(format!("Failed to request user list: {}", e)),
-                            },
-                            Err(e) => Err(format!("Failed to write to server: {}", e)),
-                        },
-                        Err(e) => Err(format!("Failed to lock stream: {}", e)),
-                    }
-                };
-
-                if let Err(error_msg) = send_result {
-                    self.add_message("System".to_string(), error_msg);
-                    self.should_auto_scroll = true;
-                }
-            }
             match rx.recv().unwrap() {
                 Event::Input(key_event) => self.handle_key_event(key_event)?,
                 Event::Mouse(mouse_event) => self.handle_mouse_event(mouse_event)?,
                 Event::CursorBlink => {
                     self.input_widget.update_cursor_blink();
                 }
+                Event::ServerFrame(frame) => self.handle_server_frame(frame),
                 Event::ServerMessage(message) => {
-                    // Parse server message and add to messages
-                    let message = message.trim().to_string();
-                    if !message.is_empty() {
-                        // Try to parse as "username: message" format
-                        if let Some(colon_pos) = message.find(':') {
-                            let author = message[..colon_pos].trim().to_string();
-                            let content = message[colon_pos + 1..].trim().to_string();
-                            self.add_message(author, content);
-                        } else {
-                            // System message (join/leave notifications)
-                            self.add_message("System".to_string(), message);
+                    // Connection status from the receiver thread
+                    self.add_message("System".to_string(), message);
+                }
+                Event::Connection(state) => {
+                    match &state {
+                        ConnectionState::Connected => {
+                            self.fail_unconfirmed(
+                                false,
+                                "Not confirmed before the connection dropped",
+                            );
+                            self.send_unsent();
+                        }
+                        ConnectionState::Reconnecting(..) => {}
+                        ConnectionState::Offline(_) => {
+                            self.fail_unconfirmed(true, "Not connected to the server")
                         }
-                        self.should_auto_scroll = true;
+                        ConnectionState::Connecting => {}
                     }
+                    self.connection = state;
+                    self.latency = None;
                 }
-                Event::UserListUpdate(users) => {
-                    self.connected_users_widget.set_users(users);
-                }
+                Event::NewSession => self.start_new_session(),
+                Event::Latency(rtt) => self.latency = Some(rtt),
             }
 
             terminal.draw(|frame| self.draw(frame))?;
@@ -240,16 +885,24 @@ impl App {
         const BG_SECONDARY: Color = Color::Rgb(30, 30, 30);
         const BG_SUCCESS: Color = Color::Rgb(89, 87, 86);
         const TEXT_PRIMARY: Color = Color::Rgb(255, 255, 255);
-        const TEXT_SECONDARY: Color = Color::Rgb(128, 128, 128);
 
         let [main_area, info_area] =
             Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
-        let [main_area, connection_area] =
+        let [main_area, sidebar_area] =
             Layout::horizontal([Constraint::Fill(1), Constraint::Length(50)]).areas(main_area);
 
+        let conversations_widget = ConversationsWidget {
+            conversations: &self.conversations,
+            active: self.active_conversation,
+        };
+        let [conversations_area, connection_area] = Layout::vertical([
+            Constraint::Length(conversations_widget.height()),
+            Constraint::Fill(1),
+        ])
+        .areas(sidebar_area);
+
         // Calculate input widget height
-        let available_width = main_area.width.saturating_sub(4);
-        let input_area_height = self.input_widget.calculate_height(available_width);
+        let input_area_height = self.input_widget.calculate_height(main_area.width);
         let total_input_height = input_area_height + 3; // Input area + info area
 
         let [content_area, input_parent] =
@@ -267,7 +920,28 @@ impl App {
         .centered()
         .bg(BG_SUCCESS);
 
-        let conn_msg = format!(" Connected to {} ", self.server_ip);
+        let conn_msg = match &self.connection {
+            ConnectionState::Connected => match self.latency {
+                Some(rtt) => format!(
+                    " Connected to {} · {} ms ",
+                    self.server_addr,
+                    rtt.as_millis()
+                ),
+                None => format!(" Connected to {} ", self.server_addr),
+            },
+            ConnectionState::Reconnecting(at, error) => {
+                let secs = at
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f64()
+                    .ceil();
+                format!(
+                    " {} · reconnecting to {} in {}s ",
+                    error, self.server_addr, secs
+                )
+            }
+            ConnectionState::Connecting => format!(" Reconnecting to {}… ", self.server_addr),
+            ConnectionState::Offline(reason) => format!(" Offline: {} ", reason),
+        };
 
         let conn_info = Line::from(Span::styled(conn_msg, Style::default().fg(TEXT_SECONDARY)))
             .bg(BG_SECONDARY);
@@ -278,55 +952,39 @@ impl App {
         ])
         .areas(info_area);
 
-        // Create lines for messages with proper wrapping, starting from scroll offset
-        let mut all_lines = Vec::new();
-        let mut is_first_message = true;
-
-        for message in self.messages.iter().skip(self.scroll_offset) {
-            if !message.author.is_empty() {
-                let content = format!("{}: {}", message.author, message.content);
-
-                // Add spacing before message (except for first message)
-                if !is_first_message {
-                    all_lines.push(Line::from(""));
-                }
-                // Add message line (will wrap automatically)
-                all_lines.push(Line::from(content));
-                is_first_message = false;
-            }
-        }
-
-        let messages_widget =
-            Paragraph::new(all_lines)
-                .wrap(Wrap { trim: true })
-                .block(Block::new().padding(Padding {
-                    left: 1,
-                    right: 1,
-                    top: 1,
-                    bottom: 1,
-                }));
-
-        // Handle auto-scroll if flag is set
-        if self.should_auto_scroll {
-            let available_width = content_area.width.saturating_sub(2); // Account for padding
-            let available_height = content_area.height.saturating_sub(2);
-
-            // Only auto-scroll if user is near the bottom
-            if is_near_bottom(
-                &self.messages,
-                self.scroll_offset,
-                available_height,
-                available_width,
-                2,
-            ) {
-                self.scroll_offset =
-                    calculate_scroll_to_bottom(&self.messages, available_height, available_width);
+        // Account for padding
+        let text_width = content_area.width.saturating_sub(2) as usize;
+        self.page_height = content_area.height.saturating_sub(2) as usize;
+        let (rows, new_below) = match self.conversations.get_mut(self.active_conversation) {
+            Some(conversation) => (
+                conversation.visible_rows(&self.time_format, text_width, self.page_height),
+                conversation.new_below,
+            ),
+            None => {
+                let (rows, _) = visible_rows(
+                    &mut self.pending_messages,
+                    &self.time_format,
+                    text_width,
+                    self.page_height,
+                    0,
+                );
+                (rows, 0)
             }
+        };
 
-            self.should_auto_scroll = false;
-        }
+        let messages_widget = Paragraph::new(rows).block(Block::new().padding(Padding {
+            left: 1,
+            right: 1,
+            top: 1,
+            bottom: 1,
+        }));
 
         frame.render_widget(Block::new().bg(BG_PRIMARY), main_area);
+        ConversationsWidget {
+            conversations: &self.conversations,
+            active: self.active_conversation,
+        }
+        .render(frame, conversations_area);
         self.connected_users_widget.render(frame, connection_area);
         frame.render_widget(
             messages_widget,
@@ -337,6 +995,21 @@ impl App {
                 height: content_area.height,
             },
         );
+        // Point out messages that arrived below the view, on its bottom padding row
+        if new_below > 0 && content_area.height > 0 {
+            let plural = if new_below == 1 { "" } else { "s" };
+            let indicator = Line::from(Span::styled(
+                format!(" {} new message{} ↓ ", new_below, plural),
+                Style::default().fg(TEXT_PRIMARY).bg(BG_SUCCESS),
+            ))
+            .centered();
+            let indicator_area = Rect {
+                y: content_area.bottom() - 1,
+                height: 1,
+                ..content_area
+            };
+            frame.render_widget(indicator, indicator_area);
+        }
         // Render input widget
         self.input_widget.render(frame, input_area_1, input_area_2);
         frame.render_widget(version_control, vc_area);
@@ -345,59 +1018,379 @@ impl App {
 
     fn handle_mouse_event(&mut self, mouse_event: MouseEvent) -> io::Result<()> {
         match mouse_event.kind {
-            MouseEventKind::ScrollDown => {
-                self.scroll_down();
-            }
-            MouseEventKind::ScrollUp => {
-                self.scroll_up();
-            }
+            MouseEventKind::ScrollDown => self.scroll(|conversation| conversation.scroll_down(3)),
+            MouseEventKind::ScrollUp => self.scroll(|conversation| conversation.scroll_up(3)),
             _ => {}
         }
         Ok(())
     }
 
+    fn scroll(&mut self, scroll: impl FnOnce(&mut Conversation)) {
+        if let Some(conversation) = self.conversations.get_mut(self.active_conversation) {
+            scroll(conversation);
+        }
+    }
+
+    // PageUp / PageDown scroll the messages by a screenful, Home / End jump to
+    // the first and newest
+    fn handle_scroll_keys(&mut self, key_event: crossterm::event::KeyEvent) -> bool {
+        // Keep a row of the last page in view
+        let page = self.page_height.saturating_sub(1).max(1);
+        match key_event.code {
+            KeyCode::PageUp => self.scroll(|conversation| conversation.scroll_up(page)),
+            KeyCode::PageDown => self.scroll(|conversation| conversation.scroll_down(page)),
+            KeyCode::Home => self.scroll(|conversation| conversation.scroll_up(usize::MAX)),
+            KeyCode::End => self.scroll(Conversation::scroll_to_bottom),
+            _ => return false,
+        }
+        true
+    }
+
+    // Alt+1..9 jumps to a conversation, Ctrl+N / Ctrl+P cycle through them
+    fn handle_conversation_keys(&mut self, key_event: crossterm::event::KeyEvent) -> bool {
+        let KeyCode::Char(c) = key_event.code else {
+            return false;
+        };
+
+        if key_event.modifiers.contains(KeyModifiers::ALT) && ('1'..='9').contains(&c) {
+            self.switch_conversation(c as usize - '1' as usize);
+        } else if key_event.modifiers.contains(KeyModifiers::CONTROL) && c == 'n' {
+            self.cycle_conversation(true);
+        } else if key_event.modifiers.contains(KeyModifiers::CONTROL) && c == 'p' {
+            self.cycle_conversation(false);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    // Alt+R sends the active conversation's failed messages again, Alt+D
+    // removes them
+    fn handle_failed_message_keys(&mut self, key_event: crossterm::event::KeyEvent) -> bool {
+        if !key_event.modifiers.contains(KeyModifiers::ALT) {
+            return false;
+        }
+        let retry = match key_event.code {
+            KeyCode::Char('r') => true,
+            KeyCode::Char('d') => false,
+            _ => return false,
+        };
+        let Some(conversation) = self.conversations.get_mut(self.active_conversation) else {
+            return true;
+        };
+        let failed = |message: &Message| {
+            message
+                .outgoing
+                .as_ref()
+                .is_some_and(|outgoing| matches!(outgoing.delivery, Delivery::Failed(_)))
+        };
+
+        if retry {
+            for message in conversation.messages.iter_mut().filter(|m| failed(m)) {
+                let Some(outgoing) = &mut message.outgoing else {
+                    continue;
+                };
+                outgoing.delivery = Delivery::Pending;
+                let frame = chat_frame(
+                    conversation.kind,
+                    &conversation.name,
+                    &message.content,
+                    outgoing.client_id,
+                    message.action,
+                );
+                self.unsent.push_back((outgoing.client_id, frame));
+            }
+            self.send_unsent();
+        } else {
+            conversation.messages.retain(|message| !failed(message));
+        }
+        true
+    }
+
+    // Up and Down step through what was sent in the active conversation, once
+    // the cursor is on the input's first or last line. Ctrl+R starts a search.
+    fn handle_history_keys(&mut self, key_event: crossterm::event::KeyEvent) -> bool {
+        let Some(conversation) = self.active().map(Conversation::title) else {
+            return false;
+        };
+        let entries = self.history.entries(&conversation);
+        match key_event.code {
+            KeyCode::Char('r') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    original: self.input_widget.get_text(),
+                });
+                self.input_widget.search = Some((String::new(), true));
+            }
+            KeyCode::Up if !self.input_widget.move_cursor_line(true) => {
+                let index = match &self.recall {
+                    Some(recall) => recall.index,
+                    None => entries.len(),
+                };
+                let Some(index) = index.checked_sub(1) else {
+                    return true;
+                };
+                let text = entries[index].clone();
+                let draft = match self.recall.take() {
+                    Some(recall) => recall.draft,
+                    None => self.input_widget.get_text(),
+                };
+                self.recall = Some(Recall { index, draft });
+                self.input_widget.set_text(text);
+            }
+            KeyCode::Down if !self.input_widget.move_cursor_line(false) => {
+                let Some(recall) = self.recall.take() else {
+                    return true;
+                };
+                match entries.get(recall.index + 1) {
+                    Some(text) => {
+                        self.input_widget.set_text(text.clone());
+                        self.recall = Some(Recall {
+                            index: recall.index + 1,
+                            ..recall
+                        });
+                    }
+                    None => self.input_widget.set_text(recall.draft),
+                }
+            }
+            KeyCode::Up | KeyCode::Down => {}
+            _ => return false,
+        }
+        true
+    }
+
+    // While searching, typing narrows the search and Ctrl+R finds the next
+    // older match. Esc or Ctrl+G puts back what was in the input before; any
+    // other key keeps the match and then does what it normally does.
+    fn handle_search_keys(&mut self, key_event: crossterm::event::KeyEvent) -> bool {
+        let Some(search) = &mut self.search else {
+            return false;
+        };
+        let conversation = self.conversations.get(self.active_conversation);
+        let conversation = conversation.map(Conversation::title).unwrap_or_default();
+        let control = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        let newest = self.history.entries(&conversation).len();
+        // Where to search back from: a longer query can still match the entry
+        // already found
+        let before = match key_event.code {
+            KeyCode::Char('r') if control => {
+                search.found.as_ref().map_or(newest, |(index, _)| *index)
+            }
+            KeyCode::Char('g') if control => {
+                self.cancel_search();
+                return true;
+            }
+            KeyCode::Char(c) if !control && !key_event.modifiers.contains(KeyModifiers::ALT) => {
+                search.query.push(c);
+                search.found.as_ref().map_or(newest, |(index, _)| index + 1)
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                newest
+            }
+            KeyCode::Esc => {
+                self.cancel_search();
+                return true;
+            }
+            _ => {
+                self.search = None;
+                self.input_widget.search = None;
+                self.input_widget.highlight = None;
+                return false;
+            }
+        };
+
+        let found = self.history.search(&conversation, &search.query, before);
+        self.input_widget.search = Some((search.query.clone(), found.is_some()));
+        if let Some((index, matched)) = found {
+            let text = self.history.entries(&conversation)[index].clone();
+            self.input_widget.set_text(text);
+            self.input_widget.highlight_match(matched.clone());
+            search.found = Some((index, matched));
+        }
+        true
+    }
+
+    fn cancel_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.input_widget.set_text(search.original);
+        }
+        self.input_widget.search = None;
+        self.input_widget.highlight = None;
+    }
+
     fn handle_key_event(&mut self, key_event: crossterm::event::KeyEvent) -> io::Result<()> {
+        self.input_widget.error = None;
+        if self.handle_conversation_keys(key_event)
+            || self.handle_failed_message_keys(key_event)
+            || self.handle_scroll_keys(key_event)
+            || self.handle_search_keys(key_event)
+            || self.handle_history_keys(key_event)
+        {
+            return Ok(());
+        }
+
         let should_quit = self.input_widget.handle_key_event(key_event)?;
         if should_quit {
             self.running = false;
             return Ok(());
         }
 
-        if key_event.code == KeyCode::Enter {
+        if key_event.code == KeyCode::Enter && !inserts_line_break(&key_event) {
             // Send message to server if not empty
             if !self.input_widget.is_empty() {
-                let message_content = self.input_widget.get_text();
-                let message = format!("{}\n", message_content);
-
-                // Add message to local UI immediately for better UX
-                self.add_message(self.username.clone(), message_content.clone());
-                self.should_auto_scroll = true;
-
-                // Send to server in background
-                let send_result = {
-                    let lock_result = self.write_stream.lock();
-                    match lock_result {
-                        Ok(mut stream) => match stream.write_all(message.as_bytes()) {
-                            Ok(_) => match stream.flush() {
-                                Ok(_) => Ok(()),
-                                Err(e) => Err(format!("Failed to send message: {}", e)),
-                            },
-                            Err(e) => Err(format!("Failed to write to server: {}", e)),
-                        },
-                        Err(e) => Err(format!("Failed to lock stream: {}", e)),
-                    }
+                let message_content = self.input_widget.get_text().trim_matches('\n').to_string();
+                // "//" escapes a message that starts with '/'
+                let command = match message_content.strip_prefix('/') {
+                    Some(text) if text.starts_with('/') => None,
+                    Some(_) => match commands::parse(&message_content) {
+                        Ok(command) => Some(command),
+                        Err(error) => {
+                            // Left in the input to be fixed
+                            self.input_widget.error = Some(error);
+                            return Ok(());
+                        }
+                    },
+                    None => None,
                 };
 
-                if let Err(error_msg) = send_result {
-                    self.add_message("System".to_string(), error_msg);
-                    self.should_auto_scroll = true;
+                // Clear input field first, since commands may switch conversations
+                self.input_widget.clear();
+                self.recall = None;
+                if let Some(conversation) = self.active().map(Conversation::title)
+                    && let Err(e) = self.history.push(&conversation, message_content.clone())
+                {
+                    self.add_message(
+                        "System".to_string(),
+                        format!("Couldn't save input history: {}", e),
+                    );
                 }
 
-                // Clear input field
-                self.input_widget.clear();
+                match command {
+                    Some(command) => self.run_command(command),
+                    None => match message_content.strip_prefix("//") {
+                        Some(text) => self.send_text(format!("/{}", text), false),
+                        None => self.send_text(message_content, false),
+                    },
+                }
             }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        App::new(
+            "alice".to_string(),
+            "localhost:8080".to_string(),
+            Arc::new(Mutex::new(None)),
+            "%H:%M".to_string(),
+            InputHistory::default(),
+        )
+    }
+
+    fn chat(id: u64, text: &str) -> protocol::Frame {
+        protocol::Frame::Chat {
+            id,
+            timestamp: 0,
+            room: "#lobby".to_string(),
+            author: "bob".to_string(),
+            text: text.to_string(),
+            client_id: None,
+            action: false,
+        }
+    }
+
+    fn contents(app: &App) -> Vec<&str> {
+        app.conversations[0]
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn shows_reused_ids_after_the_server_restarts() {
+        let mut app = app();
+        app.handle_server_frame(protocol::Frame::RoomJoined {
+            room: "#lobby".to_string(),
+        });
+        app.handle_server_frame(chat(1, "before"));
+        app.handle_server_frame(chat(1, "before"));
+        assert_eq!(contents(&app), ["You joined #lobby", "before"]);
+
+        // The restarted server numbers its messages from 1 again
+        app.start_new_session();
+        app.handle_server_frame(chat(1, "after"));
+        app.handle_server_frame(chat(1, "after"));
+        assert_eq!(contents(&app), ["You joined #lobby", "before", "after"]);
+    }
+
+    #[test]
+    fn replays_history_below_what_was_shown_before_the_server_restarted() {
+        let mut app = app();
+        app.handle_server_frame(protocol::Frame::RoomJoined {
+            room: "#lobby".to_string(),
+        });
+        app.handle_server_frame(chat(5, "old"));
+
+        app.start_new_session();
+        app.handle_server_frame(protocol::Frame::RoomJoined {
+            room: "#lobby".to_string(),
+        });
+        app.handle_server_frame(chat(3, "live"));
+        let replayed = |id, text: &str| protocol::HistoryMessage {
+            id,
+            timestamp: 0,
+            author: "bob".to_string(),
+            text: text.to_string(),
+            action: false,
+            client_id: None,
+        };
+        app.handle_server_frame(protocol::Frame::History {
+            room: "#lobby".to_string(),
+            messages: vec![replayed(1, "first"), replayed(2, "second")],
+        });
+        assert_eq!(
+            contents(&app),
+            [
+                "You joined #lobby",
+                "old",
+                "first",
+                "second",
+                "── new since you joined ──",
+                "live"
+            ]
+        );
+    }
+
+    #[test]
+    fn search_highlights_whole_graphemes() {
+        let mut app = app();
+        app.handle_server_frame(protocol::Frame::RoomJoined {
+            room: "#lobby".to_string(),
+        });
+        let family = "👩\u{200d}👩\u{200d}👧";
+        let entry = format!("hi {} ok", family);
+        app.history.push("#lobby", entry.clone()).unwrap();
+
+        let press = |app: &mut App, code, modifiers| {
+            app.handle_key_event(crossterm::event::KeyEvent::new(code, modifiers))
+                .unwrap();
+        };
+        press(&mut app, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        press(&mut app, KeyCode::Char('👧'), KeyModifiers::NONE);
+
+        // The match is the last person in the family, which can't be split off
+        assert_eq!(app.input_widget.text, entry);
+        assert_eq!(app.input_widget.cursor_position, 3);
+        assert_eq!(app.input_widget.highlight, Some(3..3 + family.len()));
+        assert_eq!(app.input_widget.search, Some(("👧".to_string(), true)));
+    }
+}
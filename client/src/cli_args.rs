@@ -1,4 +1,5 @@
 use clap::Parser;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "tailtalk")]
@@ -7,9 +8,40 @@ pub struct Args {
     #[arg(index = 1)]
     pub username: String,
 
+    /// Server hostname or IP address (IPv4 or IPv6)
     #[arg(index = 2, default_value = "0.0.0.0")]
-    pub ip: String,
+    pub host: String,
 
     #[arg(short = 'p', long, default_value = "2133")]
     pub port: u16,
+
+    /// Register the username with a password (read from TCPTALK_PASSWORD or a prompt)
+    #[arg(long)]
+    pub register: bool,
+
+    /// Connect with TLS. The server's certificate is trusted the first time and
+    /// must stay the same afterwards, unless --tls-ca is given.
+    #[arg(long)]
+    pub tls: bool,
+
+    /// Verify the server's certificate with this PEM CA certificate instead (implies --tls)
+    #[arg(long, value_name = "FILE")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// File where trusted server certificate fingerprints are stored
+    #[arg(long, value_name = "FILE")]
+    pub known_hosts: Option<PathBuf>,
+
+    /// File where sent messages are kept, to recall them with Up/Down and Ctrl+R
+    #[arg(long, value_name = "FILE")]
+    pub history_file: Option<PathBuf>,
+
+    /// strftime-style format for message timestamps, shown in local time
+    #[arg(long, value_name = "FORMAT", default_value = "%H:%M")]
+    pub time_format: String,
+
+    /// Seconds between pings to the server. The connection is dropped and
+    /// reopened after three go unanswered.
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub ping_interval: u64,
 }
@@ -0,0 +1,438 @@
+use tcptalk_protocol::Frame;
+use unicode_width::UnicodeWidthStr;
+
+// What a line starting with '/' asks for
+pub enum Command {
+    // Commands that only need a frame sent to the server
+    Send(Frame),
+    Me(String),
+    Msg { user: String, text: Option<String> },
+    Part(Option<String>),
+    Users,
+    Clear,
+    Quit,
+    Help(Option<&'static Spec>),
+}
+
+pub struct Spec {
+    pub name: &'static str,
+    // As shown in the usage: <required> and [optional] arguments, the last of
+    // which takes the rest of the line if it ends in "..."
+    pub args: &'static str,
+    pub about: &'static str,
+    pub operator: bool,
+    // Turns arguments that matched `args` into the command
+    build: fn(&mut Args) -> Result<Command, String>,
+}
+
+impl Spec {
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            format!("/{}", self.name)
+        } else {
+            format!("/{} {}", self.name, self.args)
+        }
+    }
+
+    // Splits `input` into the arguments in `args`, checking there are as many
+    // as the command takes
+    fn split_args(&self, mut input: &str) -> Result<Args, String> {
+        let mut values = Vec::new();
+        for arg in self.args.split_whitespace() {
+            input = input.trim_start();
+            let value = if arg.ends_with("...>") || arg.ends_with("...]") {
+                // Kept as typed, line breaks and all
+                std::mem::take(&mut input).trim_end()
+            } else {
+                let end = input.find(char::is_whitespace).unwrap_or(input.len());
+                let (value, rest) = input.split_at(end);
+                input = rest;
+                value
+            };
+            if value.is_empty() && arg.starts_with('<') {
+                return Err(format!("Missing {}.", arg.replace("...", "")));
+            }
+            values.push((!value.is_empty()).then(|| value.to_string()));
+        }
+        if !input.trim().is_empty() {
+            return Err("Too many arguments.".to_string());
+        }
+        Ok(Args(values.into_iter()))
+    }
+}
+
+// A command's arguments in the order its `args` lists them
+pub struct Args(std::vec::IntoIter<Option<String>>);
+
+impl Args {
+    // Present, since `split_args` checked
+    fn required(&mut self) -> String {
+        self.optional().unwrap_or_default()
+    }
+
+    fn optional(&mut self) -> Option<String> {
+        self.0.next().flatten()
+    }
+}
+
+pub const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "join",
+        args: "<room>",
+        about: "Join a room",
+        operator: false,
+        build: |args| {
+            Ok(Command::Send(Frame::JoinRoom {
+                room: args.required(),
+            }))
+        },
+    },
+    Spec {
+        name: "create",
+        args: "<room>",
+        about: "Create a room and join it",
+        operator: false,
+        build: |args| {
+            Ok(Command::Send(Frame::CreateRoom {
+                room: args.required(),
+            }))
+        },
+    },
+    Spec {
+        name: "part",
+        args: "[room]",
+        about: "Leave a room, or close the conversation on screen",
+        operator: false,
+        build: |args| Ok(Command::Part(args.optional())),
+    },
+    Spec {
+        name: "rooms",
+        args: "",
+        about: "List the rooms on the server",
+        operator: false,
+        build: |_| Ok(Command::Send(Frame::ListRooms)),
+    },
+    Spec {
+        name: "users",
+        args: "",
+        about: "List who is in the conversation on screen",
+        operator: false,
+        build: |_| Ok(Command::Users),
+    },
+    Spec {
+        name: "msg",
+        args: "<user> [message...]",
+        about: "Open a direct conversation, sending a message if given",
+        operator: false,
+        build: |args| {
+            Ok(Command::Msg {
+                user: args.required(),
+                text: args.optional(),
+            })
+        },
+    },
+    Spec {
+        name: "me",
+        args: "<action...>",
+        about: "Describe what you're doing, e.g. /me waves",
+        operator: false,
+        build: |args| Ok(Command::Me(args.required())),
+    },
+    Spec {
+        name: "nick",
+        args: "<name>",
+        about: "Change your name; only guests can",
+        operator: false,
+        build: |args| {
+            Ok(Command::Send(Frame::Nick {
+                username: args.required(),
+            }))
+        },
+    },
+    Spec {
+        name: "clear",
+        args: "",
+        about: "Clear the messages on screen",
+        operator: false,
+        build: |_| Ok(Command::Clear),
+    },
+    Spec {
+        name: "help",
+        args: "[command]",
+        about: "Show the commands, or how to use one",
+        operator: false,
+        build: |args| match args.optional() {
+            Some(name) => find(&name)
+                .map(|spec| Command::Help(Some(spec)))
+                .ok_or_else(|| format!("There's no /{} command.", name.trim_start_matches('/'))),
+            None => Ok(Command::Help(None)),
+        },
+    },
+    Spec {
+        name: "quit",
+        args: "",
+        about: "Disconnect and exit",
+        operator: false,
+        build: |_| Ok(Command::Quit),
+    },
+    Spec {
+        name: "oper",
+        args: "<password...>",
+        about: "Become an operator",
+        operator: true,
+        build: |args| {
+            Ok(Command::Send(Frame::Oper {
+                password: args.required(),
+            }))
+        },
+    },
+    Spec {
+        name: "kick",
+        args: "<user> [reason...]",
+        about: "Disconnect a user",
+        operator: true,
+        build: |args| {
+            Ok(Command::Send(Frame::Kick {
+                username: args.required(),
+                reason: args.optional(),
+            }))
+        },
+    },
+    Spec {
+        name: "ban",
+        args: "<user|ip|cidr> [duration] [reason...]",
+        about: "Ban a user, IP address or network, forever or for a while",
+        operator: true,
+        build: |args| {
+            let target = args.required();
+            let (duration, reason) = (args.optional(), args.optional());
+            // The duration is optional, so a reason may start right after the target
+            let (duration_secs, reason) = match duration.as_deref().map(parse_duration) {
+                Some(Some(secs)) => (Some(secs?), reason),
+                Some(None) => {
+                    let reason = [duration, reason].into_iter().flatten();
+                    (None, Some(reason.collect::<Vec<_>>().join(" ")))
+                }
+                None => (None, None),
+            };
+            Ok(Command::Send(Frame::Ban {
+                target,
+                duration_secs,
+                reason,
+            }))
+        },
+    },
+    Spec {
+        name: "unban",
+        args: "<target>",
+        about: "Lift a ban",
+        operator: true,
+        build: |args| {
+            Ok(Command::Send(Frame::Unban {
+                target: args.required(),
+            }))
+        },
+    },
+    Spec {
+        name: "mute",
+        args: "<user> [duration]",
+        about: "Stop a user from sending messages, forever or for a while",
+        operator: true,
+        build: |args| {
+            let username = args.required();
+            let duration_secs = match args.optional() {
+                Some(duration) => Some(
+                    parse_duration(&duration)
+                        .ok_or_else(|| "Durations look like 30s, 10m, 2h or 1d.".to_string())??,
+                ),
+                None => None,
+            };
+            Ok(Command::Send(Frame::Mute {
+                username,
+                duration_secs,
+            }))
+        },
+    },
+    Spec {
+        name: "unmute",
+        args: "<user>",
+        about: "Let a muted user send messages again",
+        operator: true,
+        build: |args| {
+            Ok(Command::Send(Frame::Unmute {
+                username: args.required(),
+            }))
+        },
+    },
+];
+
+// Looks up a command by name, with or without its leading '/'
+pub fn find(name: &str) -> Option<&'static Spec> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+fn unknown(name: &str) -> String {
+    format!(
+        "Unknown command /{}. Type /help to see the commands.",
+        name.trim_start_matches('/')
+    )
+}
+
+// Parses a line starting with '/'. Errors say what's wrong and, if the command
+// exists, how to use it.
+pub fn parse(input: &str) -> Result<Command, String> {
+    let input = input.strip_prefix('/').unwrap_or(input);
+    let (name, rest) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let spec = find(name).ok_or_else(|| unknown(name))?;
+    spec.split_args(rest)
+        .and_then(|mut args| (spec.build)(&mut args))
+        .map_err(|error| format!("{} Usage: {}", error, spec.usage()))
+}
+
+// The usage of every command, lined up in columns, or of just `topic`
+pub fn help(topic: Option<&Spec>) -> String {
+    if let Some(spec) = topic {
+        return format!("{}\n{}.", spec.usage(), spec.about);
+    }
+
+    let width = COMMANDS
+        .iter()
+        .map(|spec| spec.usage().width())
+        .max()
+        .unwrap_or(0);
+    let section = |operator: bool| {
+        COMMANDS
+            .iter()
+            .filter(move |spec| spec.operator == operator)
+            .map(move |spec| format!("  {:width$}  {}", spec.usage(), spec.about))
+    };
+    let mut lines = vec!["Commands:".to_string()];
+    lines.extend(section(false));
+    lines.push("Operators:".to_string());
+    lines.extend(section(true));
+    lines.push("Start a message with // to send one that starts with /.".to_string());
+    lines.join("\n")
+}
+
+// Longest ban or mute the server accepts without it being permanent
+const MAX_DURATION_SECS: u64 = 3650 * 24 * 60 * 60;
+
+// Parses durations like "30s", "10m", "2h" or "1d" into seconds. `None` if
+// `text` doesn't look like a duration, and an error if it's too long for one.
+fn parse_duration(text: &str) -> Option<Result<u64, String>> {
+    let unit = text.chars().last()?;
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count = &text[..text.len() - 1];
+    if count.is_empty() || !count.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let secs = count
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(multiplier));
+    match secs {
+        Some(0) => None,
+        Some(secs) if secs <= MAX_DURATION_SECS => Some(Ok(secs)),
+        _ => Some(Err(format!(
+            "Durations can be at most {}d.",
+            MAX_DURATION_SECS / 86400
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> String {
+        parse(input).err().unwrap()
+    }
+
+    #[test]
+    fn the_last_argument_can_take_the_rest_of_the_line() {
+        let Ok(Command::Msg { user, text }) = parse("/msg bob  hi there\nbob ") else {
+            panic!("expected /msg");
+        };
+        assert_eq!(user, "bob");
+        assert_eq!(text.as_deref(), Some("hi there\nbob"));
+        assert!(matches!(
+            parse("/MSG bob"),
+            Ok(Command::Msg { text: None, .. })
+        ));
+    }
+
+    #[test]
+    fn explains_what_is_wrong_with_the_usage() {
+        assert_eq!(
+            error("/msg"),
+            "Missing <user>. Usage: /msg <user> [message...]"
+        );
+        assert_eq!(error("/me "), "Missing <action>. Usage: /me <action...>");
+        assert_eq!(
+            error("/nick a b"),
+            "Too many arguments. Usage: /nick <name>"
+        );
+        assert_eq!(
+            error("/mute bob soon"),
+            "Durations look like 30s, 10m, 2h or 1d. Usage: /mute <user> [duration]"
+        );
+        assert_eq!(
+            error("/ban bob 999999999999d"),
+            "Durations can be at most 3650d. Usage: /ban <user|ip|cidr> [duration] [reason...]"
+        );
+        assert_eq!(
+            error("/nope"),
+            "Unknown command /nope. Type /help to see the commands."
+        );
+        assert_eq!(
+            error("/help nope"),
+            "There's no /nope command. Usage: /help [command]"
+        );
+    }
+
+    #[test]
+    fn a_ban_reason_can_follow_the_target() {
+        let ban = |input| match parse(input) {
+            Ok(Command::Send(Frame::Ban {
+                duration_secs,
+                reason,
+                ..
+            })) => (duration_secs, reason),
+            _ => panic!("expected /ban"),
+        };
+        assert_eq!(
+            ban("/ban bob 2h spam"),
+            (Some(7200), Some("spam".to_string()))
+        );
+        assert_eq!(
+            ban("/ban bob spam links"),
+            (None, Some("spam links".to_string()))
+        );
+        assert_eq!(ban("/ban bob"), (None, None));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Some(Ok(30)));
+        assert_eq!(parse_duration("10m"), Some(Ok(600)));
+        assert_eq!(parse_duration("3650d"), Some(Ok(MAX_DURATION_SECS)));
+        assert!(matches!(parse_duration("3651d"), Some(Err(_))));
+        assert!(matches!(parse_duration("999999999999d"), Some(Err(_))));
+        assert!(matches!(
+            parse_duration("99999999999999999999s"),
+            Some(Err(_))
+        ));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10x"), None);
+    }
+}
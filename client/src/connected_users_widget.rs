@@ -8,15 +8,20 @@ use ratatui::{
 };
 
 pub struct ConnectedUsersWidget {
+    pub room: String,
     pub users: Vec<String>,
 }
 
 impl ConnectedUsersWidget {
     pub fn new() -> Self {
-        Self { users: Vec::new() }
+        Self {
+            room: String::new(),
+            users: Vec::new(),
+        }
     }
 
-    pub fn set_users(&mut self, users: Vec<String>) {
+    pub fn set_users(&mut self, room: String, users: Vec<String>) {
+        self.room = room;
         self.users = users;
     }
 
@@ -25,7 +30,7 @@ impl ConnectedUsersWidget {
         const TEXT_PRIMARY: Color = Color::Rgb(255, 255, 255);
 
         let mut lines = vec![Line::from(Span::styled(
-            format!("List of current connections ({})", self.users.len()),
+            format!("Users in {} ({})", self.room, self.users.len()),
             Style::default().bold(),
         ))];
 
@@ -0,0 +1,360 @@
+use crate::app::Event;
+use std::{
+    collections::BTreeMap,
+    hash::{BuildHasher, RandomState},
+    io, mem,
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+use tcptalk_protocol::{Frame, FrameReader, Stream, write_frame};
+
+// Resolves `host` (an IPv4/IPv6 literal or a hostname) and tries every resolved
+// address in turn, returning the first one that accepts the connection.
+pub fn connect(host: &str, port: u16) -> io::Result<(TcpStream, SocketAddr)> {
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not resolve {}: {}", host, e)))?
+        .collect();
+
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok((stream, addr)),
+            Err(e) => last_error = Some(format!("{}: {}", addr, e)),
+        }
+    }
+
+    Err(match last_error {
+        Some(error) => io::Error::new(io::ErrorKind::ConnectionRefused, error),
+        None => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", host),
+        ),
+    })
+}
+
+// Shown in the status bar
+#[derive(Clone)]
+pub enum ConnectionState {
+    Connected,
+    // Waiting to try again at the given time, after the given error
+    Reconnecting(Instant, String),
+    // Trying to connect right now
+    Connecting,
+    // Not trying any more, for the given reason
+    Offline(String),
+}
+
+// What the client needs to log back in after losing the connection
+pub struct Session {
+    pub username: String,
+    // Kept so a registered user can log in again if the server has forgotten the
+    // session, e.g. because it restarted
+    pub password: Option<String>,
+    pub resume_token: Option<String>,
+    // Highest message ID received in each room, so a resumed session gets
+    // everything after it
+    pub last_ids: BTreeMap<String, u64>,
+}
+
+impl Session {
+    fn login(&self) -> Frame {
+        Frame::Login {
+            username: self.username.clone(),
+            password: self.password.clone(),
+        }
+    }
+
+    // Keeps what the next login needs up to date with a frame from the server
+    fn note(&mut self, frame: &Frame) {
+        match frame {
+            // Logging in again after a dropped connection uses the new name
+            Frame::Renamed { username } => self.username = username.clone(),
+            Frame::RoomLeft { room } => {
+                self.last_ids.remove(room);
+            }
+            _ => {}
+        }
+        if let Some((room, id)) = room_id(frame) {
+            let last_id = self.last_ids.entry(room.to_string()).or_default();
+            *last_id = (*last_id).max(id);
+        }
+    }
+}
+
+// Sent after resuming a session. The server answers it once it has replayed
+// what was missed, so the client knows which of its own messages never made it.
+// Heartbeat pings count up from 0, so this can't be mistaken for one.
+const CAUGHT_UP_PING: u64 = u64::MAX;
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Pings in a row the server may leave unanswered before the connection is
+// given up on
+const MISSED_PONGS: u32 = 3;
+
+#[derive(Default)]
+struct Heartbeat {
+    next_id: u64,
+    // The last ping sent and when, until the server answers it
+    waiting: Option<(u64, Instant)>,
+    // Pings in a row the server hasn't answered
+    missed: u32,
+    // Set when the connection was shut down for not answering
+    timed_out: bool,
+}
+
+// Pings the server every `interval`. A half-open connection never reports an
+// error on its own, so once too many pings go unanswered the socket is shut
+// down, which ends `run_connection`'s read and starts a reconnect.
+fn run_heartbeat(
+    write_stream: Arc<Mutex<Option<Stream>>>,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    interval: Duration,
+) {
+    loop {
+        thread::sleep(interval);
+        let mut heartbeat = heartbeat.lock().unwrap();
+        let mut write_stream = write_stream.lock().unwrap();
+        let Some(stream) = write_stream.as_mut() else {
+            continue;
+        };
+
+        if heartbeat.waiting.is_some() {
+            heartbeat.missed += 1;
+        }
+        if heartbeat.missed >= MISSED_PONGS {
+            heartbeat.timed_out = true;
+            let _ = stream.shutdown(Shutdown::Both);
+            continue;
+        }
+        let id = heartbeat.next_id;
+        heartbeat.next_id += 1;
+        heartbeat.waiting = Some((id, Instant::now()));
+        let _ = write_frame(stream, &Frame::Ping { id });
+    }
+}
+
+// Why logging back in failed
+enum Failure {
+    // Worth trying again, e.g. the server isn't back up yet
+    Retry(io::Error),
+    // The server won't let us back in
+    Refused(String),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Retry(err)
+    }
+}
+
+// The room `frame` belongs to and the highest message ID in it, if it carries
+// any. Direct messages and acks don't count: the server delivers each room in
+// ID order, but not the rooms relative to each other or to direct messages.
+fn room_id(frame: &Frame) -> Option<(&str, u64)> {
+    match frame {
+        Frame::Chat { id, room, .. }
+        | Frame::Join { id, room, .. }
+        | Frame::Leave { id, room, .. }
+        | Frame::System { id, room, .. } => Some((room, *id)),
+        Frame::History { room, messages } => messages
+            .iter()
+            .map(|message| message.id)
+            .max()
+            .map(|id| (room.as_str(), id)),
+        _ => None,
+    }
+}
+
+// Up to a quarter more than `delay`, so clients that lost the same server don't
+// all come back at once
+fn with_jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().hash_one(Instant::now());
+    delay + delay.mul_f64((random % 1000) as f64 / 4000.0)
+}
+
+// Logs in on a new connection: resumes the session if the server still has it,
+// and logs in from scratch otherwise. Returns whether the session was resumed,
+// after the messages missed in the meantime have been passed on to the app.
+fn log_in_again(
+    stream: &mut Stream,
+    reader: &mut FrameReader<Stream>,
+    session: &mut Session,
+    tx: &mpsc::Sender<Event>,
+) -> Result<bool, Failure> {
+    let mut resuming = false;
+    let first = match &session.resume_token {
+        Some(token) => {
+            resuming = true;
+            Frame::Resume {
+                token: token.clone(),
+                last_ids: session.last_ids.clone(),
+            }
+        }
+        None => session.login(),
+    };
+    write_frame(stream, &first)?;
+
+    loop {
+        match reader.read_frame().map_err(io::Error::from)? {
+            Some(Frame::LoginAccepted { resume_token, .. }) => {
+                session.resume_token = Some(resume_token);
+                // A fresh session starts over, and a restarted server may
+                // number its messages from scratch
+                if !resuming {
+                    session.last_ids.clear();
+                    return Ok(false);
+                }
+                write_frame(stream, &Frame::Ping { id: CAUGHT_UP_PING })?;
+            }
+            Some(Frame::Pong { id }) if id == CAUGHT_UP_PING => return Ok(true),
+            // The session expired, or the server restarted and forgot it
+            Some(Frame::LoginRejected { .. }) if resuming => {
+                resuming = false;
+                session.resume_token = None;
+                write_frame(stream, &session.login())?;
+            }
+            Some(Frame::LoginRejected { reason }) | Some(Frame::Disconnect { reason }) => {
+                return Err(Failure::Refused(reason));
+            }
+            Some(Frame::PasswordRequired { username }) => {
+                return Err(Failure::Refused(format!(
+                    "{} is registered; restart the client to log in with its password",
+                    username
+                )));
+            }
+            Some(frame) => {
+                session.note(&frame);
+                let _ = tx.send(Event::ServerFrame(frame));
+            }
+            None => return Err(Failure::Retry(io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+}
+
+// Forwards frames from the server to the app, and reconnects with exponential
+// backoff whenever the connection drops, until the server says not to. `open`
+// makes a new connection to the server. The server is pinged every
+// `ping_interval`, and the measured latency is passed on to the app.
+pub fn run_connection(
+    mut reader: FrameReader<Stream>,
+    write_stream: Arc<Mutex<Option<Stream>>>,
+    mut session: Session,
+    open: impl Fn() -> io::Result<Stream>,
+    ping_interval: Duration,
+    tx: mpsc::Sender<Event>,
+) {
+    let heartbeat = Arc::new(Mutex::new(Heartbeat::default()));
+    {
+        let write_stream = Arc::clone(&write_stream);
+        let heartbeat = Arc::clone(&heartbeat);
+        thread::spawn(move || run_heartbeat(write_stream, heartbeat, ping_interval));
+    }
+
+    loop {
+        // When the server says it's shutting down, it may say when it'll be back
+        let mut first_delay = FIRST_RETRY_DELAY;
+        let mut refused = None;
+        let lost = loop {
+            match reader.read_frame() {
+                Ok(Some(Frame::Ping { id })) => {
+                    if let Some(stream) = write_stream.lock().unwrap().as_mut() {
+                        let _ = write_frame(stream, &Frame::Pong { id });
+                    }
+                }
+                Ok(Some(Frame::Pong { id })) => {
+                    let mut heartbeat = heartbeat.lock().unwrap();
+                    // Any answer shows the server is still there, but only the
+                    // latest ping's gives the current latency
+                    heartbeat.missed = 0;
+                    if let Some((waiting, sent_at)) = heartbeat.waiting
+                        && waiting == id
+                    {
+                        heartbeat.waiting = None;
+                        if tx.send(Event::Latency(sent_at.elapsed())).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Ok(Some(frame)) => {
+                    match &frame {
+                        Frame::ShuttingDown {
+                            restart_in_secs: Some(secs),
+                            ..
+                        } => first_delay = Duration::from_secs(*secs).min(MAX_RETRY_DELAY),
+                        Frame::Disconnect { reason } => refused = Some(reason.clone()),
+                        _ => {}
+                    }
+                    session.note(&frame);
+                    if tx.send(Event::ServerFrame(frame)).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break "Server disconnected".to_string(),
+                Err(e) => break format!("Connection error: {}", e),
+            }
+        };
+        *write_stream.lock().unwrap() = None;
+        // The next connection starts its heartbeat afresh
+        let timed_out = mem::take(&mut *heartbeat.lock().unwrap()).timed_out;
+        let lost = if timed_out {
+            "Server stopped responding".to_string()
+        } else {
+            lost
+        };
+        if let Some(reason) = refused {
+            let _ = tx.send(Event::Connection(ConnectionState::Offline(reason)));
+            return;
+        }
+        let _ = tx.send(Event::ServerMessage(lost.clone()));
+
+        let mut delay = first_delay;
+        let mut error = lost;
+        let resumed = loop {
+            let retry_at = Instant::now() + with_jitter(delay);
+            let state = ConnectionState::Reconnecting(retry_at, error.clone());
+            if tx.send(Event::Connection(state)).is_err() {
+                return;
+            }
+            thread::sleep(retry_at.saturating_duration_since(Instant::now()));
+            let _ = tx.send(Event::Connection(ConnectionState::Connecting));
+
+            let attempt = open().map_err(Failure::from).and_then(|mut stream| {
+                let mut new_reader = FrameReader::new(stream.try_clone()?);
+                let resumed = log_in_again(&mut stream, &mut new_reader, &mut session, &tx)?;
+                Ok((stream, new_reader, resumed))
+            });
+            match attempt {
+                Ok((stream, new_reader, resumed)) => {
+                    *write_stream.lock().unwrap() = Some(stream);
+                    reader = new_reader;
+                    break resumed;
+                }
+                Err(Failure::Refused(reason)) => {
+                    let _ = tx.send(Event::Connection(ConnectionState::Offline(reason)));
+                    return;
+                }
+                Err(Failure::Retry(err)) => error = err.to_string(),
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        };
+
+        if !resumed {
+            let _ = tx.send(Event::NewSession);
+        }
+        let message = if resumed {
+            "Reconnected.".to_string()
+        } else {
+            format!(
+                "Reconnected as {}, but the server had forgotten this session. Use /join to rejoin your rooms.",
+                session.username
+            )
+        };
+        let _ = tx.send(Event::ServerMessage(message));
+        let _ = tx.send(Event::Connection(ConnectionState::Connected));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::app::Message;
+use crate::layout;
+use ratatui::text::Line;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ConversationKind {
+    Room,
+    // Private messages with the user in `Conversation::name`
+    Direct,
+}
+
+// One room or direct-message thread, with its own scrollback and unsent input
+pub struct Conversation {
+    pub kind: ConversationKind,
+    pub name: String,
+    pub messages: Vec<Message>,
+    // Rows scrolled up from the bottom; at 0 the newest message stays in view
+    pub scroll_offset: usize,
+    // Messages that arrived below the view while it was scrolled up
+    pub new_below: usize,
+    // How many of the newest messages haven't been added to `scroll_offset`
+    // yet, since that needs them laid out
+    unmeasured: usize,
+    pub unread: usize,
+    pub draft: String,
+    pub users: Vec<String>,
+}
+
+impl Conversation {
+    pub fn new(kind: ConversationKind, name: String) -> Self {
+        Self {
+            kind,
+            name,
+            messages: Vec::new(),
+            scroll_offset: 0,
+            new_below: 0,
+            unmeasured: 0,
+            unread: 0,
+            draft: String::new(),
+            users: Vec::new(),
+        }
+    }
+
+    // What the sidebar shows: rooms already start with '#', DMs get an '@'
+    pub fn title(&self) -> String {
+        match self.kind {
+            ConversationKind::Room => self.name.clone(),
+            ConversationKind::Direct => format!("@{}", self.name),
+        }
+    }
+
+    // Adds a message at the bottom. A view that's scrolled up stays where it is.
+    pub fn push(&mut self, message: Message) {
+        self.messages.push(message);
+        if self.scroll_offset > 0 {
+            self.new_below += 1;
+            self.unmeasured += 1;
+        }
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll_offset = self.scroll_offset.saturating_add(rows);
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll_offset = self.scroll_offset.saturating_sub(rows);
+        if self.scroll_offset == 0 {
+            self.scroll_to_bottom();
+        }
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_offset = 0;
+        self.new_below = 0;
+        self.unmeasured = 0;
+    }
+
+    // The rows on screen, `height` rows of `width` columns
+    pub fn visible_rows(
+        &mut self,
+        time_format: &str,
+        width: usize,
+        height: usize,
+    ) -> Vec<Line<'static>> {
+        if self.scroll_offset > 0 {
+            let start = self.messages.len().saturating_sub(self.unmeasured);
+            self.scroll_offset += self.messages[start..]
+                .iter_mut()
+                .map(|message| message.lines(time_format, width).len() + 1)
+                .sum::<usize>();
+        }
+        self.unmeasured = 0;
+
+        let (rows, scroll_offset) = layout::visible_rows(
+            &mut self.messages,
+            time_format,
+            width,
+            height,
+            self.scroll_offset,
+        );
+        if scroll_offset == 0 {
+            self.scroll_to_bottom();
+        }
+        self.scroll_offset = scroll_offset;
+        rows
+    }
+
+    // Whether a message with this server ID is already shown. Only messages
+    // from the current session count, as the server may reuse an earlier one's.
+    pub fn contains(&self, id: u64) -> bool {
+        self.messages
+            .iter()
+            .any(|message| !message.earlier_session && message.id == Some(id))
+    }
+
+    // Usernames are unique case-insensitively, so DM threads match the same way
+    pub fn is(&self, kind: ConversationKind, name: &str) -> bool {
+        self.kind == kind
+            && match kind {
+                ConversationKind::Room => self.name == name,
+                ConversationKind::Direct => self.name.eq_ignore_ascii_case(name),
+            }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::conversation::Conversation;
+use ratatui::{
+    Frame,
+    layout::Rect,
+    prelude::Stylize,
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Block, Padding, Paragraph},
+};
+
+pub struct ConversationsWidget<'a> {
+    pub conversations: &'a [Conversation],
+    pub active: usize,
+}
+
+impl ConversationsWidget<'_> {
+    // Title line plus top and bottom padding
+    const CHROME_HEIGHT: u16 = 3;
+
+    pub fn height(&self) -> u16 {
+        self.conversations.len() as u16 + Self::CHROME_HEIGHT
+    }
+
+    pub fn render(&self, frame: &mut Frame, area: Rect) {
+        const BG_PRIMARY: Color = Color::Rgb(0, 0, 0);
+        const TEXT_PRIMARY: Color = Color::Rgb(255, 255, 255);
+        const TEXT_SECONDARY: Color = Color::Rgb(128, 128, 128);
+        const TEXT_ACTIVE: Color = Color::Cyan;
+
+        let mut lines = vec![Line::from(Span::styled(
+            "Conversations (Alt+1..9, Ctrl+N/P)",
+            Style::default().bold(),
+        ))];
+
+        for (i, conversation) in self.conversations.iter().enumerate() {
+            let number = if i < 9 {
+                format!("{} ", i + 1)
+            } else {
+                "  ".to_string()
+            };
+            let mut spans = vec![Span::styled(number, Style::default().fg(TEXT_SECONDARY))];
+
+            if i == self.active {
+                spans.push(Span::styled(
+                    conversation.title(),
+                    Style::default().fg(TEXT_ACTIVE).bold(),
+                ));
+            } else {
+                spans.push(Span::from(conversation.title()));
+            }
+
+            if conversation.unread > 0 {
+                spans.push(Span::styled(
+                    format!(" ({})", conversation.unread),
+                    Style::default().bold(),
+                ));
+            }
+            lines.push(Line::from(spans));
+        }
+
+        let widget = Paragraph::new(lines)
+            .style(Style::default().fg(TEXT_PRIMARY))
+            .bg(BG_PRIMARY)
+            .block(Block::new().padding(Padding {
+                left: 0,
+                right: 0,
+                top: 1,
+                bottom: 1,
+            }));
+
+        frame.render_widget(widget, area);
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+// Messages kept per conversation; older ones are forgotten
+const MAX_ENTRIES: usize = 200;
+
+// Commands that carry a password, which is never written to the history file
+const SECRET_COMMANDS: &[&str] = &["/oper"];
+
+pub fn default_history_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tcptalk").join("history"))
+}
+
+// What the user has sent in each conversation, oldest first, so it can be
+// recalled with Up and Down or searched with Ctrl+R
+#[derive(Default)]
+pub struct InputHistory {
+    // Where entries are saved; `None` keeps them for this session only
+    path: Option<PathBuf>,
+    entries: HashMap<String, Vec<String>>,
+    // Lines in the file, which is only appended to until it gets well past
+    // what's kept
+    lines_in_file: usize,
+}
+
+impl InputHistory {
+    // Reads the history saved at `path`, which needn't exist yet
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut history = Self {
+            path: Some(path),
+            ..Self::default()
+        };
+
+        for line in contents.lines() {
+            history.lines_in_file += 1;
+            if let Some((conversation, text)) = line.split_once('\t') {
+                history.remember(conversation, unescape(text));
+            }
+        }
+        Ok(history)
+    }
+
+    pub fn entries(&self, conversation: &str) -> &[String] {
+        self.entries
+            .get(conversation)
+            .map_or(&[], |entries| entries)
+    }
+
+    // Adds something the user sent in `conversation` and saves it. Sending the
+    // same thing twice in a row is only remembered once, and commands with
+    // secrets in them aren't remembered at all.
+    pub fn push(&mut self, conversation: &str, text: String) -> io::Result<()> {
+        let command = text.split(char::is_whitespace).next().unwrap_or_default();
+        if SECRET_COMMANDS
+            .iter()
+            .any(|secret| command.eq_ignore_ascii_case(secret))
+        {
+            return Ok(());
+        }
+        if self.entries(conversation).last() == Some(&text) {
+            return Ok(());
+        }
+        let line = format!("{}\t{}", conversation, escape(&text));
+        self.remember(conversation, text);
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if self.lines_in_file >= 2 * self.entries.values().map(Vec::len).sum::<usize>() {
+            return self.rewrite();
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", line)?;
+        self.lines_in_file += 1;
+        Ok(())
+    }
+
+    fn remember(&mut self, conversation: &str, text: String) {
+        let entries = self.entries.entry(conversation.to_string()).or_default();
+        entries.push(text);
+        if entries.len() > MAX_ENTRIES {
+            entries.remove(0);
+        }
+    }
+
+    // Replaces the file with just the entries that are kept
+    fn rewrite(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for (conversation, entries) in &self.entries {
+            for text in entries {
+                contents.push_str(&format!("{}\t{}\n", conversation, escape(text)));
+            }
+        }
+        fs::write(path, contents)?;
+        self.lines_in_file = self.entries.values().map(Vec::len).sum();
+        Ok(())
+    }
+
+    // The newest entry in `conversation` older than `before` that contains
+    // `query`, as its index and the bytes of the entry that matched
+    pub fn search(
+        &self,
+        conversation: &str,
+        query: &str,
+        before: usize,
+    ) -> Option<(usize, Range<usize>)> {
+        let entries = self.entries(conversation);
+        entries[..before.min(entries.len())]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, entry)| {
+                let (offset, matched) = entry.match_indices(query).next()?;
+                Some((index, offset..offset + matched.len()))
+            })
+    }
+}
+
+// Messages can span lines, but each entry in the file is one line
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// Where the user is in a conversation's history while pressing Up and Down
+pub struct Recall {
+    pub index: usize,
+    // What was in the input before recalling started, restored by going back
+    // down past the newest entry
+    pub draft: String,
+}
+
+// A Ctrl+R search through a conversation's history
+pub struct Search {
+    pub query: String,
+    // The matching entry's index and the bytes of it that matched
+    pub found: Option<(usize, Range<usize>)>,
+    // What was in the input before searching, restored if it's cancelled
+    pub original: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tcptalk-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn multi_line_entries_survive_a_reload() {
+        let path = temp_file("history-reload");
+        let mut history = InputHistory::load(path.clone()).unwrap();
+        history
+            .push("#lobby", "fn main() {\n    \\n\n}".to_string())
+            .unwrap();
+        history.push("@bob", "hi".to_string()).unwrap();
+
+        let history = InputHistory::load(path.clone()).unwrap();
+        assert_eq!(history.entries("#lobby"), ["fn main() {\n    \\n\n}"]);
+        assert_eq!(history.entries("@bob"), ["hi"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_newest_entries_when_full() {
+        let path = temp_file("history-cap");
+        let mut history = InputHistory::load(path.clone()).unwrap();
+        for i in 0..MAX_ENTRIES * 3 {
+            history.push("#lobby", i.to_string()).unwrap();
+        }
+
+        let history = InputHistory::load(path.clone()).unwrap();
+        let entries = history.entries("#lobby");
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries.last().unwrap(), &(MAX_ENTRIES * 3 - 1).to_string());
+        assert!(history.lines_in_file <= 2 * MAX_ENTRIES);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn never_saves_the_operator_password() {
+        let path = temp_file("history-secret");
+        let mut history = InputHistory::load(path.clone()).unwrap();
+        history.push("#lobby", "/oper secret".to_string()).unwrap();
+        history.push("#lobby", "/OPER secret".to_string()).unwrap();
+        history.push("#lobby", "/join #ops".to_string()).unwrap();
+
+        assert_eq!(history.entries("#lobby"), ["/join #ops"]);
+        assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn searches_from_the_newest_entry_back() {
+        let mut history = InputHistory::default();
+        for text in ["deploy staging", "hello", "deploy prod"] {
+            history.push("#ops", text.to_string()).unwrap();
+        }
+        assert_eq!(history.search("#ops", "deploy", 3), Some((2, 0..6)));
+        assert_eq!(history.search("#ops", "deploy", 2), Some((0, 0..6)));
+        assert_eq!(history.search("#ops", "llo", 3), Some((1, 2..5)));
+        assert_eq!(history.search("#ops", "deploy", 0), None);
+        assert_eq!(history.search("#lobby", "deploy", 3), None);
+    }
+}
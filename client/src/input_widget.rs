@@ -5,16 +5,26 @@ use ratatui::{
     prelude::Stylize,
     style::{Color, Style},
     text::{Line, Span},
-    widgets::{Block, BorderType, Borders, Padding, Paragraph, Wrap},
+    widgets::{Block, BorderType, Borders, Padding, Paragraph},
 };
-use std::io;
+use std::{io, ops::Range};
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
 
 pub struct InputWidget {
     pub text: String,
+    // Byte offset into `text`, always on a grapheme cluster boundary
     pub cursor_position: usize,
     pub cursor_visible: bool,
     pub last_input_time: std::time::Instant,
     pub username: String,
+    pub room: String,
+    // Part of `text` to highlight, e.g. what matched a history search
+    pub highlight: Option<Range<usize>>,
+    // While searching the history: what's been typed, and whether it matched
+    pub search: Option<(String, bool)>,
+    // Why what was entered couldn't be used, e.g. a mistyped command
+    pub error: Option<String>,
 }
 
 impl InputWidget {
@@ -25,6 +35,10 @@ impl InputWidget {
             cursor_visible: true,
             last_input_time: std::time::Instant::now(),
             username,
+            room: String::new(),
+            highlight: None,
+            search: None,
+            error: None,
         }
     }
 
@@ -33,6 +47,73 @@ impl InputWidget {
         self.cursor_position = 0;
     }
 
+    // Removes and returns the current text, e.g. to keep it as a draft
+    pub fn take_text(&mut self) -> String {
+        self.cursor_position = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    pub fn set_text(&mut self, text: String) {
+        self.cursor_position = text.len();
+        self.text = text;
+    }
+
+    // Puts the cursor at `offset`, or at the start of the grapheme cluster it's
+    // inside of
+    pub fn set_cursor(&mut self, offset: usize) {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        self.cursor_position = offset;
+        if !self.is_boundary(offset) {
+            self.cursor_position = self.prev_boundary(offset);
+        }
+    }
+
+    // Highlights `range`, widened to whole grapheme clusters, and puts the
+    // cursor at its start
+    pub fn highlight_match(&mut self, range: Range<usize>) {
+        self.set_cursor(range.start);
+        let end = range.end.clamp(self.cursor_position, self.text.len());
+        let end = if self.text.is_char_boundary(end) && self.is_boundary(end) {
+            end
+        } else {
+            self.next_boundary(end)
+        };
+        self.highlight = Some(self.cursor_position..end);
+    }
+
+    // Moves the cursor to the line above or below, keeping its column where
+    // that line is long enough. Returns false if there's no line to move to.
+    pub fn move_cursor_line(&mut self, up: bool) -> bool {
+        let line_start = self.text[..self.cursor_position]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let column = self.text[line_start..self.cursor_position]
+            .graphemes(true)
+            .count();
+        let target_start = if up {
+            if line_start == 0 {
+                return false;
+            }
+            self.text[..line_start - 1].rfind('\n').map_or(0, |i| i + 1)
+        } else {
+            match self.text[self.cursor_position..].find('\n') {
+                Some(i) => self.cursor_position + i + 1,
+                None => return false,
+            }
+        };
+        let target_end = self.text[target_start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| target_start + i);
+        self.cursor_position = self.text[target_start..target_end]
+            .grapheme_indices(true)
+            .nth(column)
+            .map_or(target_end, |(i, _)| target_start + i);
+        true
+    }
+
     pub fn is_empty(&self) -> bool {
         self.text.trim().is_empty()
     }
@@ -41,16 +122,45 @@ impl InputWidget {
         self.text.clone()
     }
 
-    fn move_cursor_left(&mut self) {
-        if self.cursor_position > 0 {
-            self.cursor_position -= 1;
+    // The grapheme boundary before `offset`, so the cursor never splits a
+    // character and the combining marks or emoji modifiers that go with it
+    fn prev_boundary(&self, offset: usize) -> usize {
+        GraphemeCursor::new(offset, self.text.len(), true)
+            .prev_boundary(&self.text, 0)
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    fn is_boundary(&self, offset: usize) -> bool {
+        GraphemeCursor::new(offset, self.text.len(), true)
+            .is_boundary(&self.text, 0)
+            .unwrap_or(true)
+    }
+
+    fn next_boundary(&self, offset: usize) -> usize {
+        GraphemeCursor::new(offset, self.text.len(), true)
+            .next_boundary(&self.text, 0)
+            .ok()
+            .flatten()
+            .unwrap_or(self.text.len())
+    }
+
+    // Typing a combining mark or a second flag letter can merge the character
+    // before the cursor with the one after it, so edits move the cursor past
+    // whatever it ended up inside
+    fn snap_cursor_to_boundary(&mut self) {
+        if !self.is_boundary(self.cursor_position) {
+            self.cursor_position = self.next_boundary(self.cursor_position);
         }
     }
 
+    fn move_cursor_left(&mut self) {
+        self.cursor_position = self.prev_boundary(self.cursor_position);
+    }
+
     fn move_cursor_right(&mut self) {
-        if self.cursor_position < self.text.len() {
-            self.cursor_position += 1;
-        }
+        self.cursor_position = self.next_boundary(self.cursor_position);
     }
 
     fn move_cursor_to_start(&mut self) {
@@ -61,74 +171,31 @@ impl InputWidget {
         self.cursor_position = self.text.len();
     }
 
-    fn move_cursor_word_left(&mut self) {
-        if self.cursor_position == 0 {
-            return;
-        }
+    // Where the word before the cursor starts, skipping separators right before it
+    fn word_start_before_cursor(&self) -> usize {
+        self.text[..self.cursor_position]
+            .grapheme_indices(true)
+            .rev()
+            .skip_while(|(_, grapheme)| is_separator(grapheme))
+            .find(|(_, grapheme)| is_separator(grapheme))
+            .map_or(0, |(i, grapheme)| i + grapheme.len())
+    }
 
-        let text_before_cursor = &self.text[..self.cursor_position];
-        let trimmed = text_before_cursor.trim_end_matches(|c: char| {
-            c.is_whitespace()
-                || c == '\''
-                || c == '"'
-                || c == ';'
-                || c == ','
-                || c == '.'
-                || c == '!'
-                || c == '?'
-        });
-
-        if let Some(last_boundary_pos) = trimmed.rfind(|c: char| {
-            c.is_whitespace()
-                || c == '\''
-                || c == '"'
-                || c == ';'
-                || c == ','
-                || c == '.'
-                || c == '!'
-                || c == '?'
-        }) {
-            self.cursor_position = last_boundary_pos + 1;
-        } else {
-            self.cursor_position = 0;
-        }
+    // Where the next word after the cursor starts
+    fn word_start_after_cursor(&self) -> usize {
+        self.text[self.cursor_position..]
+            .grapheme_indices(true)
+            .skip_while(|(_, grapheme)| !is_separator(grapheme))
+            .find(|(_, grapheme)| !is_separator(grapheme))
+            .map_or(self.text.len(), |(i, _)| self.cursor_position + i)
     }
 
-    fn move_cursor_word_right(&mut self) {
-        if self.cursor_position >= self.text.len() {
-            return;
-        }
+    fn move_cursor_word_left(&mut self) {
+        self.cursor_position = self.word_start_before_cursor();
+    }
 
-        let text_after_cursor = &self.text[self.cursor_position..];
-        if let Some(next_boundary_pos) = text_after_cursor.find(|c: char| {
-            c.is_whitespace()
-                || c == '\''
-                || c == '"'
-                || c == ';'
-                || c == ','
-                || c == '.'
-                || c == '!'
-                || c == '?'
-        }) {
-            let new_pos = self.cursor_position + next_boundary_pos;
-            let remaining = &self.text[new_pos..];
-            if let Some(non_boundary_pos) = remaining.find(|c: char| {
-                !(c.is_whitespace()
-                    || c == '\''
-                    || c == '"'
-                    || c == ';'
-                    || c == ','
-                    || c == '.'
-                    || c == '!'
-                    || c == '?')
-            }) {
-                self.cursor_position = new_pos + non_boundary_pos;
-            } else {
-                self.cursor_position = self.text.len();
-            }
-        } else {
-            self.cursor_position = self.text.len();
-        }
+    fn move_cursor_word_right(&mut self) {
+        self.cursor_position = self.word_start_after_cursor();
     }
 
     pub fn handle_key_event(&mut self, key_event: crossterm::event::KeyEvent) -> io::Result<bool> {
@@ -144,7 +211,7 @@ impl InputWidget {
                 if ctrl_c_quit {
                     return Ok(true); // Signal to quit
                 } else if win_del {
-                    self.text.drain(0..self.cursor_position);
+                    self.text.drain(..self.cursor_position);
                     self.cursor_position = 0;
                 } else if ctrl_lft {
                     self.move_cursor_to_start();
@@ -154,57 +221,29 @@ impl InputWidget {
                     self.move_cursor_word_right();
                 } else if alt_rht {
                     self.move_cursor_word_left();
-                } else {
+                } else if !c.is_control() {
                     self.text.insert(self.cursor_position, c);
-                    self.cursor_position += 1;
+                    self.cursor_position += c.len_utf8();
+                    self.snap_cursor_to_boundary();
                 }
 
                 self.last_input_time = std::time::Instant::now();
             }
             KeyCode::Backspace => {
-                if key_event.modifiers.contains(KeyModifiers::ALT) {
-                    if self.cursor_position > 0 {
-                        let text_before_cursor = &self.text[..self.cursor_position];
-                        let trimmed = text_before_cursor.trim_end_matches(|c: char| {
-                            c.is_whitespace()
-                                || c == '\''
-                                || c == '"'
-                                || c == ';'
-                                || c == ','
-                                || c == '.'
-                                || c == '!'
-                                || c == '?'
-                        });
-
-                        if let Some(last_boundary_pos) = trimmed.rfind(|c: char| {
-                            c.is_whitespace()
-                                || c == '\''
-                                || c == '"'
-                                || c == ';'
-                                || c == ','
-                                || c == '.'
-                                || c == '!'
-                                || c == '?'
-                        }) {
-                            self.text.drain(last_boundary_pos + 1..self.cursor_position);
-                            self.cursor_position = last_boundary_pos + 1;
-                        } else {
-                            self.text.drain(0..self.cursor_position);
-                            self.cursor_position = 0;
-                        }
-                    }
+                let start = if key_event.modifiers.contains(KeyModifiers::ALT) {
+                    self.word_start_before_cursor()
                 } else {
-                    if self.cursor_position > 0 {
-                        self.text.remove(self.cursor_position - 1);
-                        self.cursor_position -= 1;
-                    }
-                }
+                    self.prev_boundary(self.cursor_position)
+                };
+                self.text.drain(start..self.cursor_position);
+                self.cursor_position = start;
+                self.snap_cursor_to_boundary();
                 self.last_input_time = std::time::Instant::now();
             }
             KeyCode::Delete => {
-                if self.cursor_position < self.text.len() {
-                    self.text.remove(self.cursor_position);
-                }
+                let end = self.next_boundary(self.cursor_position);
+                self.text.drain(self.cursor_position..end);
+                self.snap_cursor_to_boundary();
                 self.last_input_time = std::time::Instant::now();
             }
             KeyCode::Left => {
@@ -228,6 +267,10 @@ impl InputWidget {
                 self.last_input_time = std::time::Instant::now();
             }
             KeyCode::Enter => {
+                if inserts_line_break(&key_event) {
+                    self.text.insert(self.cursor_position, '\n');
+                    self.cursor_position += 1;
+                }
                 self.last_input_time = std::time::Instant::now();
             }
             _ => {}
@@ -244,48 +287,91 @@ impl InputWidget {
         }
     }
 
-    pub fn render(&self, frame: &mut Frame, input_area: Rect, info_area: Rect) {
-        let before_cursor = &self.text[..self.cursor_position];
-
-        let mut input_spans = vec![Span::from(before_cursor.to_string())];
-
-        if self.cursor_position < self.text.len() {
-            let char_at_cursor = &self.text[self.cursor_position..self.cursor_position + 1];
-            if self.cursor_visible {
-                input_spans.push(Span::styled(
-                    char_at_cursor,
-                    Style::default().fg(Color::Cyan).bg(Color::Rgb(0, 100, 100)),
-                ));
-            } else {
-                input_spans.push(Span::from(char_at_cursor));
-            }
-            input_spans.push(Span::from(&self.text[self.cursor_position + 1..]));
-        } else {
-            if self.cursor_visible {
-                input_spans.push(Span::styled("█", Style::default().fg(Color::Cyan)));
-            }
+    // The text in `row`, split up so the highlight and the cursor, if it's on
+    // this row, can be styled
+    fn row_spans(&self, row: Range<usize>, has_cursor: bool) -> Vec<Span<'_>> {
+        let cursor_style = Style::default().fg(Color::Cyan).bg(Color::Rgb(0, 100, 100));
+        let highlight_style = Style::default().fg(Color::Black).bg(Color::Cyan);
+        let text_end = row.end - usize::from(self.text[row.clone()].ends_with('\n'));
+        let highlight = self.highlight.clone().unwrap_or(0..0);
+        let cursor_end = self.next_boundary(self.cursor_position);
+        // At the end of the text or of a line, the cursor is a block after it
+        let block_cursor = cursor_end == self.cursor_position
+            || self.text[self.cursor_position..].starts_with('\n');
+
+        let mut cuts = vec![row.start, text_end, highlight.start, highlight.end];
+        if has_cursor {
+            cuts.extend([self.cursor_position, cursor_end]);
         }
+        cuts.retain(|cut| (row.start..=text_end).contains(cut));
+        cuts.sort_unstable();
+        cuts.dedup();
 
-        let input_paragraph = Paragraph::new(vec![Line::from(input_spans)])
-            .block(
-                Block::new()
-                    .borders(Borders::LEFT)
-                    .border_type(BorderType::Thick)
-                    .padding(Padding {
-                        left: 1,
-                        right: 3,
-                        top: 0,
-                        bottom: 0,
-                    }),
-            )
-            .wrap(Wrap { trim: true });
+        let mut spans: Vec<Span> = cuts
+            .windows(2)
+            .map(|cut| {
+                let (start, end) = (cut[0], cut[1]);
+                let on_cursor = has_cursor && !block_cursor && start == self.cursor_position;
+                let style = if on_cursor && self.cursor_visible {
+                    cursor_style
+                } else if highlight.start <= start && end <= highlight.end {
+                    highlight_style
+                } else {
+                    Style::default()
+                };
+                Span::styled(&self.text[start..end], style)
+            })
+            .collect();
+        if has_cursor && block_cursor && self.cursor_visible {
+            spans.push(Span::styled("█", Style::default().fg(Color::Cyan)));
+        }
+        spans
+    }
+
+    pub fn render(&self, frame: &mut Frame, input_area: Rect, info_area: Rect) {
+        // Wrapped here rather than by the paragraph, so the height from
+        // `calculate_height` always matches what's drawn
+        let rows = wrap(&self.text, text_width(input_area.width));
+        let last_row = rows.len() - 1;
+        // A cursor after the last character is on the last row
+        let cursor_row = rows
+            .iter()
+            .position(|row| row.contains(&self.cursor_position))
+            .unwrap_or(last_row);
+        let lines: Vec<Line> = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| Line::from(self.row_spans(row, i == cursor_row)))
+            .collect();
 
+        // Keep the cursor in view when there are more rows than fit
+        let scroll = cursor_row.saturating_sub(input_area.height.saturating_sub(1) as usize);
+        let input_paragraph = Paragraph::new(lines).scroll((scroll as u16, 0)).block(
+            Block::new()
+                .borders(Borders::LEFT)
+                .border_type(BorderType::Thick)
+                .padding(Padding {
+                    left: 1,
+                    right: 3,
+                    top: 0,
+                    bottom: 0,
+                }),
+        );
+
+        let info = match (&self.error, &self.search) {
+            (Some(error), _) => Span::from(error.as_str()).fg(Color::Rgb(220, 80, 80)),
+            (None, Some((query, true))) => Span::from(format!("(reverse-i-search)`{}'", query)),
+            (None, Some((query, false))) => {
+                Span::from(format!("(failed reverse-i-search)`{}'", query))
+            }
+            (None, None) => Span::from(format!(
+                "Sending message as {} in {}",
+                self.username, self.room
+            )),
+        };
         let input_info = Paragraph::new(vec![
             Line::from(""),
-            Line::from(
-                Span::from(format!("Sending message as {}", self.username))
-                    .style(Style::default().bold()),
-            ),
+            Line::from(info.bold()),
             Line::from(""),
         ])
         .block(Block::new().padding(Padding {
@@ -307,9 +393,293 @@ impl InputWidget {
         frame.render_widget(input_info, info_area);
     }
 
-    pub fn calculate_height(&self, available_width: u16) -> u16 {
-        let text_width = self.text.len() as u16 + 1;
-        let lines_needed = std::cmp::max(1, (text_width + available_width - 1) / available_width);
-        std::cmp::max(3, lines_needed)
+    // Rows the input area needs at `area_width` columns wide: at least three,
+    // growing with the text up to `MAX_ROWS`
+    pub fn calculate_height(&self, area_width: u16) -> u16 {
+        let rows = wrap(&self.text, text_width(area_width)).len();
+        rows.clamp(3, MAX_ROWS) as u16
+    }
+}
+
+// The input area grows to fit this many rows, then scrolls
+const MAX_ROWS: usize = 10;
+
+// Shift+Enter or Alt+Enter starts a new line instead of sending. Terminals that
+// don't report modifiers with Enter need Alt+Enter.
+pub fn inserts_line_break(key_event: &crossterm::event::KeyEvent) -> bool {
+    key_event.code == KeyCode::Enter
+        && key_event
+            .modifiers
+            .intersects(KeyModifiers::SHIFT | KeyModifiers::ALT)
+}
+
+// Columns left for text in an input area `area_width` wide: it's drawn one
+// column in, with a border and a column of padding on the left and three
+// columns of padding on the right
+fn text_width(area_width: u16) -> usize {
+    area_width.saturating_sub(6) as usize
+}
+
+// Characters that separate words for word-wise movement and deletion
+fn is_separator(grapheme: &str) -> bool {
+    grapheme
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_whitespace() || matches!(c, '\'' | '"' | ';' | ',' | '.' | '!' | '?'))
+}
+
+// Splits `text` into rows of at most `width` columns, never inside a grapheme
+// cluster. Wide characters take two columns, combining marks none. A line break
+// ends its row, and takes a column so the cursor can sit on it. Room is left at
+// the end for the cursor too, so the result is never empty. A grapheme wider
+// than `width` gets a row to itself.
+fn wrap(text: &str, width: usize) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        let line_break = grapheme == "\n";
+        let grapheme_width = if line_break { 1 } else { grapheme.width() };
+        if used + grapheme_width > width && i > start {
+            rows.push(start..i);
+            start = i;
+            used = 0;
+        }
+        used += grapheme_width;
+        if line_break {
+            rows.push(start..i + 1);
+            start = i + 1;
+            used = 0;
+        }
+    }
+    // The cursor after the last character takes a column too
+    if used + 1 > width && text.len() > start {
+        rows.push(start..text.len());
+        start = text.len();
+    }
+    rows.push(start..text.len());
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyEvent;
+    use proptest::prelude::*;
+
+    // Mostly characters that are hard to get right: accents, combining marks,
+    // CJK, emoji with modifiers and joiners, and flag letters
+    fn any_char() -> impl Strategy<Value = char> {
+        prop_oneof![
+            proptest::char::range('a', 'z'),
+            Just(' '),
+            Just('.'),
+            Just('\n'),
+            Just('é'),
+            Just('\u{301}'),
+            Just('\u{308}'),
+            proptest::char::range('\u{4e00}', '\u{4e20}'),
+            Just('👍'),
+            Just('\u{1f3fd}'),
+            Just('\u{200d}'),
+            Just('👩'),
+            Just('🇺'),
+            Just('🇸'),
+            any::<char>(),
+        ]
+    }
+
+    fn any_text() -> impl Strategy<Value = String> {
+        proptest::collection::vec(any_char(), 0..40).prop_map(|chars| {
+            chars
+                .into_iter()
+                .filter(|c| !c.is_control() || *c == '\n')
+                .collect()
+        })
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    fn any_key() -> impl Strategy<Value = KeyEvent> {
+        prop_oneof![
+            4 => any_char().prop_map(|c| key(KeyCode::Char(c), KeyModifiers::NONE)),
+            1 => Just(key(KeyCode::Enter, KeyModifiers::ALT)),
+            1 => Just(key(KeyCode::Enter, KeyModifiers::SHIFT)),
+            1 => Just(key(KeyCode::Backspace, KeyModifiers::NONE)),
+            1 => Just(key(KeyCode::Backspace, KeyModifiers::ALT)),
+            1 => Just(key(KeyCode::Delete, KeyModifiers::NONE)),
+            1 => Just(key(KeyCode::Left, KeyModifiers::NONE)),
+            1 => Just(key(KeyCode::Right, KeyModifiers::NONE)),
+            1 => Just(key(KeyCode::Left, KeyModifiers::ALT)),
+            1 => Just(key(KeyCode::Right, KeyModifiers::ALT)),
+            1 => Just(key(KeyCode::Char('a'), KeyModifiers::CONTROL)),
+            1 => Just(key(KeyCode::Char('e'), KeyModifiers::CONTROL)),
+            1 => Just(key(KeyCode::Char('u'), KeyModifiers::CONTROL)),
+        ]
+    }
+
+    fn typed(text: &str) -> InputWidget {
+        let mut widget = InputWidget::new("alice".to_string());
+        for c in text.chars() {
+            let key = match c {
+                '\n' => key(KeyCode::Enter, KeyModifiers::SHIFT),
+                c => key(KeyCode::Char(c), KeyModifiers::NONE),
+            };
+            widget.handle_key_event(key).unwrap();
+        }
+        widget
+    }
+
+    fn press(widget: &mut InputWidget, code: KeyCode, modifiers: KeyModifiers) {
+        widget.handle_key_event(key(code, modifiers)).unwrap();
+    }
+
+    fn is_grapheme_boundary(text: &str, offset: usize) -> bool {
+        GraphemeCursor::new(offset, text.len(), true)
+            .is_boundary(text, 0)
+            .unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn cursor_stays_on_a_grapheme_boundary(keys in proptest::collection::vec(any_key(), 0..60)) {
+            let mut widget = InputWidget::new("alice".to_string());
+            for key in keys {
+                widget.handle_key_event(key).unwrap();
+                prop_assert!(widget.cursor_position <= widget.text.len());
+                prop_assert!(is_grapheme_boundary(&widget.text, widget.cursor_position));
+            }
+        }
+
+        #[test]
+        fn typing_inserts_the_text(text in any_text()) {
+            let widget = typed(&text);
+            prop_assert_eq!(&widget.text, &text);
+            prop_assert_eq!(widget.cursor_position, text.len());
+        }
+
+        #[test]
+        fn backspace_removes_the_last_grapheme(text in any_text()) {
+            let mut widget = typed(&text);
+            press(&mut widget, KeyCode::Backspace, KeyModifiers::NONE);
+            let start = text.grapheme_indices(true).next_back().map_or(0, |(i, _)| i);
+            prop_assert_eq!(&widget.text, &text[..start]);
+        }
+
+        #[test]
+        fn left_steps_over_one_grapheme_at_a_time(text in any_text()) {
+            let mut widget = typed(&text);
+            let mut stops = Vec::new();
+            while widget.cursor_position > 0 {
+                press(&mut widget, KeyCode::Left, KeyModifiers::NONE);
+                stops.push(widget.cursor_position);
+            }
+            let starts: Vec<usize> = text.grapheme_indices(true).rev().map(|(i, _)| i).collect();
+            prop_assert_eq!(stops, starts);
+        }
+
+        #[test]
+        fn right_undoes_left(text in any_text(), lefts in 0usize..50) {
+            let mut widget = typed(&text);
+            let mut positions = vec![widget.cursor_position];
+            for _ in 0..lefts.min(text.graphemes(true).count()) {
+                press(&mut widget, KeyCode::Left, KeyModifiers::NONE);
+                positions.push(widget.cursor_position);
+            }
+            for expected in positions.into_iter().rev() {
+                prop_assert_eq!(widget.cursor_position, expected);
+                press(&mut widget, KeyCode::Right, KeyModifiers::NONE);
+            }
+        }
+
+        #[test]
+        fn word_jumps_land_on_word_starts(text in any_text(), lefts in 0usize..50) {
+            let mut widget = typed(&text);
+            for _ in 0..lefts {
+                press(&mut widget, KeyCode::Left, KeyModifiers::NONE);
+            }
+            let is_word_start = |offset: usize| {
+                let after = text[offset..].graphemes(true).next();
+                let before = text[..offset].graphemes(true).next_back();
+                offset == 0
+                    || offset == text.len()
+                    || (after.is_some_and(|g| !is_separator(g)) && before.is_some_and(is_separator))
+            };
+
+            let mut right = typed(&text);
+            right.cursor_position = widget.cursor_position;
+            press(&mut right, KeyCode::Right, KeyModifiers::ALT);
+            prop_assert!(right.cursor_position >= widget.cursor_position);
+            prop_assert!(is_word_start(right.cursor_position));
+
+            press(&mut widget, KeyCode::Left, KeyModifiers::ALT);
+            prop_assert!(is_word_start(widget.cursor_position));
+        }
+
+        #[test]
+        fn wrapped_rows_fit_and_cover_the_text(text in any_text(), width in 0usize..30) {
+            let rows = wrap(&text, width);
+            prop_assert_eq!(rows.first().unwrap().start, 0);
+            prop_assert_eq!(rows.last().unwrap().end, text.len());
+            for pair in rows.windows(2) {
+                prop_assert_eq!(pair[0].end, pair[1].start);
+            }
+            // A line break only ever ends a row, and takes a column like the
+            // cursor after the last row
+            let columns = |row: &str| match row.strip_suffix('\n') {
+                Some(row) => row.width() + 1,
+                None => row.width(),
+            };
+            for row in &rows {
+                let row_text = &text[row.clone()];
+                prop_assert!(is_grapheme_boundary(&text, row.start));
+                prop_assert!(!row_text.trim_end_matches('\n').contains('\n'));
+                prop_assert!(columns(row_text) <= width.max(1) || row_text.graphemes(true).count() == 1);
+            }
+            let last = &text[rows.last().unwrap().clone()];
+            prop_assert!(!last.contains('\n'));
+            prop_assert!(last.width() < width.max(1) || last.graphemes(true).count() <= 1);
+
+            let widget = typed(&text);
+            let area_width = (width + 6) as u16;
+            prop_assert_eq!(
+                widget.calculate_height(area_width) as usize,
+                rows.len().clamp(3, MAX_ROWS)
+            );
+        }
+    }
+
+    #[test]
+    fn modified_enter_starts_a_new_line() {
+        let mut widget = typed("one");
+        press(&mut widget, KeyCode::Enter, KeyModifiers::ALT);
+        press(&mut widget, KeyCode::Char('2'), KeyModifiers::NONE);
+        press(&mut widget, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(widget.text, "one\n2");
+        assert_eq!(wrap(&widget.text, 20), vec![0..4, 4..5]);
+    }
+
+    #[test]
+    fn up_and_down_keep_the_column_between_lines() {
+        let mut widget = typed("世界!\nab\nabcd");
+        widget.cursor_position = widget.text.len() - 1;
+        assert!(widget.move_cursor_line(true));
+        assert_eq!(&widget.text[widget.cursor_position..], "\nabcd");
+        assert!(widget.move_cursor_line(true));
+        assert_eq!(&widget.text[widget.cursor_position..], "!\nab\nabcd");
+        assert!(!widget.move_cursor_line(true));
+        assert!(widget.move_cursor_line(false));
+        assert!(widget.move_cursor_line(false));
+        assert!(!widget.move_cursor_line(false));
+        assert_eq!(&widget.text[widget.cursor_position..], "cd");
+    }
+
+    #[test]
+    fn zero_width_area_does_not_panic() {
+        let widget = typed("héllo 世界 👍🏽");
+        assert!(widget.calculate_height(0) >= 3);
     }
 }
@@ -0,0 +1,245 @@
+use crate::app::Message;
+use ratatui::{
+    style::Style,
+    text::{Line, Span},
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Rows being filled in for one message
+struct Rows {
+    lines: Vec<Line<'static>>,
+    current: Vec<Span<'static>>,
+    // Columns used in the current row, including its indent
+    used: usize,
+    // Whether the current row has any text yet
+    has_text: bool,
+    // Whether the current row continues a line that was too long, rather than
+    // starting after a line break
+    wrapped: bool,
+    indent: usize,
+    width: usize,
+}
+
+impl Rows {
+    fn push(&mut self, grapheme: &str, style: Style) {
+        self.used += grapheme.width();
+        self.has_text = true;
+        self.wrapped = false;
+        match self.current.last_mut() {
+            Some(span) if span.style == style => span.content.to_mut().push_str(grapheme),
+            _ => self.current.push(Span::styled(grapheme.to_string(), style)),
+        }
+    }
+
+    fn fits(&self, width: usize) -> bool {
+        self.used + width <= self.width
+    }
+
+    fn new_row(&mut self, wrapped: bool) {
+        // Spaces where a line wraps aren't shown
+        if wrapped {
+            while let Some(span) = self.current.last_mut() {
+                let trimmed = span.content.trim_end().len();
+                if trimmed > 0 {
+                    span.content.to_mut().truncate(trimmed);
+                    break;
+                }
+                self.current.pop();
+            }
+        }
+        self.lines
+            .push(Line::from(std::mem::take(&mut self.current)));
+        self.used = self.indent;
+        if self.indent > 0 {
+            self.current.push(Span::raw(" ".repeat(self.indent)));
+        }
+        self.has_text = false;
+        self.wrapped = wrapped;
+    }
+}
+
+// Lays out a message as rows at most `width` columns wide. Text wraps at spaces
+// where it can, and line breaks in it start a new row. Every row after the first
+// is indented by `indent` columns, so the text lines up under where it started.
+pub fn wrap_message(spans: &[Span], indent: usize, width: usize) -> Vec<Line<'static>> {
+    let width = width.max(1);
+    // Indenting a narrow pane would leave too little room for the text
+    let indent = if indent * 2 > width { 0 } else { indent };
+    let graphemes: Vec<(&str, Style)> = spans
+        .iter()
+        .flat_map(|span| {
+            span.content
+                .graphemes(true)
+                .map(move |grapheme| (grapheme, span.style))
+        })
+        .collect();
+
+    let mut rows = Rows {
+        lines: Vec::new(),
+        current: Vec::new(),
+        used: 0,
+        has_text: false,
+        wrapped: false,
+        indent,
+        width,
+    };
+    let mut i = 0;
+    while i < graphemes.len() {
+        let (grapheme, style) = graphemes[i];
+        if grapheme == "\n" || grapheme == "\r\n" {
+            rows.new_row(false);
+            i += 1;
+            continue;
+        }
+        if grapheme.trim().is_empty() {
+            // Spaces at the start of a wrapped row are dropped, but indentation
+            // after a line break is kept
+            if !rows.fits(grapheme.width()) {
+                rows.new_row(true);
+            } else if !rows.wrapped {
+                rows.push(grapheme, style);
+            }
+            i += 1;
+            continue;
+        }
+
+        // A word goes on the next row if it doesn't fit on this one, and is only
+        // broken up if it doesn't fit on a row of its own either
+        let end = graphemes[i..]
+            .iter()
+            .position(|(grapheme, _)| grapheme.trim().is_empty())
+            .map_or(graphemes.len(), |len| i + len);
+        let word_width: usize = graphemes[i..end]
+            .iter()
+            .map(|(grapheme, _)| grapheme.width())
+            .sum();
+        if rows.has_text && !rows.fits(word_width) && indent + word_width <= width {
+            rows.new_row(true);
+        }
+        for &(grapheme, style) in &graphemes[i..end] {
+            if rows.has_text && !rows.fits(grapheme.width()) {
+                rows.new_row(true);
+            }
+            rows.push(grapheme, style);
+        }
+        i = end;
+    }
+    rows.new_row(false);
+    rows.lines
+}
+
+// The rows of `messages` that fit in `height` rows, ending `from_bottom` rows
+// above the last one, with a blank row between messages. Also returns
+// `from_bottom` limited so the view doesn't scroll past the first row.
+pub fn visible_rows(
+    messages: &mut [Message],
+    time_format: &str,
+    width: usize,
+    height: usize,
+    from_bottom: usize,
+) -> (Vec<Line<'static>>, usize) {
+    let messages: Vec<&[Line<'static>]> = messages
+        .iter_mut()
+        .map(|message| message.lines(time_format, width))
+        .collect();
+    let total = messages
+        .iter()
+        .map(|rows| rows.len() + 1)
+        .sum::<usize>()
+        .saturating_sub(1);
+    let from_bottom = from_bottom.min(total.saturating_sub(height));
+    let start = total.saturating_sub(height + from_bottom);
+
+    let blank = Line::default();
+    let rows = messages
+        .iter()
+        .enumerate()
+        .flat_map(|(i, rows)| (i > 0).then_some(&blank).into_iter().chain(rows.iter()))
+        .skip(start)
+        .take(height)
+        .cloned()
+        .collect();
+    (rows, from_bottom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(text: &str, indent: usize, width: usize) -> Vec<String> {
+        wrap_message(&[Span::raw(text)], indent, width)
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn wraps_at_spaces_under_a_hanging_indent() {
+        assert_eq!(
+            rows("bob: one two three four", 5, 14),
+            ["bob: one two", "     three", "     four"]
+        );
+    }
+
+    #[test]
+    fn keeps_spaces_after_a_line_break() {
+        assert_eq!(
+            rows("bob: fn main() {\n    run();\n}", 5, 40),
+            ["bob: fn main() {", "         run();", "     }"]
+        );
+    }
+
+    #[test]
+    fn breaks_words_longer_than_a_row() {
+        assert_eq!(rows("bob: abcdefghij", 5, 10), ["bob: abcde", "     fghij"]);
+    }
+
+    fn messages(count: usize) -> Vec<Message> {
+        (0..count)
+            .map(|i| Message::local("bob".to_string(), format!("message {}", i)))
+            .collect()
+    }
+
+    fn visible(
+        messages: &mut [Message],
+        height: usize,
+        from_bottom: usize,
+    ) -> (Vec<String>, usize) {
+        let (rows, from_bottom) = visible_rows(messages, "", 40, height, from_bottom);
+        let rows = rows.iter().map(|row| row.to_string()).collect();
+        (rows, from_bottom)
+    }
+
+    #[test]
+    fn shows_the_newest_rows_with_a_gap_between_messages() {
+        assert_eq!(
+            visible(&mut messages(5), 3, 0),
+            (
+                vec![
+                    " bob: message 3".into(),
+                    "".into(),
+                    " bob: message 4".into()
+                ],
+                0
+            )
+        );
+        assert_eq!(
+            visible(&mut messages(5), 3, 1),
+            (vec!["".into(), " bob: message 3".into(), "".into()], 1)
+        );
+    }
+
+    #[test]
+    fn stops_scrolling_at_the_first_row() {
+        let (rows, from_bottom) = visible(&mut messages(5), 3, 100);
+        assert_eq!(rows[0], " bob: message 0");
+        assert_eq!(from_bottom, 6);
+        assert_eq!(visible(&mut messages(1), 3, 100).1, 0);
+    }
+
+    #[test]
+    fn measures_wide_characters_by_display_width() {
+        assert_eq!(rows("世界世界", 0, 5), ["世界", "世界"]);
+    }
+}
@@ -1,20 +1,37 @@
 mod cli_args;
 use crate::cli_args::Args;
+use chrono::format::{Item, StrftimeItems};
 use clap::Parser;
-use serde_json;
+use tcptalk_protocol::{Frame, FrameError, FrameReader, Stream, write_frame};
 
 mod app;
-use crate::app::{App, Event};
+use crate::app::{App, Event, describe_shutdown};
 
 mod events;
-use crate::events::{handle_input_events, handle_server_messages, run_cursor_blink_thread};
+use crate::events::{handle_input_events, run_cursor_blink_thread};
 
+mod connection;
+use crate::connection::{Session, connect, run_connection};
+
+mod password;
+use crate::password::{new_password, read_password};
+
+mod history;
+use crate::history::InputHistory;
+
+mod tls;
+
+mod commands;
 mod connected_users_widget;
+mod conversation;
+mod conversations_widget;
 mod input_widget;
+mod layout;
 
 use std::{
-    io::{self, Read, Write},
-    net::TcpStream,
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, TcpStream},
     sync::{Arc, Mutex, mpsc},
     thread,
     time::Duration,
@@ -22,98 +39,170 @@ use std::{
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
-    let server_addr = format!("{}:2133", args.ip);
+
+    // chrono panics when rendering an invalid format, so reject it up front
+    if StrftimeItems::new(&args.time_format).any(|item| item == Item::Error) {
+        eprintln!("Invalid --time-format: {}", args.time_format);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid time format",
+        ));
+    }
 
     // Connect to server
-    let mut stream = match TcpStream::connect(&server_addr) {
-        Ok(stream) => {
-            println!("Connected to server at {}", server_addr);
-            stream
-        }
+    let (mut stream, resolved_addr) = match connect(&args.host, args.port)
+        .and_then(|(socket, resolved_addr)| Ok((secure(socket, &args)?, resolved_addr)))
+    {
+        Ok(connected) => connected,
         Err(e) => {
-            eprintln!("Failed to connect to server at {}: {}", server_addr, e);
-            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, e));
+            eprintln!(
+                "Failed to connect to server at {}:{}: {}",
+                args.host, args.port, e
+            );
+            return Err(e);
         }
     };
 
-    // Handle username handshake with server
-    let mut buf = [0u8; 1024];
-
-    // Read "Enter your username: " prompt from server
-    let n = stream.read(&mut buf)?;
-    let _prompt = String::from_utf8_lossy(&buf[..n]);
+    // Show the hostname alongside the address it resolved to, unless they are the same
+    let mut server_addr = if args.host.parse::<IpAddr>().is_ok() {
+        resolved_addr.to_string()
+    } else {
+        format!("{} ({})", args.host, resolved_addr)
+    };
+    if let Stream::Tls(_) = stream {
+        server_addr.push_str(" over TLS");
+    }
+    println!("Connected to server at {}", server_addr);
 
-    // Send username to server
-    let username_msg = format!("{}\n", args.username);
-    stream.write_all(username_msg.as_bytes())?;
-    stream.flush()?;
+    // Create separate streams for reading and writing to avoid deadlock
+    let mut read_stream = FrameReader::new(
+        stream
+            .try_clone()
+            .expect("Failed to clone stream for reading"),
+    );
 
-    // Read any initial server messages (like "Username cannot be empty" or welcome)
-    let mut initial_messages = Vec::new();
-    loop {
-        let n = stream.read(&mut buf)?;
-        if n == 0 {
-            break;
+    // Handle username handshake with server. The password is remembered for
+    // logging in again after a lost connection.
+    let mut password = None;
+    let login = if args.register {
+        let new_password = new_password(&args.username)?;
+        password = Some(new_password.clone());
+        Frame::Register {
+            username: args.username.clone(),
+            password: new_password,
         }
-        let response = String::from_utf8_lossy(&buf[..n]);
-        if response.contains("Username cannot be empty")
-            || response.contains("Username 'System' is reserved")
-            || response.contains("Username is already taken")
-        {
-            eprintln!("Server rejected username: {}", response.trim());
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, response.trim()));
+    } else {
+        Frame::Login {
+            username: args.username.clone(),
+            password: None,
         }
-        initial_messages.push(response.to_string());
-
-        // Check if there's more data available with a small timeout
-        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
-        match stream.read(&mut buf) {
-            Ok(0) | Err(_) => break,
-            Ok(n) => {
-                let more_response = String::from_utf8_lossy(&buf[..n]);
-                initial_messages.push(more_response.to_string());
+    };
+    write_frame(&mut stream, &login)?;
+
+    // Read any server notices until the username is accepted or rejected
+    let mut initial_messages = Vec::new();
+    let (username, resume_token) = loop {
+        let frame = match read_stream.read_frame() {
+            Ok(frame) => frame,
+            // TLS records start with content type 0x15 (alert) or 0x16 (handshake)
+            Err(FrameError::UnsupportedVersion(0x15 | 0x16))
+                if matches!(stream, Stream::Plain(_)) =>
+            {
+                eprintln!("The server only accepts TLS connections. Connect with --tls.");
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match frame {
+            // The server may have tidied the name up, so its version is the one
+            // messages come back under
+            Some(Frame::LoginAccepted {
+                username,
+                resume_token,
+            }) => break (username, resume_token),
+            Some(Frame::LoginRejected { reason }) => {
+                eprintln!("Login failed: {}", reason);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+            }
+            Some(Frame::PasswordRequired { username }) => {
+                password = Some(read_password(&username)?);
+                let login = Frame::Login {
+                    password: password.clone(),
+                    username,
+                };
+                write_frame(&mut stream, &login)?;
+            }
+            Some(Frame::Notice { text })
+            | Some(Frame::Error { message: text })
+            | Some(Frame::Disconnect { reason: text }) => {
+                initial_messages.push(text);
+            }
+            Some(Frame::ShuttingDown {
+                reason,
+                restart_in_secs,
+            }) => {
+                initial_messages.push(describe_shutdown(reason.as_deref(), restart_in_secs));
+            }
+            Some(_) => {}
+            None => {
+                for msg in &initial_messages {
+                    eprintln!("{}", msg);
+                }
+                eprintln!("Server closed the connection during login");
+                return Err(io::ErrorKind::UnexpectedEof.into());
             }
         }
-    }
-    stream.set_read_timeout(None)?; // Remove timeout
+    };
 
     crossterm::execute!(std::io::stdout(), crossterm::event::EnableMouseCapture)?;
 
-    // Create separate streams for reading and writing to avoid deadlock
-    let read_stream = stream
-        .try_clone()
-        .expect("Failed to clone stream for reading");
-    let write_stream = Arc::new(Mutex::new(stream));
+    let write_stream = Arc::new(Mutex::new(Some(stream)));
+
+    let history_file = args
+        .history_file
+        .clone()
+        .or_else(history::default_history_file);
+    let history = match history_file.map(InputHistory::load).transpose() {
+        Ok(history) => history.unwrap_or_default(),
+        Err(e) => {
+            initial_messages.push(format!("Couldn't read input history: {}", e));
+            InputHistory::default()
+        }
+    };
 
     let mut app = App::new(
-        args.username.clone(),
-        args.ip.clone(),
+        username.clone(),
+        server_addr,
         Arc::clone(&write_stream),
+        args.time_format.clone(),
+        history,
     );
 
     // Add welcome message since server doesn't send join message to sender
-    app.add_message("System".to_string(), format!("Welcome to the chat! You are connected as {}", args.username));
-    
-    // Add any initial messages from server (filter out USER_LIST messages)
+    app.add_message(
+        "System".to_string(),
+        format!("Welcome to the chat! You are connected as {}", username),
+    );
+
+    // Add any initial messages from server
     for msg in initial_messages {
-        let msg = msg.trim();
-        if !msg.is_empty() {
-            // Check if this is a user list update and filter it out
-            if msg.starts_with("USER_LIST:") {
-                // Parse and update user list, but don't display as message
-                if let Some(json_part) = msg.strip_prefix("USER_LIST:") {
-                    if let Ok(users) = serde_json::from_str::<Vec<String>>(json_part) {
-                        app.connected_users_widget.set_users(users);
-                    }
-                }
-            } else {
-                app.add_message("System".to_string(), msg.to_string());
-            }
-        }
+        app.add_message("System".to_string(), msg);
     }
 
     let mut terminal = ratatui::init();
 
+    // Lets Shift+Enter be told apart from Enter, in terminals that support it
+    let enhanced_keys = crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced_keys {
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::event::PushKeyboardEnhancementFlags(
+                crossterm::event::KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+            )
+        )?;
+    }
+
     let (event_tx, event_rx) = mpsc::channel::<Event>();
 
     let tx_to_input_events = event_tx.clone();
@@ -126,15 +215,60 @@ fn main() -> io::Result<()> {
         run_cursor_blink_thread(tx_to_cursor_events);
     });
 
-    // Start message receiver thread with separate read stream
+    // Start message receiver thread with separate read stream. It also
+    // reconnects when the connection drops.
+    let session = Session {
+        username,
+        password,
+        resume_token: Some(resume_token),
+        last_ids: BTreeMap::new(),
+    };
     let rx_event_tx = event_tx.clone();
+    let ping_interval = Duration::from_secs(args.ping_interval);
     thread::spawn(move || {
-        handle_server_messages(read_stream, rx_event_tx);
+        let open = || connect(&args.host, args.port).and_then(|(socket, _)| secure(socket, &args));
+        run_connection(
+            read_stream,
+            write_stream,
+            session,
+            open,
+            ping_interval,
+            rx_event_tx,
+        );
     });
 
     let app_result = app.run(&mut terminal, event_rx, event_tx.clone());
 
+    if enhanced_keys {
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::event::PopKeyboardEnhancementFlags
+        )?;
+    }
     ratatui::restore();
     crossterm::execute!(std::io::stdout(), crossterm::event::DisableMouseCapture)?;
     app_result
 }
+
+// Wraps the socket in TLS when asked to, checking the server's certificate
+// against the given CA or the known hosts file
+fn secure(socket: TcpStream, args: &Args) -> io::Result<Stream> {
+    if let Some(ca) = &args.tls_ca {
+        return tls::connect_with_ca(socket, &args.host, ca).map(Stream::Tls);
+    }
+    if !args.tls {
+        return Ok(Stream::Plain(socket));
+    }
+
+    let known_hosts = args
+        .known_hosts
+        .clone()
+        .or_else(tls::default_known_hosts)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no config directory for the known hosts file; pass --known-hosts",
+            )
+        })?;
+    tls::connect_with_known_hosts(socket, &args.host, args.port, &known_hosts).map(Stream::Tls)
+}
@@ -0,0 +1,28 @@
+use std::{env, io};
+
+// Read instead of prompting when set, for scripts and password managers
+const PASSWORD_ENV: &str = "TCPTALK_PASSWORD";
+
+// The password for a registered `username`
+pub fn read_password(username: &str) -> io::Result<String> {
+    if let Ok(password) = env::var(PASSWORD_ENV) {
+        return Ok(password);
+    }
+    rpassword::prompt_password(format!("Password for {}: ", username))
+}
+
+// A password for registering `username`, typed twice to catch typos
+pub fn new_password(username: &str) -> io::Result<String> {
+    if let Ok(password) = env::var(PASSWORD_ENV) {
+        return Ok(password);
+    }
+    let password = rpassword::prompt_password(format!("Choose a password for {}: ", username))?;
+    let confirmation = rpassword::prompt_password("Repeat the password: ")?;
+    if password != confirmation {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the passwords do not match",
+        ));
+    }
+    Ok(password)
+}
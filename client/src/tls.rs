@@ -0,0 +1,180 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tcptalk_protocol::{
+    TlsStream,
+    rustls::{
+        ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+        pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+    },
+    stream::fingerprint,
+};
+
+// Where trusted server fingerprints are remembered unless --known-hosts says otherwise
+pub fn default_known_hosts() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tcptalk").join("known_hosts"))
+}
+
+// Verifies the server against the CA certificates in `ca_path`, like a browser
+// would, including that the certificate is valid for `host`
+pub fn connect_with_ca(socket: TcpStream, host: &str, ca_path: &Path) -> io::Result<TlsStream> {
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(format!("{}: {}", ca_path.display(), e)))?;
+    for cert in certs {
+        roots
+            .add(cert)
+            .map_err(|e| invalid_data(format!("{}: {}", ca_path.display(), e)))?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    handshake(socket, host, config)
+}
+
+// Trust on first use: the first certificate seen for `host`:`port` is
+// remembered in `known_hosts`, and later connections must present the same one
+pub fn connect_with_known_hosts(
+    socket: TcpStream,
+    host: &str,
+    port: u16,
+    known_hosts: &Path,
+) -> io::Result<TlsStream> {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    let stream = handshake(socket, host, config)?;
+
+    let cert = stream
+        .peer_certificate()
+        .ok_or_else(|| invalid_data("server did not send a certificate".to_string()))?;
+    let received = fingerprint(&cert);
+
+    match known_fingerprint(known_hosts, host, port)? {
+        Some(expected) if expected == received => Ok(stream),
+        Some(expected) => Err(invalid_data(format!(
+            "the TLS certificate of {}:{} has changed!\n  \
+             expected SHA256 {}\n  \
+             received SHA256 {}\n\
+             Someone may be intercepting the connection. If the server's certificate was \
+             replaced on purpose, remove its line from {} and connect again.",
+            host,
+            port,
+            expected,
+            received,
+            known_hosts.display()
+        ))),
+        None => {
+            remember_fingerprint(known_hosts, host, port, &received)?;
+            println!(
+                "Trusting the TLS certificate of {}:{} from now on\n  SHA256 {}",
+                host, port, received
+            );
+            Ok(stream)
+        }
+    }
+}
+
+fn handshake(socket: TcpStream, host: &str, config: ClientConfig) -> io::Result<TlsStream> {
+    let server_name = ServerName::try_from(host.to_string()).map_err(invalid_data)?;
+    let conn = ClientConnection::new(Arc::new(config), server_name).map_err(invalid_data)?;
+    TlsStream::handshake(socket, conn)
+}
+
+// Each line of the file is "<host> <port> <fingerprint>"
+fn known_fingerprint(known_hosts: &Path, host: &str, port: u16) -> io::Result<Option<String>> {
+    let contents = match fs::read_to_string(known_hosts) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let port = port.to_string();
+    Ok(contents.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let matches = fields.next() == Some(host) && fields.next() == Some(port.as_str());
+        matches.then(|| fields.next().unwrap_or_default().to_string())
+    }))
+}
+
+fn remember_fingerprint(
+    known_hosts: &Path,
+    host: &str,
+    port: u16,
+    fingerprint: &str,
+) -> io::Result<()> {
+    if let Some(dir) = known_hosts.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(known_hosts)?;
+    writeln!(file, "{} {} {}", host, port, fingerprint)
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+// Accepts whatever certificate the server presents, leaving the decision to the
+// fingerprint check, but still makes the server prove it holds the matching key
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tcptalk_protocol::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tcptalk_protocol::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tcptalk_protocol::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
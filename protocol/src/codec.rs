@@ -0,0 +1,405 @@
+//! Length-prefixed frame encoding.
+//!
+//! ```text
+//! +---------+----------------+---------------------+
+//! | version | payload length | payload (JSON)      |
+//! | 1 byte  | 4 bytes, BE    | `length` bytes      |
+//! +---------+----------------+---------------------+
+//! ```
+
+use crate::{Frame, PROTOCOL_VERSION};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// Size of the version + length header in front of every payload.
+pub const HEADER_LEN: usize = 5;
+
+/// Default payload limit used by [`FrameReader::new`].
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Size of the chunks [`FrameReader`] reads from the socket.
+pub const READ_CHUNK_LEN: usize = 4096;
+
+pub fn encode_frame(frame: &Frame) -> io::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(frame)?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.push(PROTOCOL_VERSION);
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    writer.write_all(&encode_frame(frame)?)?;
+    writer.flush()
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The peer announced a payload larger than the reader's limit. The payload is
+    /// skipped, so the next call to `read_frame` continues with the following frame.
+    TooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// The payload was not valid UTF-8 JSON for a known frame. The payload has been
+    /// consumed, so reading can continue.
+    Malformed(String),
+    /// The header carried a version this build does not speak. The stream cannot be
+    /// resynchronised after this.
+    UnsupportedVersion(u8),
+}
+
+impl FrameError {
+    /// Whether the reader is still positioned at a frame boundary after this error.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, FrameError::TooLarge { .. } | FrameError::Malformed(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::TooLarge { len, max } => {
+                write!(f, "message of {} bytes exceeds the {} byte limit", len, max)
+            }
+            FrameError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// Reassembles frames from bytes as they arrive, without doing any I/O itself.
+///
+/// Bytes are accumulated across calls to [`FrameDecoder::extend`] until a whole
+/// frame is available, so a frame split over several TCP segments (including a
+/// UTF-8 sequence cut in half) is reassembled, and several frames arriving in one
+/// chunk are returned one by one.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    // Where the bytes not decoded yet start in `buf`. What's before it is only
+    // dropped when more bytes arrive, so a chunk holding many frames isn't
+    // shifted down once for each of them.
+    start: usize,
+    max_frame_len: usize,
+    // Bytes of an oversized payload that still have to be thrown away
+    skipping: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            max_frame_len,
+            skipping: 0,
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Whether the decoder sits at a frame boundary with nothing buffered, i.e.
+    /// whether the peer closing the connection now would be a clean close.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.buf.len() && self.skipping == 0
+    }
+
+    /// Decodes the next complete frame, or returns `Ok(None)` if more bytes are needed.
+    pub fn decode(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.skipping > 0 {
+            let n = self.skipping.min(self.buf.len() - self.start);
+            self.start += n;
+            self.skipping -= n;
+            if self.skipping > 0 {
+                return Ok(None);
+            }
+        }
+
+        let unread = &self.buf[self.start..];
+        if unread.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        if unread[0] != PROTOCOL_VERSION {
+            return Err(FrameError::UnsupportedVersion(unread[0]));
+        }
+
+        let len = u32::from_be_bytes([unread[1], unread[2], unread[3], unread[4]]) as usize;
+        if len > self.max_frame_len {
+            self.start += HEADER_LEN;
+            self.skipping = len;
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+
+        if unread.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let payload = &unread[HEADER_LEN..HEADER_LEN + len];
+        self.start += HEADER_LEN + len;
+        let text =
+            std::str::from_utf8(payload).map_err(|e| FrameError::Malformed(e.to_string()))?;
+        let frame = serde_json::from_str(text).map_err(|e| FrameError::Malformed(e.to_string()))?;
+        Ok(Some(frame))
+    }
+}
+
+/// Buffered frame reader over a blocking [`Read`], built on [`FrameDecoder`].
+pub struct FrameReader<R> {
+    inner: R,
+    decoder: FrameDecoder,
+    bytes_read: u64,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_max_frame_len(inner, MAX_FRAME_LEN)
+    }
+
+    pub fn with_max_frame_len(inner: R, max_frame_len: usize) -> Self {
+        Self {
+            inner,
+            decoder: FrameDecoder::new(max_frame_len),
+            bytes_read: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Total bytes read from the underlying reader so far, including any buffered
+    /// bytes of frames that haven't been returned yet.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Reads the next frame, returning `Ok(None)` if the peer closed the connection
+    /// cleanly between two frames.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        loop {
+            if let Some(frame) = self.decoder.decode()? {
+                return Ok(Some(frame));
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_LEN];
+            match self.inner.read(&mut chunk) {
+                Ok(0) if self.decoder.is_empty() => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    self.bytes_read += n as u64;
+                    self.decoder.extend(&chunk[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(text: &str) -> Frame {
+        Frame::Notice {
+            text: text.to_string(),
+        }
+    }
+
+    // Hands out the bytes a few at a time, like a slow connection
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        chunk_len: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk_len.min(self.bytes.len()).min(buf.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_through_the_encoding() {
+        let frames = [
+            notice("hello"),
+            notice("héllo 世界"),
+            Frame::Error {
+                message: "nope".to_string(),
+            },
+            Frame::Ping { id: 7 },
+        ];
+        let mut bytes = Vec::new();
+        for frame in &frames {
+            write_frame(&mut bytes, frame).unwrap();
+        }
+        assert_eq!(bytes[0], PROTOCOL_VERSION);
+
+        let mut reader = FrameReader::new(bytes.as_slice());
+        for frame in &frames {
+            assert_eq!(reader.read_frame().unwrap().as_ref(), Some(frame));
+        }
+        assert!(reader.read_frame().unwrap().is_none());
+        assert_eq!(reader.bytes_read(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn a_truncated_frame_is_an_unexpected_eof() {
+        let bytes = encode_frame(&notice("hi")).unwrap();
+        for len in 1..bytes.len() {
+            let error = FrameReader::new(&bytes[..len]).read_frame().unwrap_err();
+            assert!(
+                matches!(&error, FrameError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof),
+                "{} bytes: {}",
+                len,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn reassembles_a_frame_split_across_several_reads() {
+        let bytes = encode_frame(&notice("hello")).unwrap();
+        let mut reader = FrameReader::new(Trickle {
+            bytes: &bytes,
+            chunk_len: 3,
+        });
+        assert_eq!(reader.read_frame().unwrap(), Some(notice("hello")));
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn reassembles_a_character_split_between_reads() {
+        let bytes = encode_frame(&notice("héllo 世界")).unwrap();
+        // Cut inside the two bytes of "é"
+        let cut = bytes.iter().position(|&byte| byte == 0xc3).unwrap() + 1;
+        let mut reader = FrameReader::new(Trickle {
+            bytes: &bytes,
+            chunk_len: cut,
+        });
+        assert_eq!(reader.read_frame().unwrap(), Some(notice("héllo 世界")));
+    }
+
+    #[test]
+    fn skips_a_frame_that_is_too_large_and_reads_the_next() {
+        let mut bytes = encode_frame(&notice(&"x".repeat(100))).unwrap();
+        bytes.extend(encode_frame(&notice("hi")).unwrap());
+        let mut reader = FrameReader::with_max_frame_len(
+            Trickle {
+                bytes: &bytes,
+                chunk_len: 20,
+            },
+            50,
+        );
+        let error = reader.read_frame().unwrap_err();
+        assert!(matches!(error, FrameError::TooLarge { max: 50, .. }));
+        assert!(error.is_recoverable());
+        assert_eq!(reader.read_frame().unwrap(), Some(notice("hi")));
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn a_truncated_header_is_incomplete_rather_than_an_error() {
+        let bytes = encode_frame(&notice("hi")).unwrap();
+        for len in 0..HEADER_LEN {
+            let mut decoder = FrameDecoder::new(MAX_FRAME_LEN);
+            decoder.extend(&bytes[..len]);
+            assert!(decoder.decode().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn decodes_a_frame_split_across_several_chunks() {
+        let bytes = encode_frame(&notice("hello")).unwrap();
+        let mut decoder = FrameDecoder::new(MAX_FRAME_LEN);
+        for chunk in bytes.chunks(3) {
+            assert!(decoder.decode().unwrap().is_none());
+            decoder.extend(chunk);
+        }
+        assert_eq!(decoder.decode().unwrap(), Some(notice("hello")));
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn decodes_every_frame_in_a_chunk_then_the_rest_of_a_partial_one() {
+        let mut bytes = Vec::new();
+        for i in 0..100 {
+            bytes.extend(encode_frame(&notice(&i.to_string())).unwrap());
+        }
+        let last = encode_frame(&notice("last")).unwrap();
+        bytes.extend(&last[..4]);
+        let mut decoder = FrameDecoder::new(MAX_FRAME_LEN);
+        decoder.extend(&bytes);
+        for i in 0..100 {
+            assert_eq!(decoder.decode().unwrap(), Some(notice(&i.to_string())));
+        }
+        assert!(decoder.decode().unwrap().is_none());
+        assert!(!decoder.is_empty());
+        decoder.extend(&last[4..]);
+        assert_eq!(decoder.decode().unwrap(), Some(notice("last")));
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn skips_a_frame_that_is_too_large_and_decodes_the_next() {
+        let large = encode_frame(&notice(&"x".repeat(100))).unwrap();
+        let small = encode_frame(&notice("hi")).unwrap();
+        let mut decoder = FrameDecoder::new(50);
+        // The oversized payload arrives in pieces, followed by the next frame
+        decoder.extend(&large[..20]);
+        assert!(matches!(
+            decoder.decode(),
+            Err(FrameError::TooLarge { max: 50, .. })
+        ));
+        assert!(decoder.decode().unwrap().is_none());
+        decoder.extend(&large[20..]);
+        decoder.extend(&small);
+        assert_eq!(decoder.decode().unwrap(), Some(notice("hi")));
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn rejects_an_unknown_version() {
+        let mut bytes = encode_frame(&notice("hi")).unwrap();
+        bytes[0] = PROTOCOL_VERSION + 1;
+        let error = FrameReader::new(bytes.as_slice()).read_frame().unwrap_err();
+        assert!(matches!(error, FrameError::UnsupportedVersion(v) if v == PROTOCOL_VERSION + 1));
+        assert!(!error.is_recoverable());
+    }
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    // Client -> server
+    /// Request to use `username` for this connection. Registered names need their
+    /// password; guests leave it out.
+    Login {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// Register `username` with a password and log in as it.
+    Register { username: String, password: String },
+    /// Log back in with the `resume_token` from an earlier `LoginAccepted`, after
+    /// losing the connection. The session's rooms are rejoined, and each room's
+    /// stored messages with IDs above its entry in `last_ids` are replayed.
+    Resume {
+        token: String,
+        #[serde(default)]
+        last_ids: BTreeMap<String, u64>,
+    },
+    /// A chat message typed by the user, for a room they are in. `text` may
+    /// span several lines, separated by '\n'. A client that numbers its
+    /// messages with `client_id` hears back about each one with its own `Chat`
+    /// or a `Rejected`. An `action` describes what the user is doing, as with
+    /// "/me waves", and is shown after their name.
+    Say {
+        room: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        action: bool,
+    },
+    /// Ask the server for the users in `room`.
+    ListUsers { room: String },
+    /// Create a new room and join it.
+    CreateRoom { room: String },
+    /// Join an existing room.
+    JoinRoom { room: String },
+    /// Leave a room.
+    LeaveRoom { room: String },
+    /// Ask the server for every room that currently exists.
+    ListRooms,
+    /// A private message for a single user, matched case-insensitively. A
+    /// client that numbers its messages with `client_id` hears back about each
+    /// one with an `Ack` or `Rejected`. `action` works as for `Say`.
+    DirectMessage {
+        to: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        action: bool,
+    },
+    /// Change this connection's username. Only guests can, and only to a name
+    /// they could log in with.
+    Nick { username: String },
+    /// Become a server operator using the operator password.
+    Oper { password: String },
+    /// Operators only: disconnect `username`.
+    Kick {
+        username: String,
+        reason: Option<String>,
+    },
+    /// Operators only: ban a username, IP address or CIDR network, for
+    /// `duration_secs` or forever. Matching users are disconnected.
+    Ban {
+        target: String,
+        duration_secs: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Operators only: lift a ban on `target`.
+    Unban { target: String },
+    /// Operators only: stop `username` from sending messages, for `duration_secs`
+    /// or until unmuted.
+    Mute {
+        username: String,
+        duration_secs: Option<u64>,
+    },
+    /// Operators only: let `username` send messages again.
+    Unmute { username: String },
+
+    // Server -> client
+    //
+    // Chat, join and leave events carry a server-assigned `id`, unique across the
+    // server and increasing over time, and a `timestamp` in milliseconds since the
+    // Unix epoch, UTC.
+    /// The username from the last `Login` was accepted. `resume_token` lets the
+    /// client `Resume` this session for a while after disconnecting.
+    LoginAccepted {
+        username: String,
+        resume_token: String,
+    },
+    /// The username from the last `Login`, `Register` or `Resume` was refused; the
+    /// client may try again.
+    LoginRejected { reason: String },
+    /// `username` is registered; log in again with its password.
+    PasswordRequired { username: String },
+    /// This connection's username is now `username`, after a `Nick`.
+    Renamed { username: String },
+    /// A chat message in a room. The copy echoed back to its sender carries the
+    /// `client_id` the sender gave it.
+    Chat {
+        id: u64,
+        timestamp: u64,
+        room: String,
+        author: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        action: bool,
+    },
+    /// A private message sent only to this connection.
+    DirectChat {
+        id: u64,
+        timestamp: u64,
+        from: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "is_false")]
+        action: bool,
+    },
+    /// A user joined a room.
+    Join {
+        id: u64,
+        timestamp: u64,
+        room: String,
+        username: String,
+    },
+    /// A user left a room.
+    Leave {
+        id: u64,
+        timestamp: u64,
+        room: String,
+        username: String,
+    },
+    /// The full list of users in a room.
+    UserList { room: String, users: Vec<String> },
+    /// This connection is now a member of `room`.
+    RoomJoined { room: String },
+    /// This connection is no longer a member of `room`.
+    RoomLeft { room: String },
+    /// Every room on the server.
+    RoomList { rooms: Vec<RoomSummary> },
+    /// Earlier messages in `room`, oldest first, sent when this connection joins it.
+    History {
+        room: String,
+        messages: Vec<HistoryMessage>,
+    },
+    /// An announcement from the server to everyone in `room`, such as a moderation
+    /// action.
+    System {
+        id: u64,
+        timestamp: u64,
+        room: String,
+        text: String,
+    },
+    /// Informational text from the server.
+    Notice { text: String },
+    /// The server is closing this connection, e.g. because the user was kicked.
+    /// The client shouldn't reconnect on its own.
+    Disconnect { reason: String },
+    /// The server is shutting down and will close the connection once everything
+    /// queued for it has been sent. `restart_in_secs` estimates when it will be back.
+    ShuttingDown {
+        reason: Option<String>,
+        restart_in_secs: Option<u64>,
+    },
+    /// The server could not process the last frame.
+    Error { message: String },
+    /// The direct message this connection numbered `client_id` was delivered,
+    /// with the server-assigned `id` and `timestamp`. Room messages are
+    /// acknowledged by their echoed `Chat` instead.
+    Ack {
+        client_id: u64,
+        id: u64,
+        timestamp: u64,
+    },
+    /// The message this connection numbered `client_id` was not delivered.
+    Rejected { client_id: u64, message: String },
+
+    // Either direction
+    /// Checks that the connection is still alive. The other side answers with a
+    /// `Pong` carrying the same `id`.
+    Ping { id: u64 },
+    /// The answer to a `Ping`.
+    Pong { id: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomSummary {
+    pub name: String,
+    pub members: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    pub id: u64,
+    /// Milliseconds since the Unix epoch, UTC.
+    pub timestamp: u64,
+    pub author: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub action: bool,
+    /// The `client_id` the author numbered the message with. Only sent to the
+    /// author, when resuming a session, so it can match up messages it never
+    /// heard back about.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<u64>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
@@ -0,0 +1,23 @@
+//! Wire protocol shared by the tcptalk server and client.
+//!
+//! Every message on the socket is a [`Frame`], encoded by the [`codec`] module as a
+//! small header (protocol version + payload length) followed by a JSON payload.
+//! Frames travel over a [`Stream`], which is either plain TCP or TLS.
+
+pub mod codec;
+mod frame;
+pub mod stream;
+
+pub use codec::{FrameDecoder, FrameError, FrameReader, write_frame};
+pub use frame::{Frame, HistoryMessage, RoomSummary};
+pub use stream::{Stream, TlsStream};
+
+/// TLS library used for encrypted connections, re-exported so the server and
+/// client build their configuration against the same version.
+pub use rustls;
+
+/// Version byte written in front of every frame.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Default TCP port used by both the server and the client.
+pub const DEFAULT_PORT: u16 = 2133;
@@ -0,0 +1,188 @@
+//! Connection wrapper shared by the server and client, so the rest of the code
+//! reads and writes frames the same way over plain TCP or TLS.
+//!
+//! Like [`TcpStream`], a [`Stream`] can be cloned with [`Stream::try_clone`] and
+//! used from two threads at once: one blocked reading while the other writes.
+
+use rustls::Connection;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+// Ciphertext read from the socket per call. Small enough that decrypting it
+// can't overflow rustls' received plaintext buffer.
+const TLS_READ_CHUNK_LEN: usize = 8192;
+
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Plain(socket) => Stream::Plain(socket.try_clone()?),
+            Stream::Tls(stream) => Stream::Tls(stream.try_clone()?),
+        })
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => &stream.socket,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket().peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket().shutdown(how)
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => (&*socket).read(buf),
+            Stream::Tls(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => (&*socket).write(buf),
+            Stream::Tls(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => (&*socket).flush(),
+            Stream::Tls(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// A TLS session over a TCP socket. Clones share the session state; the lock
+/// around it is never held while waiting for the peer to send something.
+pub struct TlsStream {
+    socket: TcpStream,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl TlsStream {
+    /// Runs the TLS handshake on `socket` to completion.
+    pub fn handshake(mut socket: TcpStream, conn: impl Into<Connection>) -> io::Result<Self> {
+        let mut conn = conn.into();
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut socket)?;
+        }
+
+        Ok(Self {
+            socket,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            conn: Arc::clone(&self.conn),
+        })
+    }
+
+    /// The DER-encoded certificate the peer identified itself with, if any.
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        let conn = self.conn.lock().unwrap();
+        let certificates = conn.peer_certificates()?;
+        certificates.first().map(|cert| cert.to_vec())
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0; TLS_READ_CHUNK_LEN];
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let len = (&self.socket).read(&mut incoming)?;
+            if len == 0 {
+                return Ok(0);
+            }
+
+            let mut conn = self.conn.lock().unwrap();
+            let mut records = &incoming[..len];
+            while !records.is_empty() {
+                conn.read_tls(&mut records)?;
+                if let Err(err) = conn.process_new_packets() {
+                    // Let the peer know why before giving up
+                    let _ = conn.write_tls(&mut &self.socket);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+            // Answer key updates and alerts
+            while conn.wants_write() {
+                conn.write_tls(&mut &self.socket)?;
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let len = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.socket)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.socket)?;
+        }
+        (&self.socket).flush()
+    }
+}
+
+/// SHA-256 fingerprint of a DER-encoded certificate, as colon-separated hex
+/// pairs like `openssl x509 -fingerprint -sha256` prints.
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
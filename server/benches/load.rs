@@ -0,0 +1,274 @@
+// Load benchmark: holds thousands of idle connections open against a real server
+// process, then measures fan-out of chat messages to a busy room.
+//
+//     cargo bench --bench load
+//
+// Sizes can be changed with TCPTALK_BENCH_IDLE, TCPTALK_BENCH_RECEIVERS,
+// TCPTALK_BENCH_SENDERS and TCPTALK_BENCH_MESSAGES. Every connection needs a file
+// descriptor on both ends, so raise `ulimit -n` for large runs.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::Server;
+use std::{
+    env, fs,
+    net::TcpStream,
+    path::PathBuf,
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+use tcptalk_protocol::{Frame, FrameReader, write_frame};
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn start_server(port: u16) -> (Server, PathBuf) {
+    let dir = common::temp_dir("bench");
+    // Everything comes from one address, so the per-IP limits have to go
+    let config = r#"
+max_connections = 100000
+
+# The benchmark's readers share the machine with the server, so give them room
+# to fall behind during a burst
+[outbound]
+queue_len = 4096
+
+[rate_limit]
+max_connections_per_ip = 100000
+
+[rate_limit.connection]
+messages_per_second = 100000.0
+message_burst = 100000
+bytes_per_second = 100000000
+byte_burst = 100000000
+
+[rate_limit.ip]
+messages_per_second = 1000000.0
+message_burst = 1000000
+bytes_per_second = 1000000000
+byte_burst = 1000000000
+"#;
+    fs::write(dir.join("bench.toml"), config).unwrap();
+    let server = common::start_server(&dir, port, &["--config", "bench.toml"]);
+    (server, dir)
+}
+
+// Resident memory of the server process, from /proc
+fn server_rss_kib(server: &Server) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", server.0.id())).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: FrameReader<TcpStream>,
+}
+
+impl Connection {
+    fn send(&mut self, frame: &Frame) {
+        write_frame(&mut self.stream, frame).unwrap();
+    }
+
+    fn expect(&mut self, matches: impl Fn(&Frame) -> bool) -> Frame {
+        loop {
+            match self.reader.read_frame().unwrap() {
+                Some(frame) if matches(&frame) => return frame,
+                Some(_) => {}
+                None => panic!("server closed the connection"),
+            }
+        }
+    }
+}
+
+// Logs in and leaves the default room, so connecting thousands of users doesn't
+// turn into thousands of user list broadcasts to everyone
+fn connect(port: u16, username: String) -> Connection {
+    let stream = common::tcp_connect(port, Duration::from_secs(30));
+    let mut connection = Connection {
+        reader: FrameReader::new(stream.try_clone().unwrap()),
+        stream,
+    };
+    connection.send(&Frame::Login {
+        username,
+        password: None,
+    });
+    connection.send(&Frame::LeaveRoom {
+        room: "#lobby".to_string(),
+    });
+    connection
+}
+
+fn idle_connections(port: u16, count: usize) -> Vec<Connection> {
+    let start = Instant::now();
+    // Send every login before waiting for any of them, like a crowd would
+    let mut connections: Vec<Connection> = (0..count)
+        .map(|i| connect(port, format!("idle{}", i)))
+        .collect();
+    for connection in &mut connections {
+        connection.expect(|frame| matches!(frame, Frame::RoomLeft { .. }));
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "idle: {} connections logged in in {:.2?} ({:.0} logins/s)",
+        count,
+        elapsed,
+        count as f64 / elapsed.as_secs_f64()
+    );
+    connections
+}
+
+// Returns the connections it used, so they can outlive the server
+fn fan_out(port: u16, receivers: usize, senders: usize, messages: usize) -> Vec<Connection> {
+    let room = "#bench".to_string();
+    let join = |connection: &mut Connection, create: bool| {
+        connection.expect(|frame| matches!(frame, Frame::RoomLeft { .. }));
+        connection.send(&if create {
+            Frame::CreateRoom { room: room.clone() }
+        } else {
+            Frame::JoinRoom { room: room.clone() }
+        });
+        connection.expect(|frame| matches!(frame, Frame::RoomJoined { .. }));
+    };
+
+    let mut receiving = Vec::new();
+    for i in 0..receivers {
+        let mut connection = connect(port, format!("receiver{}", i));
+        join(&mut connection, i == 0);
+        receiving.push(connection);
+    }
+    let mut sending = Vec::new();
+    for i in 0..senders {
+        let mut connection = connect(port, format!("sender{}", i));
+        join(&mut connection, false);
+        sending.push(connection);
+    }
+
+    // Message texts carry the time they were sent, relative to `epoch`
+    let epoch = Instant::now();
+    let expected = senders * messages;
+    let start = Arc::new(Barrier::new(receivers + senders + 1));
+
+    let receiver_threads: Vec<_> = receiving
+        .into_iter()
+        .map(|mut connection| {
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                start.wait();
+                let mut latencies = Vec::with_capacity(expected);
+                while latencies.len() < expected {
+                    let chat = connection.expect(|frame| matches!(frame, Frame::Chat { .. }));
+                    let Frame::Chat { text, .. } = chat else {
+                        unreachable!()
+                    };
+                    let sent: u64 = text.parse().unwrap();
+                    latencies.push(epoch.elapsed().as_micros() as u64 - sent);
+                }
+                (connection, latencies)
+            })
+        })
+        .collect();
+
+    let sender_threads: Vec<_> = sending
+        .into_iter()
+        .map(|mut connection| {
+            let start = Arc::clone(&start);
+            let room = room.clone();
+            thread::spawn(move || {
+                start.wait();
+                for _ in 0..messages {
+                    connection.send(&Frame::Say {
+                        room: room.clone(),
+                        text: epoch.elapsed().as_micros().to_string(),
+                        client_id: None,
+                        action: false,
+                    });
+                }
+                connection
+            })
+        })
+        .collect();
+
+    start.wait();
+    let sent_at = Instant::now();
+    let mut connections = Vec::new();
+    let mut latencies = Vec::new();
+    for handle in receiver_threads {
+        let (connection, received) = handle.join().unwrap();
+        connections.push(connection);
+        latencies.extend(received);
+    }
+    let elapsed = sent_at.elapsed();
+    connections.extend(
+        sender_threads
+            .into_iter()
+            .map(|handle| handle.join().unwrap()),
+    );
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| {
+        let index = ((latencies.len() - 1) as f64 * p) as usize;
+        Duration::from_micros(latencies[index])
+    };
+    println!(
+        "fan-out: {} senders x {} messages to {} receivers: {} deliveries in {:.2?} ({:.0}/s)",
+        senders,
+        messages,
+        receivers,
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(0.5),
+        percentile(0.99),
+        percentile(1.0)
+    );
+    connections
+}
+
+fn main() {
+    // `cargo bench` passes "--bench"; any other run (e.g. `cargo test --benches`)
+    // only checks that the benchmark builds
+    if !env::args().any(|arg| arg == "--bench") {
+        return;
+    }
+
+    let idle = setting("TCPTALK_BENCH_IDLE", 5000);
+    let receivers = setting("TCPTALK_BENCH_RECEIVERS", 200);
+    let senders = setting("TCPTALK_BENCH_SENDERS", 4);
+    let messages = setting("TCPTALK_BENCH_MESSAGES", 250);
+
+    let port = common::free_port();
+    let (server, dir) = start_server(port);
+    let baseline = server_rss_kib(&server);
+
+    let idle_connections = idle_connections(port, idle);
+    if let (Some(baseline), Some(rss)) = (baseline, server_rss_kib(&server)) {
+        println!(
+            "memory: {} KiB before, {} KiB with {} idle connections (~{:.1} KiB each)",
+            baseline,
+            rss,
+            idle,
+            rss.saturating_sub(baseline) as f64 / idle.max(1) as f64
+        );
+    }
+
+    // The idle connections stay open while the room is busy
+    let busy_connections = fan_out(port, receivers, senders, messages);
+
+    // Stop the server first, so it doesn't handle thousands of disconnects
+    drop(server);
+    drop(idle_connections);
+    drop(busy_connections);
+    let _ = fs::remove_dir_all(dir);
+}
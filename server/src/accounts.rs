@@ -0,0 +1,272 @@
+use crate::config::AccountsConfig;
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    sync::{Arc, Mutex},
+};
+use tcptalk_protocol::Frame;
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    username: String,
+    /// Argon2 hash in PHC string format, salt included
+    password_hash: String,
+}
+
+// Registered usernames and their password hashes, loaded from a JSON-lines file
+// that new registrations are appended to
+pub struct Accounts {
+    file: Option<File>,
+    // Keyed by lowercased username, since names are unique case-insensitively
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    pub fn open(config: &AccountsConfig) -> io::Result<Self> {
+        let mut accounts = HashMap::new();
+        let Some(path) = &config.path else {
+            return Ok(Self {
+                file: None,
+                accounts,
+            });
+        };
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let account: Account = serde_json::from_str(&line).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: {}", path.display(), line_number + 1, e),
+                    )
+                })?;
+                accounts.insert(account.username.to_lowercase(), account);
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(file),
+            accounts,
+        })
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.accounts.contains_key(&username.to_lowercase())
+    }
+
+    fn password_hash(&self, username: &str) -> Option<String> {
+        self.accounts
+            .get(&username.to_lowercase())
+            .map(|account| account.password_hash.clone())
+    }
+
+    fn insert(&mut self, username: &str, password_hash: String) -> Result<(), String> {
+        let Some(file) = &mut self.file else {
+            return Err("This server doesn't have registered accounts.".to_string());
+        };
+        let key = username.to_lowercase();
+        if self.accounts.contains_key(&key) {
+            return Err(format!("{} is already registered.", username));
+        }
+
+        let account = Account {
+            username: username.to_string(),
+            password_hash,
+        };
+        let mut line = serde_json::to_vec(&account).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        if let Err(err) = file.write_all(&line).and_then(|_| file.flush()) {
+            eprintln!("Failed to save account {}: {}", username, err);
+            return Err("Registration failed. Please try again later.".to_string());
+        }
+
+        self.accounts.insert(key, account);
+        Ok(())
+    }
+}
+
+// Checks a login attempt against the registered accounts. The error is the
+// frame to answer with: a rejection, or a request for the password.
+pub fn authenticate(
+    username: &str,
+    password: Option<String>,
+    accounts: &Arc<Mutex<Accounts>>,
+    config: &AccountsConfig,
+) -> Result<(), Frame> {
+    let password_hash = accounts.lock().unwrap().password_hash(username);
+
+    match (password_hash, password) {
+        (Some(_), None) => Err(Frame::PasswordRequired {
+            username: username.to_string(),
+        }),
+        // Verifying is slow on purpose, so it happens outside the lock
+        (Some(hash), Some(password)) if verify_password(&password, &hash) => Ok(()),
+        (Some(_), Some(_)) => Err(Frame::LoginRejected {
+            reason: "Wrong password.".to_string(),
+        }),
+        (None, Some(_)) => Err(Frame::LoginRejected {
+            reason: format!("No account is registered as {}.", username),
+        }),
+        (None, None) if config.allow_guests => Ok(()),
+        (None, None) => Err(Frame::LoginRejected {
+            reason: "Only registered users may log in here. Register a username first.".to_string(),
+        }),
+    }
+}
+
+pub fn register(
+    username: &str,
+    password: &str,
+    accounts: &Arc<Mutex<Accounts>>,
+    config: &AccountsConfig,
+) -> Result<(), Frame> {
+    let reject = |reason: String| Frame::LoginRejected { reason };
+
+    if config.path.is_none() {
+        return Err(reject(
+            "This server doesn't have registered accounts.".to_string(),
+        ));
+    }
+    if !config.allow_registration {
+        return Err(reject("Registration is closed on this server.".to_string()));
+    }
+    if password.chars().count() < config.min_password_length {
+        return Err(reject(format!(
+            "Passwords must be at least {} characters long.",
+            config.min_password_length
+        )));
+    }
+
+    let password_hash = hash_password(password).map_err(reject)?;
+    accounts
+        .lock()
+        .unwrap()
+        .insert(username, password_hash)
+        .map_err(reject)
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Failed to hash password: {}", e))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+    use std::{fs, path::PathBuf};
+
+    fn config(path: Option<PathBuf>) -> AccountsConfig {
+        AccountsConfig {
+            path,
+            ..AccountsConfig::default()
+        }
+    }
+
+    fn rejection(result: Result<(), Frame>) -> String {
+        match result {
+            Err(Frame::LoginRejected { reason }) => reason,
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn registered_accounts_survive_a_restart() {
+        let path = temp_file("accounts-reload");
+        let config = config(Some(path.clone()));
+        let accounts = Arc::new(Mutex::new(Accounts::open(&config).unwrap()));
+        register("Alice", "correct horse", &accounts, &config).unwrap();
+        let taken = rejection(register("alice", "battery staple", &accounts, &config));
+        assert_eq!(taken, "alice is already registered.");
+        drop(accounts);
+
+        let accounts = Arc::new(Mutex::new(Accounts::open(&config).unwrap()));
+        assert!(accounts.lock().unwrap().password_hash("ALICE").is_some());
+        let login = |password: Option<&str>| {
+            authenticate("alice", password.map(str::to_string), &accounts, &config)
+        };
+        assert!(login(Some("correct horse")).is_ok());
+        assert_eq!(rejection(login(Some("battery staple"))), "Wrong password.");
+        assert!(matches!(
+            login(None),
+            Err(Frame::PasswordRequired { username }) if username == "alice"
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn registration_follows_the_config() {
+        let accounts = Arc::new(Mutex::new(Accounts::open(&config(None)).unwrap()));
+        let without_file = rejection(register("bob", "long enough", &accounts, &config(None)));
+        assert_eq!(
+            without_file,
+            "This server doesn't have registered accounts."
+        );
+
+        let path = temp_file("accounts-rules");
+        let closed = AccountsConfig {
+            allow_registration: false,
+            ..config(Some(path.clone()))
+        };
+        let accounts = Arc::new(Mutex::new(Accounts::open(&closed).unwrap()));
+        let refused = rejection(register("bob", "long enough", &accounts, &closed));
+        assert_eq!(refused, "Registration is closed on this server.");
+
+        let open = config(Some(path.clone()));
+        let short = rejection(register("bob", "short", &accounts, &open));
+        assert_eq!(short, "Passwords must be at least 8 characters long.");
+        assert!(accounts.lock().unwrap().password_hash("bob").is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn guests_need_no_password_unless_turned_away() {
+        let accounts = Arc::new(Mutex::new(Accounts::open(&config(None)).unwrap()));
+        assert!(authenticate("carol", None, &accounts, &config(None)).is_ok());
+        let unknown = rejection(authenticate(
+            "carol",
+            Some("password".to_string()),
+            &accounts,
+            &config(None),
+        ));
+        assert_eq!(unknown, "No account is registered as carol.");
+
+        let no_guests = AccountsConfig {
+            allow_guests: false,
+            ..config(None)
+        };
+        assert!(authenticate("carol", None, &accounts, &no_guests).is_err());
+    }
+
+    #[test]
+    fn verifies_only_matching_hashes() {
+        let hash = hash_password("hunter22").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("hunter22", &hash));
+        assert!(!verify_password("hunter23", &hash));
+        assert!(!verify_password("hunter22", "not a hash"));
+    }
+}
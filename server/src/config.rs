@@ -0,0 +1,877 @@
+use crate::moderation::MAX_DURATION_SECS;
+use crate::rooms::normalize_room_name;
+use argon2::password_hash::PasswordHash;
+use clap::Parser;
+use serde::Deserialize;
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
+use tcptalk_protocol::{DEFAULT_PORT, codec};
+
+#[derive(Parser)]
+#[command(name = "tcptalk-server")]
+#[command(about = "A multi-user TCP chat server")]
+pub struct Args {
+    /// Path to a TOML config file
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on (IPv4 or IPv6); repeat to listen on several
+    #[arg(short, long = "bind", value_name = "ADDR")]
+    pub bind: Vec<IpAddr>,
+
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Maximum number of simultaneous connections
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+
+    /// Largest message a client may send, in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_message_size: Option<usize>,
+
+    /// Message of the day shown to every user after they log in
+    #[arg(long)]
+    pub motd: Option<String>,
+
+    /// Room every user joins when they connect
+    #[arg(long, value_name = "ROOM")]
+    pub default_room: Option<String>,
+
+    /// Keep chat history in this file (append-only) instead of only in memory
+    #[arg(long, value_name = "FILE")]
+    pub history_file: Option<PathBuf>,
+
+    /// Store registered accounts in this file, enabling registration and password logins
+    #[arg(long, value_name = "FILE")]
+    pub accounts_file: Option<PathBuf>,
+
+    /// Only let registered users log in
+    #[arg(long)]
+    pub no_guests: bool,
+
+    /// Encrypt connections with TLS
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM certificate (chain) for TLS; generated self-signed if it and the key don't exist
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for TLS
+    #[arg(long, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Maximum number of simultaneous connections from one IP address
+    #[arg(long, value_name = "N")]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Save bans in this file
+    #[arg(long, value_name = "FILE")]
+    pub bans_file: Option<PathBuf>,
+
+    /// Seconds to spend sending queued messages to clients when shutting down
+    #[arg(long, value_name = "SECS")]
+    pub drain_timeout: Option<u64>,
+
+    /// Read a password from stdin, print its hash for `moderation.operator_password_hash`, and exit
+    #[arg(long)]
+    pub hash_password: bool,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub max_connections: usize,
+    pub max_message_size: usize,
+    pub motd: Option<String>,
+    pub default_room: String,
+    pub usernames: UsernameRules,
+    pub history: HistoryConfig,
+    pub accounts: AccountsConfig,
+    pub tls: TlsConfig,
+    pub moderation: ModerationConfig,
+    pub rate_limit: RateLimitConfig,
+    pub outbound: OutboundConfig,
+    pub shutdown: ShutdownConfig,
+    pub resume: ResumeConfig,
+    pub heartbeat: HeartbeatConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameRules {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Characters allowed in addition to letters and digits
+    pub allowed_symbols: String,
+    /// Names nobody may log in as, compared case-insensitively. "System" is
+    /// always reserved because clients use it for their own notices.
+    pub reserved: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    Memory,
+    File,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub backend: HistoryBackend,
+    /// File used by the "file" backend
+    pub path: Option<PathBuf>,
+    /// Messages kept in memory per room
+    pub capacity: usize,
+    /// Messages replayed to a user when they join a room
+    pub replay: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// File registered accounts are stored in. Without one, everyone is a guest.
+    pub path: Option<PathBuf>,
+    /// Let people without an account use any name that isn't registered
+    pub allow_guests: bool,
+    /// Let people register new accounts from the client
+    pub allow_registration: bool,
+    pub min_password_length: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain. If neither it nor `key` exists, a self-signed
+    /// certificate is generated and saved here on startup.
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    /// Registered users who are operators as soon as they log in
+    pub operators: Vec<String>,
+    /// Argon2 hash of the password for `/oper`, from `tcptalk-server --hash-password`
+    pub operator_password_hash: Option<String>,
+    /// File bans are saved in, so they survive restarts
+    pub bans_path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// Frames queued for a client before it counts as a slow consumer
+    pub queue_len: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// How long the "disconnect" policy lets a client stay backed up
+    pub slow_consumer_timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How often each client is pinged
+    pub interval_secs: u64,
+    /// Pings in a row a client may leave unanswered before it's disconnected
+    pub missed_pongs: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResumeConfig {
+    /// How long a disconnected user's session can be resumed, keeping their
+    /// username reserved in the meantime
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long queued messages get to reach clients before the server exits
+    pub drain_timeout_secs: u64,
+    /// Told to clients when the server shuts down
+    pub reason: Option<String>,
+    /// Told to clients as when to expect the server back
+    pub restart_eta_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Make room by dropping the oldest queued frame
+    DropOldest,
+    /// Drop new frames, and disconnect the client if it doesn't catch up in time
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limits for each connection
+    pub connection: BucketLimits,
+    /// Limits shared by all connections from the same IP address
+    pub ip: BucketLimits,
+    /// What happens to a client that goes over a limit
+    pub action: FloodAction,
+    /// How long the "mute" action mutes for
+    pub mute_secs: u64,
+    pub max_connections_per_ip: usize,
+    /// Failed logins a connection may make before it's disconnected
+    pub max_failed_logins: u32,
+    /// Failed logins allowed from one IP address in `failed_login_window_secs`.
+    /// Once they're used up, its connections are closed instead of trying more
+    /// passwords, until the window has passed.
+    pub max_failed_logins_per_ip: u32,
+    pub failed_login_window_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketLimits {
+    pub messages_per_second: f64,
+    /// Messages that may be sent at once before the rate applies
+    pub message_burst: u32,
+    pub bytes_per_second: u64,
+    pub byte_burst: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FloodAction {
+    /// Silently drop messages over the limit
+    Drop,
+    /// Drop them and tell the client
+    Warn,
+    /// Drop them and mute the client for `mute_secs`
+    Mute,
+    Disconnect,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: DEFAULT_PORT,
+            max_connections: 256,
+            max_message_size: 16 * 1024,
+            motd: None,
+            default_room: "#lobby".to_string(),
+            usernames: UsernameRules::default(),
+            history: HistoryConfig::default(),
+            accounts: AccountsConfig::default(),
+            tls: TlsConfig::default(),
+            moderation: ModerationConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            outbound: OutboundConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            resume: ResumeConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+        }
+    }
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            allow_guests: true,
+            allow_registration: true,
+            min_password_length: 8,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert: PathBuf::from("tcptalk-cert.pem"),
+            key: PathBuf::from("tcptalk-key.pem"),
+        }
+    }
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            operators: Vec::new(),
+            operator_password_hash: None,
+            bans_path: PathBuf::from("bans.json"),
+        }
+    }
+}
+
+// `--check-config` prints the config, so the password hash is left out
+impl fmt::Debug for ModerationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hash = self.operator_password_hash.as_ref().map(|_| "<redacted>");
+        f.debug_struct("ModerationConfig")
+            .field("operators", &self.operators)
+            .field("operator_password_hash", &hash)
+            .field("bans_path", &self.bans_path)
+            .finish()
+    }
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            queue_len: 256,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            slow_consumer_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 15,
+            missed_pongs: 3,
+        }
+    }
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        Self { window_secs: 120 }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 5,
+            reason: None,
+            restart_eta_secs: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            connection: BucketLimits {
+                messages_per_second: 5.0,
+                message_burst: 10,
+                bytes_per_second: 16 * 1024,
+                byte_burst: 64 * 1024,
+            },
+            ip: BucketLimits {
+                messages_per_second: 20.0,
+                message_burst: 40,
+                bytes_per_second: 64 * 1024,
+                byte_burst: 256 * 1024,
+            },
+            action: FloodAction::Warn,
+            mute_secs: 60,
+            max_connections_per_ip: 8,
+            max_failed_logins: 3,
+            max_failed_logins_per_ip: 20,
+            failed_login_window_secs: 600,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            backend: HistoryBackend::Memory,
+            path: None,
+            capacity: 500,
+            replay: 50,
+        }
+    }
+}
+
+impl Default for UsernameRules {
+    fn default() -> Self {
+        Self {
+            min_length: 1,
+            max_length: 32,
+            allowed_symbols: "_-.".to_string(),
+            reserved: Vec::new(),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl Config {
+    /// Loads the config file named on the command line (if any), then applies the
+    /// command-line overrides and validates the result.
+    pub fn load(args: &Args) -> io::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                toml::from_str(&contents)
+                    .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+            }
+            None => Config::default(),
+        };
+
+        if !args.bind.is_empty() {
+            config.bind = args.bind.clone();
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(max_connections) = args.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(max_message_size) = args.max_message_size {
+            config.max_message_size = max_message_size;
+        }
+        if let Some(motd) = &args.motd {
+            config.motd = Some(motd.clone());
+        }
+        if let Some(default_room) = &args.default_room {
+            config.default_room = default_room.clone();
+        }
+        if let Some(history_file) = &args.history_file {
+            config.history.backend = HistoryBackend::File;
+            config.history.path = Some(history_file.clone());
+        }
+        if let Some(accounts_file) = &args.accounts_file {
+            config.accounts.path = Some(accounts_file.clone());
+        }
+        if args.no_guests {
+            config.accounts.allow_guests = false;
+        }
+        if args.tls {
+            config.tls.enabled = true;
+        }
+        if let Some(tls_cert) = &args.tls_cert {
+            config.tls.cert = tls_cert.clone();
+        }
+        if let Some(tls_key) = &args.tls_key {
+            config.tls.key = tls_key.clone();
+        }
+        if let Some(max_connections_per_ip) = args.max_connections_per_ip {
+            config.rate_limit.max_connections_per_ip = max_connections_per_ip;
+        }
+        if let Some(bans_file) = &args.bans_file {
+            config.moderation.bans_path = bans_file.clone();
+        }
+        if let Some(drain_timeout) = args.drain_timeout {
+            config.shutdown.drain_timeout_secs = drain_timeout;
+        }
+
+        config.default_room = normalize_room_name(&config.default_room)
+            .map_err(|e| invalid(format!("default_room: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.bind.is_empty() {
+            return Err(invalid("at least one bind address is required".to_string()));
+        }
+        for (i, addr) in self.bind.iter().enumerate() {
+            if self.bind[..i].contains(addr) {
+                return Err(invalid(format!("bind address {} is listed twice", addr)));
+            }
+        }
+        if self.max_connections == 0 {
+            return Err(invalid("max_connections must be at least 1".to_string()));
+        }
+        if !(256..=codec::MAX_FRAME_LEN).contains(&self.max_message_size) {
+            return Err(invalid(format!(
+                "max_message_size must be between 256 and {} bytes",
+                codec::MAX_FRAME_LEN
+            )));
+        }
+        self.usernames.validate_rules()?;
+        self.history.validate()?;
+        self.accounts.validate()?;
+        if !self.moderation.operators.is_empty() && self.accounts.path.is_none() {
+            return Err(invalid(
+                "accounts.path is required when moderation.operators is set".to_string(),
+            ));
+        }
+        self.moderation.validate()?;
+        self.rate_limit.validate(self.max_message_size)?;
+        if self.outbound.queue_len == 0 {
+            return Err(invalid("outbound.queue_len must be at least 1".to_string()));
+        }
+        if self.outbound.slow_consumer_timeout_secs == 0 {
+            return Err(invalid(
+                "outbound.slow_consumer_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.heartbeat.interval_secs == 0 || self.heartbeat.missed_pongs == 0 {
+            return Err(invalid(
+                "heartbeat.interval_secs and heartbeat.missed_pongs must be at least 1".to_string(),
+            ));
+        }
+        if self.shutdown.drain_timeout_secs == 0 {
+            return Err(invalid(
+                "shutdown.drain_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl RateLimitConfig {
+    fn validate(&self, max_message_size: usize) -> io::Result<()> {
+        if self.max_connections_per_ip == 0 {
+            return Err(invalid(
+                "rate_limit.max_connections_per_ip must be at least 1".to_string(),
+            ));
+        }
+        if self.max_failed_logins == 0 || self.max_failed_logins_per_ip == 0 {
+            return Err(invalid(
+                "rate_limit: failed login limits must be at least 1".to_string(),
+            ));
+        }
+        if self.failed_login_window_secs == 0 {
+            return Err(invalid(
+                "rate_limit.failed_login_window_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.mute_secs > MAX_DURATION_SECS {
+            return Err(invalid(format!(
+                "rate_limit.mute_secs must be at most {}",
+                MAX_DURATION_SECS
+            )));
+        }
+        for (name, limits) in [("connection", &self.connection), ("ip", &self.ip)] {
+            if limits.messages_per_second.is_nan()
+                || limits.messages_per_second <= 0.0
+                || limits.bytes_per_second == 0
+            {
+                return Err(invalid(format!(
+                    "rate_limit.{}: rates must be greater than 0",
+                    name
+                )));
+            }
+            if limits.message_burst == 0 {
+                return Err(invalid(format!(
+                    "rate_limit.{}.message_burst must be at least 1",
+                    name
+                )));
+            }
+            // Otherwise the largest allowed message could never get through
+            if limits.byte_burst < (max_message_size + codec::HEADER_LEN) as u64 {
+                return Err(invalid(format!(
+                    "rate_limit.{}.byte_burst must be at least max_message_size + {} bytes",
+                    name,
+                    codec::HEADER_LEN
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ModerationConfig {
+    fn validate(&self) -> io::Result<()> {
+        if let Some(hash) = &self.operator_password_hash {
+            PasswordHash::new(hash).map_err(|e| {
+                invalid(format!(
+                    "moderation.operator_password_hash is not an argon2 hash: {}",
+                    e
+                ))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl AccountsConfig {
+    fn validate(&self) -> io::Result<()> {
+        if !self.allow_guests && self.path.is_none() {
+            return Err(invalid(
+                "accounts.path is required when guests are not allowed".to_string(),
+            ));
+        }
+        if self.min_password_length == 0 {
+            return Err(invalid(
+                "accounts.min_password_length must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl HistoryConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.backend == HistoryBackend::File && self.path.is_none() {
+            return Err(invalid(
+                "history.path is required when history.backend is \"file\"".to_string(),
+            ));
+        }
+        if self.capacity == 0 {
+            return Err(invalid("history.capacity must be at least 1".to_string()));
+        }
+        if self.replay > self.capacity {
+            return Err(invalid(
+                "history.replay may not be larger than history.capacity".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl UsernameRules {
+    fn validate_rules(&self) -> io::Result<()> {
+        if self.min_length == 0 {
+            return Err(invalid(
+                "usernames.min_length must be at least 1".to_string(),
+            ));
+        }
+        if self.min_length > self.max_length {
+            return Err(invalid(
+                "usernames.min_length is greater than usernames.max_length".to_string(),
+            ));
+        }
+        if let Some(c) = self.allowed_symbols.chars().find(|c| c.is_whitespace()) {
+            return Err(invalid(format!(
+                "usernames.allowed_symbols may not contain whitespace ({:?})",
+                c
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks `username` against the rules, returning the reason it was refused.
+    pub fn check(&self, username: &str) -> Result<(), String> {
+        let length = username.chars().count();
+        if length == 0 {
+            return Err("Username cannot be empty. Please try again.".to_string());
+        }
+        if length < self.min_length {
+            return Err(format!(
+                "Username must be at least {} characters long.",
+                self.min_length
+            ));
+        }
+        if length > self.max_length {
+            return Err(format!(
+                "Username must be at most {} characters long.",
+                self.max_length
+            ));
+        }
+        if let Some(c) = username
+            .chars()
+            .find(|c| !c.is_alphanumeric() && !self.allowed_symbols.contains(*c))
+        {
+            return Err(format!("Username may not contain {:?}.", c));
+        }
+        if username.eq_ignore_ascii_case("System")
+            || self
+                .reserved
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(username))
+        {
+            return Err(format!(
+                "Username '{}' is reserved. Please choose another.",
+                username
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+    use std::net::Ipv6Addr;
+
+    // Loads `contents` as the config file, with `flags` on the command line
+    fn load(name: &str, contents: &str, flags: &[&str]) -> io::Result<Config> {
+        let path = temp_file(&format!("{}.toml", name));
+        fs::write(&path, contents).unwrap();
+        let path_arg = path.to_string_lossy().into_owned();
+        let mut argv = vec!["tcptalk-server", "--config", &path_arg];
+        argv.extend(flags);
+        let config = Config::load(&Args::parse_from(argv));
+        fs::remove_file(path).unwrap();
+        config
+    }
+
+    fn error(result: io::Result<Config>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let contents = r##"
+            port = 9000
+            max_connections = 10
+            motd = "from the file"
+            default_room = "#general"
+
+            [history]
+            backend = "memory"
+            replay = 5
+        "##;
+        let config = load(
+            "config-override",
+            contents,
+            &[
+                "--port",
+                "9100",
+                "--motd",
+                "from the flags",
+                "--history-file",
+                "h.jsonl",
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.motd.as_deref(), Some("from the flags"));
+        assert_eq!(config.history.backend, HistoryBackend::File);
+        assert_eq!(config.history.path, Some(PathBuf::from("h.jsonl")));
+        // Left alone by the flags
+        assert_eq!(config.max_connections, 10);
+        assert_eq!(config.default_room, "#general");
+        assert_eq!(config.history.replay, 5);
+        // Neither sets it
+        assert_eq!(config.history.capacity, HistoryConfig::default().capacity);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let top_level = error(load("config-unknown", "prot = 9000", &[]));
+        assert!(top_level.contains("unknown field `prot`"), "{}", top_level);
+
+        let nested = error(load(
+            "config-unknown-nested",
+            "[usernames]\nmin_length = 1\nmin_len = 1",
+            &[],
+        ));
+        assert!(nested.contains("unknown field `min_len`"), "{}", nested);
+    }
+
+    #[test]
+    fn binds_to_several_addresses() {
+        let config = load("config-bind", r#"bind = ["127.0.0.1", "::1"]"#, &[]).unwrap();
+        assert_eq!(
+            config.bind,
+            [
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+
+        // Flags replace the file's addresses rather than adding to them
+        let config = load(
+            "config-bind-flags",
+            r#"bind = ["127.0.0.1"]"#,
+            &["--bind", "::", "--bind", "0.0.0.0"],
+        )
+        .unwrap();
+        assert_eq!(
+            config.bind,
+            [
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_settings_are_reported() {
+        let cases = [
+            ("bind = []", "at least one bind address"),
+            (
+                r#"bind = ["::1", "::1"]"#,
+                "bind address ::1 is listed twice",
+            ),
+            ("max_connections = 0", "max_connections must be at least 1"),
+            ("max_message_size = 10", "max_message_size must be between"),
+            (
+                "usernames.min_length = 0",
+                "usernames.min_length must be at least 1",
+            ),
+            (
+                r#"default_room = "no spaces""#,
+                "default_room: Room names may not contain ' '",
+            ),
+            ("[history]\nbackend = \"file\"", "history.path is required"),
+            (
+                "[history]\ncapacity = 5\nreplay = 10",
+                "history.replay may not be larger",
+            ),
+            (
+                "[accounts]\nallow_guests = false",
+                "accounts.path is required",
+            ),
+            (
+                r#"moderation.operators = ["alice"]"#,
+                "accounts.path is required",
+            ),
+            (
+                r#"moderation.operator_password_hash = "hunter2""#,
+                "is not an argon2 hash",
+            ),
+            (
+                "rate_limit.max_connections_per_ip = 0",
+                "rate_limit.max_connections_per_ip must be at least 1",
+            ),
+            (
+                "outbound.queue_len = 0",
+                "outbound.queue_len must be at least 1",
+            ),
+            (
+                "shutdown.drain_timeout_secs = 0",
+                "drain_timeout_secs must be at least 1",
+            ),
+            ("heartbeat.missed_pongs = 0", "heartbeat.interval_secs and"),
+            (
+                "rate_limit.mute_secs = 999999999999",
+                "rate_limit.mute_secs must be at most",
+            ),
+        ];
+        for (contents, expected) in cases {
+            let message = error(load("config-invalid", contents, &[]));
+            assert!(message.contains(expected), "{}: {}", contents, message);
+        }
+
+        // Flags are checked the same way
+        let message = error(load(
+            "config-invalid-flags",
+            "",
+            &["--max-connections", "0"],
+        ));
+        assert!(
+            message.contains("max_connections must be at least 1"),
+            "{}",
+            message
+        );
+        assert!(load("config-valid", "", &[]).is_ok());
+    }
+
+    #[test]
+    fn the_operator_password_hash_is_not_printed() {
+        let moderation = ModerationConfig {
+            operator_password_hash: Some("$argon2id$v=19$secret".to_string()),
+            ..ModerationConfig::default()
+        };
+        let printed = format!("{:#?}", moderation);
+        assert!(printed.contains("<redacted>"));
+        assert!(!printed.contains("secret"));
+    }
+}
@@ -0,0 +1,367 @@
+use crate::config::{HistoryBackend, HistoryConfig};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tcptalk_protocol::HistoryMessage;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub room: String,
+    #[serde(flatten)]
+    pub message: HistoryMessage,
+}
+
+// Storage for chat history. Implementations keep messages per room and return
+// the most recent ones in the order they were appended.
+pub trait HistoryStore: Send {
+    fn append(&mut self, entry: HistoryEntry) -> io::Result<()>;
+
+    // The last `limit` messages of `room`, oldest first
+    fn recent(&self, room: &str, limit: usize) -> Vec<HistoryMessage>;
+
+    // Highest message ID stored, so IDs keep increasing across restarts
+    fn last_id(&self) -> u64;
+
+    // Makes sure everything appended so far is durably stored
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps the last `capacity` messages of every room in memory
+pub struct MemoryStore {
+    rooms: HashMap<String, VecDeque<HistoryMessage>>,
+    capacity: usize,
+    last_id: u64,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            capacity,
+            last_id: 0,
+        }
+    }
+}
+
+impl HistoryStore for MemoryStore {
+    fn append(&mut self, entry: HistoryEntry) -> io::Result<()> {
+        self.last_id = self.last_id.max(entry.message.id);
+
+        let messages = self.rooms.entry(entry.room).or_default();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(entry.message);
+        Ok(())
+    }
+
+    fn recent(&self, room: &str, limit: usize) -> Vec<HistoryMessage> {
+        let Some(messages) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        let skip = messages.len().saturating_sub(limit);
+        messages.iter().skip(skip).cloned().collect()
+    }
+
+    fn last_id(&self) -> u64 {
+        self.last_id
+    }
+}
+
+// Appends every message to a JSON-lines file and serves reads from an in-memory
+// window of the most recent messages, which is reloaded from the file on startup
+pub struct FileStore {
+    // The file is written by a thread of its own, so sending a message never
+    // waits on the disk
+    writer: mpsc::Sender<FileWrite>,
+    recent: MemoryStore,
+}
+
+enum FileWrite {
+    Line(Vec<u8>),
+    // Syncs everything written so far to disk, then reports how that went
+    Sync(mpsc::Sender<io::Result<()>>),
+}
+
+// Writes lines to the history file in the order they were appended, until the
+// store is dropped
+fn write_lines(mut file: File, path: PathBuf, writes: mpsc::Receiver<FileWrite>) {
+    for write in writes {
+        match write {
+            FileWrite::Line(line) => {
+                if let Err(err) = file.write_all(&line) {
+                    eprintln!("Failed to write to {}: {}", path.display(), err);
+                }
+            }
+            FileWrite::Sync(done) => {
+                let _ = done.send(file.sync_all());
+            }
+        }
+    }
+}
+
+impl FileStore {
+    pub fn open(path: &Path, capacity: usize) -> io::Result<Self> {
+        let mut recent = MemoryStore::new(capacity);
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<HistoryEntry>(&line) {
+                    Ok(entry) => recent.append(entry)?,
+                    // A torn final write shouldn't stop the server from starting
+                    Err(err) => eprintln!(
+                        "Skipping unreadable history entry at {}:{}: {}",
+                        path.display(),
+                        line_number + 1,
+                        err
+                    ),
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        // Otherwise the next message would be appended to a torn line and lost
+        // along with it
+        if file.seek(SeekFrom::End(-1)).is_ok() {
+            let mut last = [0];
+            file.read_exact(&mut last)?;
+            if last != *b"\n" {
+                file.write_all(b"\n")?;
+            }
+        }
+        let (writer, writes) = mpsc::channel();
+        let path = path.to_path_buf();
+        thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || write_lines(file, path, writes))?;
+        Ok(Self { writer, recent })
+    }
+
+    fn send(&self, write: FileWrite) -> io::Result<()> {
+        self.writer
+            .send(write)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "history writer stopped"))
+    }
+}
+
+impl HistoryStore for FileStore {
+    fn append(&mut self, entry: HistoryEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.send(FileWrite::Line(line))?;
+        self.recent.append(entry)
+    }
+
+    fn recent(&self, room: &str, limit: usize) -> Vec<HistoryMessage> {
+        self.recent.recent(room, limit)
+    }
+
+    fn last_id(&self) -> u64 {
+        self.recent.last_id()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let (done, result) = mpsc::channel();
+        self.send(FileWrite::Sync(done))?;
+        result.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "history writer stopped",
+            ))
+        })
+    }
+}
+
+// Assigns IDs and timestamps to new messages and records them in a store
+pub struct History {
+    store: Box<dyn HistoryStore>,
+    next_id: u64,
+    replay_len: usize,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> io::Result<Self> {
+        let store: Box<dyn HistoryStore> = match (config.backend, &config.path) {
+            (HistoryBackend::File, Some(path)) => Box::new(FileStore::open(path, config.capacity)?),
+            _ => Box::new(MemoryStore::new(config.capacity)),
+        };
+        Ok(Self::new(store, config.replay))
+    }
+
+    // IDs start from the time shifted up 16 bits, so those handed out to events
+    // that were never stored aren't handed out again after a restart, even
+    // with nothing kept on disk. The server would have to average 65536 IDs a
+    // millisecond to catch up with the clock.
+    pub fn new(store: Box<dyn HistoryStore>, replay_len: usize) -> Self {
+        let next_id = (store.last_id() + 1).max(now_millis() << 16);
+        Self {
+            store,
+            next_id,
+            replay_len,
+        }
+    }
+
+    // Allocates an ID for an event that isn't kept in history, like a join or a
+    // direct message, so it can't collide with the ID of a stored message
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn record(
+        &mut self,
+        room: &str,
+        author: &str,
+        text: &str,
+        action: bool,
+        client_id: Option<u64>,
+    ) -> HistoryMessage {
+        let message = HistoryMessage {
+            id: self.next_id(),
+            timestamp: now_millis(),
+            author: author.to_string(),
+            text: text.to_string(),
+            action,
+            client_id,
+        };
+
+        let entry = HistoryEntry {
+            room: room.to_string(),
+            message: message.clone(),
+        };
+        if let Err(err) = self.store.append(entry) {
+            eprintln!("Failed to store message {}: {}", message.id, err);
+        }
+        message
+    }
+
+    // The messages replayed to a client when it joins `room`
+    pub fn replay(&self, room: &str) -> Vec<HistoryMessage> {
+        self.store.recent(room, self.replay_len)
+    }
+
+    // Every stored message in `room` after `after_id`, for a client catching up
+    // on what it missed
+    pub fn since(&self, room: &str, after_id: u64) -> Vec<HistoryMessage> {
+        let mut messages = self.store.recent(room, usize::MAX);
+        messages.retain(|message| message.id > after_id);
+        messages
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+    use std::fs;
+
+    fn texts(messages: &[HistoryMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn memory_keeps_the_newest_messages_of_each_room() {
+        let mut history = History::new(Box::new(MemoryStore::new(3)), 2);
+        for i in 0..5 {
+            history.record("#lobby", "alice", &format!("lobby {}", i), false, None);
+        }
+        history.record("#ops", "bob", "ops 0", false, None);
+
+        assert_eq!(texts(&history.replay("#lobby")), ["lobby 3", "lobby 4"]);
+        let kept = history.since("#lobby", 0);
+        assert_eq!(texts(&kept), ["lobby 2", "lobby 3", "lobby 4"]);
+        assert_eq!(texts(&history.replay("#ops")), ["ops 0"]);
+        assert!(history.replay("#empty").is_empty());
+    }
+
+    #[test]
+    fn since_returns_only_newer_messages() {
+        let mut history = History::new(Box::new(MemoryStore::new(10)), 10);
+        let first = history.record("#lobby", "alice", "one", false, None);
+        // IDs are shared with events that aren't stored, like joins
+        history.next_id();
+        let second = history.record("#lobby", "bob", "two", true, None);
+        assert!(second.id > first.id + 1);
+
+        assert_eq!(texts(&history.since("#lobby", first.id)), ["two"]);
+        assert!(history.since("#lobby", second.id).is_empty());
+        assert!(history.since("#lobby", first.id)[0].action);
+    }
+
+    #[test]
+    fn unstored_ids_are_not_reused_after_a_restart() {
+        let mut history = History::new(Box::new(MemoryStore::new(10)), 10);
+        let stored = history.record("#lobby", "alice", "one", false, None);
+        let join = history.next_id();
+        assert!(join > stored.id);
+        drop(history);
+
+        // The restart itself takes longer than this
+        thread::sleep(std::time::Duration::from_millis(2));
+        let mut history = History::new(Box::new(MemoryStore::new(10)), 10);
+        assert!(history.next_id() > join);
+        assert!(history.record("#lobby", "bob", "two", false, None).id > join);
+    }
+
+    #[test]
+    fn file_history_is_reloaded_and_ids_keep_increasing() {
+        let path = temp_file("history-file");
+        let mut history = History::new(Box::new(FileStore::open(&path, 2).unwrap()), 10);
+        let mut last = 0;
+        for text in ["one", "two", "three"] {
+            last = history.record("#lobby", "alice", text, false, None).id;
+        }
+        history.flush().unwrap();
+        drop(history);
+        // A write torn by a crash is skipped
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"room\":\"#lob")
+            .unwrap();
+
+        let mut history = History::new(Box::new(FileStore::open(&path, 2).unwrap()), 10);
+        assert_eq!(texts(&history.replay("#lobby")), ["two", "three"]);
+        assert!(history.record("#lobby", "bob", "four", false, None).id > last);
+        history.flush().unwrap();
+        drop(history);
+
+        let history = History::new(Box::new(FileStore::open(&path, 2).unwrap()), 10);
+        assert_eq!(texts(&history.replay("#lobby")), ["three", "four"]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,211 +1,1343 @@
+mod config;
+use crate::config::{Args, Config, FloodAction};
+
+mod accounts;
+use crate::accounts::Accounts;
+
+mod history;
+use crate::history::{History, now_millis};
+
+mod tls;
+
+mod moderation;
+use crate::moderation::{Moderation, handle_moderation_request, handle_oper_request};
+
+mod outbox;
+use crate::outbox::{Outbox, RoomFrame, Sent};
+
+mod reader;
+use crate::reader::FrameReader;
+
+mod rate_limit;
+use crate::rate_limit::{ConnectionLimits, RateLimiter, handle_flood};
+
+mod sessions;
+use crate::sessions::{Session, Sessions, new_token};
+
+mod rooms;
+use crate::rooms::{
+    announce_join, announce_leave, broadcast_user_list, handle_room_list_request,
+    handle_room_request, handle_user_list_request, join_room, normalize_room_name, release_room,
+};
+
+#[cfg(test)]
+pub(crate) mod test_support;
+
+use clap::Parser;
 use indexmap::IndexMap;
-use serde_json;
+use socket2::{Domain, Socket, Type};
 use std::{
-    io::{self, Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::{Arc, Mutex},
-    thread,
+    collections::{BTreeMap, HashMap},
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    process,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tcptalk_protocol::{Frame, codec, rustls::ServerConfig};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    signal,
+    sync::broadcast,
+    task,
+    time::{self, MissedTickBehavior},
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 struct Client {
-    stream: TcpStream,
+    outbox: Outbox,
     username: String,
+    // Each room the client is in, with a sender for the room's broadcast channel
+    rooms: IndexMap<String, broadcast::Sender<RoomFrame>>,
+    operator: bool,
+    // Lets the client resume this session after disconnecting; taken away when
+    // it's removed on purpose, e.g. by a kick
+    resume_token: Option<String>,
 }
 
+impl Client {
+    // Tells the client why it's being disconnected, then closes the connection.
+    // Its session can't be resumed.
+    fn disconnect(&mut self, reason: &str) {
+        let notice = Frame::Disconnect {
+            reason: reason.to_string(),
+        };
+        let _ = self.outbox.send(&notice);
+        self.outbox.close();
+        self.resume_token = None;
+    }
+}
+
+// Logged-in clients, and the broadcast channel of every room that has members.
+// They share a lock, so a room's channel comes and goes with its membership.
+#[derive(Default)]
+struct Connections {
+    clients: IndexMap<SocketAddr, Client>,
+    rooms: HashMap<String, broadcast::Sender<RoomFrame>>,
+}
+
+impl Connections {
+    // Removes the client at `addr`, and the channels of the rooms it was the
+    // last member of. Returns the client and the rooms it was in.
+    fn remove(&mut self, addr: &SocketAddr) -> Option<(Client, Vec<String>)> {
+        let mut client = self.clients.shift_remove(addr)?;
+        let rooms: Vec<String> = mem::take(&mut client.rooms).into_keys().collect();
+        for room in &rooms {
+            release_room(self, room);
+        }
+        Some((client, rooms))
+    }
+}
+
+// Server-wide state, cloned into every accept loop and connection task
+#[derive(Clone)]
+struct Shared {
+    connections: Arc<Mutex<Connections>>,
+    history: Arc<Mutex<History>>,
+    accounts: Arc<Mutex<Accounts>>,
+    moderation: Arc<Mutex<Moderation>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    sessions: Arc<Mutex<Sessions>>,
+    config: Arc<Config>,
+    open_connections: Arc<AtomicUsize>,
+    tls: Option<Arc<ServerConfig>>,
+    // Cancelled when the server is shutting down
+    shutdown: CancellationToken,
+    // Every connection's tasks, so shutdown can wait for them
+    tasks: TaskTracker,
+}
+
+// Sends `frame` to every member of `room` through the room's broadcast channel.
+// Each member's writer task does the actual sending.
 fn broadcast_message(
-    message: &[u8],
+    frame: &Frame,
+    room: &str,
     sender_addr: SocketAddr,
-    connections: &Arc<Mutex<IndexMap<SocketAddr, Client>>>,
+    connections: &Arc<Mutex<Connections>>,
     include_sender: bool,
 ) -> io::Result<()> {
+    let frame = RoomFrame {
+        bytes: Arc::from(codec::encode_frame(frame)?),
+        exclude: (!include_sender).then_some(sender_addr),
+        echo: None,
+    };
+    send_to_room(frame, room, connections);
+    Ok(())
+}
+
+// Like `broadcast_message`, but the sender gets `echo` instead of `frame`, in the
+// same place among the room's other messages as everyone else gets `frame`
+fn broadcast_with_echo(
+    frame: &Frame,
+    echo: &Frame,
+    room: &str,
+    sender_addr: SocketAddr,
+    connections: &Arc<Mutex<Connections>>,
+) -> io::Result<()> {
+    let frame = RoomFrame {
+        bytes: Arc::from(codec::encode_frame(frame)?),
+        exclude: Some(sender_addr),
+        echo: Some(Arc::from(codec::encode_frame(echo)?)),
+    };
+    send_to_room(frame, room, connections);
+    Ok(())
+}
+
+fn send_to_room(frame: RoomFrame, room: &str, connections: &Arc<Mutex<Connections>>) {
+    if let Some(sender) = connections.lock().unwrap().rooms.get(room) {
+        // Only fails if nobody is subscribed yet
+        let _ = sender.send(frame);
+    }
+}
+
+// Sends `frame` to a single logged-in client
+fn send_to(
+    addr: SocketAddr,
+    frame: &Frame,
+    connections: &Arc<Mutex<Connections>>,
+) -> io::Result<()> {
+    let conn_map = connections.lock().unwrap();
+    match conn_map.clients.get(&addr) {
+        Some(client) => client.outbox.send(frame).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+// Tells a client that its message wasn't delivered. If the client numbered the
+// message, it gets a `Rejected` it can match up with it.
+fn reject(
+    addr: SocketAddr,
+    client_id: Option<u64>,
+    message: String,
+    connections: &Arc<Mutex<Connections>>,
+) -> io::Result<()> {
+    let frame = match client_id {
+        Some(client_id) => Frame::Rejected { client_id, message },
+        None => Frame::Error { message },
+    };
+    send_to(addr, &frame, connections)
+}
+
+// Delivers a `DirectMessage` to the user it's for, matched case-insensitively
+// like usernames are at login. The sender gets an error if nobody by that name
+// is online, or the message couldn't be queued for them.
+fn send_direct(
+    addr: SocketAddr,
+    from: &str,
+    frame: Frame,
+    connections: &Arc<Mutex<Connections>>,
+    history: &Arc<Mutex<History>>,
+) -> io::Result<()> {
+    let Frame::DirectMessage {
+        to,
+        text,
+        client_id,
+        action,
+    } = frame
+    else {
+        return Ok(());
+    };
+    // Locked first, as everywhere else, and held while the message is queued so
+    // IDs reach each client in order
+    let mut history = history.lock().unwrap();
     let mut conn_map = connections.lock().unwrap();
-    let mut to_remove = Vec::new();
-
-    for (addr, client) in conn_map.iter_mut() {
-        if include_sender || *addr != sender_addr {
-            match client.stream.write_all(message) {
-                Ok(_) => match client.stream.flush() {
-                    Ok(_) => {}
-                    Err(_) => to_remove.push(*addr),
-                },
-                Err(_) => to_remove.push(*addr),
+    let recipient = conn_map
+        .clients
+        .iter_mut()
+        .find(|(_, client)| client.username.eq_ignore_ascii_case(&to));
+
+    let timestamp = now_millis();
+    let result = match recipient {
+        Some((recipient_addr, _)) if *recipient_addr == addr => {
+            Err("You can't send a direct message to yourself.".to_string())
+        }
+        Some((_, client)) => {
+            let id = history.next_id();
+            let direct = Frame::DirectChat {
+                id,
+                timestamp,
+                from: from.to_string(),
+                text,
+                action,
+            };
+            match client.outbox.send(&direct)? {
+                Sent::Queued => Ok(id),
+                Sent::Dropped => Err(format!(
+                    "{} isn't keeping up with messages. Please try again later.",
+                    to
+                )),
+                Sent::Closed => Err(format!("{} is not online.", to)),
             }
         }
+        None => Err(format!("{} is not online.", to)),
+    };
+    drop(conn_map);
+    drop(history);
+
+    match (result, client_id) {
+        (Ok(id), Some(client_id)) => {
+            let ack = Frame::Ack {
+                client_id,
+                id,
+                timestamp,
+            };
+            send_to(addr, &ack, connections)
+        }
+        (Ok(_), None) => Ok(()),
+        (Err(message), client_id) => reject(addr, client_id, message, connections),
     }
+}
 
-    for addr in to_remove {
-        conn_map.shift_remove(&addr);
-        println!(
-            "Removed dead connection: {} (Total: {})",
-            addr,
-            conn_map.len()
-        );
+// Reads the next frame from a client. Oversized or malformed frames are reported
+// back to the client through `reply` and skipped; any other error ends the
+// connection.
+async fn next_frame<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    reply: impl Fn(&Frame) -> io::Result<()>,
+) -> io::Result<Option<Frame>> {
+    loop {
+        match reader.read_frame().await {
+            Ok(frame) => return Ok(frame),
+            Err(err) if err.is_recoverable() => {
+                let error = Frame::Error {
+                    message: err.to_string(),
+                };
+                reply(&error)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
     }
+}
 
-    Ok(())
+// A client that has logged in, or resumed an earlier session
+struct LoggedIn {
+    username: String,
+    resume_token: String,
+    // The resumed session's rooms, and the last message ID the client saw in
+    // each of them
+    resumed: Option<(Vec<String>, BTreeMap<String, u64>)>,
 }
 
-fn broadcast_user_list(connections: &Arc<Mutex<IndexMap<SocketAddr, Client>>>) -> io::Result<()> {
-    let conn_map = connections.lock().unwrap();
-    let user_list: Vec<String> = conn_map
-        .values()
-        .map(|client| client.username.clone())
-        .collect();
-    drop(conn_map);
+// Finds the session `token` belongs to. If the session's old connection hasn't
+// dropped yet, the new one takes over from it.
+fn resume_session(token: &str, ip: IpAddr, shared: &Shared) -> Result<Session, String> {
+    let session = {
+        let mut conn_map = shared.connections.lock().unwrap();
+        let mut sessions = shared.sessions.lock().unwrap();
+        let old = conn_map
+            .clients
+            .iter()
+            .find(|(_, client)| client.resume_token.as_deref() == Some(token))
+            .map(|(addr, _)| *addr);
+        let session = match old.and_then(|addr| conn_map.remove(&addr)) {
+            // Its handler finds it gone and leaves the announcements to this one
+            Some((client, rooms)) => {
+                client.outbox.close();
+                Session {
+                    username: client.username,
+                    rooms,
+                }
+            }
+            None => sessions
+                .take(token)
+                .ok_or_else(|| "Your session has expired.".to_string())?,
+        };
+        // Kept for this connection until it's added to `connections`. It's only
+        // reserved already if the user is logging in with their password
+        // elsewhere, which drops the session if it succeeds.
+        if !sessions.reserve(&session.username) {
+            let reason = format!("Someone is logging in as {}.", session.username);
+            sessions.hold(token.to_string(), session);
+            return Err(reason);
+        }
+        session
+    };
+    let allowed = shared
+        .moderation
+        .lock()
+        .unwrap()
+        .check_login(&session.username, ip);
+    if let Err(reason) = allowed {
+        shared.sessions.lock().unwrap().release(&session.username);
+        return Err(reason);
+    }
+    Ok(session)
+}
 
-    let user_list_json = format!("USER_LIST:{}\n", serde_json::to_string(&user_list).unwrap());
-    broadcast_message(
-        user_list_json.as_bytes(),
-        "0.0.0.0:0".parse().unwrap(),
-        connections,
-        true,
-    )
+// Whether someone is using `username`, is logging in with it, or may still come
+// back for it after losing their connection. Names are only claimed with the
+// connections lock held, so nobody can take one between this check and the claim.
+fn username_taken(username: &str, conn_map: &Connections, sessions: &Arc<Mutex<Sessions>>) -> bool {
+    conn_map
+        .clients
+        .values()
+        .any(|client| client.username.eq_ignore_ascii_case(username))
+        || sessions.lock().unwrap().is_reserved(username)
 }
 
-fn handle_user_list_request(
-    mut stream: TcpStream,
-    connections: &Arc<Mutex<IndexMap<SocketAddr, Client>>>,
-) -> io::Result<()> {
+// Reserves `username` for a client that's logging in, unless it's taken. With
+// `over_held`, a session held for the name doesn't count, since the client is
+// about to prove it owns the name. The client must release the name once it's
+// in `connections`, or if it doesn't get in.
+fn reserve_username(
+    username: &str,
+    over_held: bool,
+    connections: &Arc<Mutex<Connections>>,
+    sessions: &Arc<Mutex<Sessions>>,
+) -> bool {
     let conn_map = connections.lock().unwrap();
-    let user_list: Vec<String> = conn_map
+    if conn_map
+        .clients
         .values()
-        .map(|client| client.username.clone())
-        .collect();
-    drop(conn_map);
-
-    let user_list_json = format!("USER_LIST:{}\n", serde_json::to_string(&user_list).unwrap());
-    stream.write_all(user_list_json.as_bytes())?;
-    stream.flush()?;
-    Ok(())
+        .any(|client| client.username.eq_ignore_ascii_case(username))
+    {
+        return false;
+    }
+    let mut sessions = sessions.lock().unwrap();
+    if !over_held && sessions.is_reserved(username) {
+        return false;
+    }
+    sessions.reserve(username)
 }
 
-fn get_username(
-    mut stream: &TcpStream,
-    connections: &Arc<Mutex<IndexMap<SocketAddr, Client>>>,
-) -> io::Result<String> {
-    loop {
-        stream.write_all(b"Enter your username: ")?;
-        stream.flush()?;
+// Renames the guest at `addr` to `username`, which has to be a name they could
+// log in with. Returns the new name and the rooms to announce it in.
+fn rename(
+    addr: SocketAddr,
+    username: &str,
+    connections: &Arc<Mutex<Connections>>,
+    accounts: &Arc<Mutex<Accounts>>,
+    moderation: &Arc<Mutex<Moderation>>,
+    sessions: &Arc<Mutex<Sessions>>,
+    config: &Config,
+) -> Result<(String, Vec<String>), String> {
+    let username = username.trim().to_string();
+    config.usernames.check(&username)?;
+    moderation
+        .lock()
+        .unwrap()
+        .check_login(&username, addr.ip())?;
+
+    let old = match connections.lock().unwrap().clients.get(&addr) {
+        Some(client) => client.username.clone(),
+        None => return Err("You are not logged in.".to_string()),
+    };
+    let accounts = accounts.lock().unwrap();
+    if accounts.is_registered(&old) {
+        return Err("Registered users can't change their name.".to_string());
+    }
+    // Muted users would otherwise get around it under a new name
+    moderation.lock().unwrap().check_mute(&old)?;
+    if accounts.is_registered(&username) {
+        return Err(format!(
+            "{} is registered. Log in with its password to use it.",
+            username
+        ));
+    }
+    drop(accounts);
 
-        let mut buf = [0u8; 32];
-        let n = stream.read(&mut buf)?;
+    let mut conn_map = connections.lock().unwrap();
+    // Changing only the case of your own name is fine
+    if !username.eq_ignore_ascii_case(&old) && username_taken(&username, &conn_map, sessions) {
+        return Err("Username is already taken. Please choose another.".to_string());
+    }
+    let client = conn_map
+        .clients
+        .get_mut(&addr)
+        .ok_or_else(|| "You are not logged in.".to_string())?;
+    client.username = username.clone();
+    Ok((username, client.rooms.keys().cloned().collect()))
+}
 
-        let username = String::from_utf8_lossy(&buf[..n]).trim().to_string();
+async fn get_username<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    outbox: &Outbox,
+    limits: &mut ConnectionLimits,
+    ip: IpAddr,
+    shared: &Shared,
+) -> io::Result<LoggedIn> {
+    let Shared {
+        connections,
+        accounts,
+        moderation,
+        rate_limiter,
+        sessions,
+        config,
+        ..
+    } = shared;
+    let reply = |frame: &Frame| outbox.send(frame).map(|_| ());
+    // Closes the connection, telling the client why
+    let refuse = |reason: &str| {
+        let disconnect = Frame::Disconnect {
+            reason: reason.to_string(),
+        };
+        match outbox.close_with(&disconnect) {
+            Ok(()) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{}: {}", ip, reason),
+            ),
+            Err(err) => err,
+        }
+    };
 
-        if username.is_empty() {
-            stream.write_all(b"Username cannot be empty. Please try again.\n")?;
-            stream.flush()?;
+    let (username, resumed) = loop {
+        let Some(frame) = next_frame(reader, reply).await? else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        // Every password tried costs a hash, so logging in is limited too
+        if !rate_limiter
+            .lock()
+            .unwrap()
+            .check(ip, limits, reader.bytes_read())
+        {
+            if config.rate_limit.action == FloodAction::Disconnect {
+                return Err(refuse("Disconnected for flooding."));
+            }
+            let reason = "You are logging in too fast. Please wait and try again.".to_string();
+            reply(&Frame::LoginRejected { reason })?;
             continue;
         }
 
-        if username.eq_ignore_ascii_case("System") {
-            stream.write_all(b"Username 'System' is reserved. Please choose another.\n")?;
-            stream.flush()?;
-            continue;
+        let (username, password, registering) = match frame {
+            Frame::Login { username, password } => (username, password, false),
+            Frame::Register { username, password } => (username, Some(password), true),
+            Frame::Resume { token, last_ids } => match resume_session(&token, ip, shared) {
+                Ok(session) => break (session.username, Some((session.rooms, last_ids))),
+                Err(reason) => {
+                    reply(&Frame::LoginRejected { reason })?;
+                    continue;
+                }
+            },
+            _ => {
+                let error = Frame::Error {
+                    message: "Log in before sending messages.".to_string(),
+                };
+                reply(&error)?;
+                continue;
+            }
+        };
+        let username = username.trim().to_string();
+
+        let with_password = password.is_some();
+        if with_password && rate_limiter.lock().unwrap().login_blocked(ip) {
+            return Err(refuse(
+                "Too many failed logins from your address. Please try again later.",
+            ));
         }
 
-        let conn_map = connections.lock().unwrap();
-        let username_taken = conn_map
-            .values()
-            .any(|client| client.username.eq_ignore_ascii_case(&username));
-        drop(conn_map);
+        let rejection = config
+            .usernames
+            .check(&username)
+            .and_then(|_| moderation.lock().unwrap().check_login(&username, ip));
+        // A registered user's session is held for them after they disconnect,
+        // but logging in with the password works too
+        let over_held =
+            with_password && !registering && accounts.lock().unwrap().is_registered(&username);
+        // Reserved before the password is checked, since that takes a while and
+        // another client could log in with the name in the meantime
+        let rejection = rejection.err().or_else(|| {
+            (!reserve_username(&username, over_held, connections, sessions))
+                .then(|| "Username is already taken. Please choose another.".to_string())
+        });
+
+        if let Some(reason) = rejection {
+            reply(&Frame::LoginRejected { reason })?;
+            continue;
+        }
 
-        if username_taken {
-            stream.write_all(b"Username is already taken. Please choose another.\n")?;
-            stream.flush()?;
+        // Password hashing is slow on purpose, so it runs off the async workers.
+        // Guests have nothing to hash.
+        let result = match password {
+            Some(password) if registering => task::block_in_place(|| {
+                accounts::register(&username, &password, accounts, &config.accounts)
+            }),
+            Some(password) => task::block_in_place(|| {
+                accounts::authenticate(&username, Some(password), accounts, &config.accounts)
+            }),
+            None => accounts::authenticate(&username, None, accounts, &config.accounts),
+        };
+        if let Err(frame) = result {
+            sessions.lock().unwrap().release(&username);
+            let failed = with_password && matches!(frame, Frame::LoginRejected { .. });
+            reply(&frame)?;
+            if failed && rate_limiter.lock().unwrap().login_failed(ip, limits) {
+                return Err(refuse("Too many failed logins."));
+            }
             continue;
         }
+        // Replaced by this login, so it can't be resumed any more
+        sessions.lock().unwrap().drop_held(&username);
+        break (username, None);
+    };
 
-        return Ok(username);
+    let resume_token = new_token();
+    reply(&Frame::LoginAccepted {
+        username: username.clone(),
+        resume_token: resume_token.clone(),
+    })?;
+    if let Some(motd) = &config.motd {
+        reply(&Frame::Notice { text: motd.clone() })?;
     }
+    Ok(LoggedIn {
+        username,
+        resume_token,
+        resumed,
+    })
 }
 
-fn handle_client(
-    mut stream: TcpStream,
-    connections: Arc<Mutex<IndexMap<SocketAddr, Client>>>,
-) -> io::Result<()> {
-    let addr = stream.peer_addr()?;
+fn shutdown_notice(config: &Config) -> Frame {
+    Frame::ShuttingDown {
+        reason: config.shutdown.reason.clone(),
+        restart_in_secs: config.shutdown.restart_eta_secs,
+    }
+}
+
+async fn handle_client<S>(stream: S, addr: SocketAddr, shared: Shared) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = FrameReader::with_max_frame_len(reader, shared.config.max_message_size);
+    let outbox = Outbox::spawn(writer, addr, &shared.config.outbound, &shared.tasks);
+    let closed = outbox.closed();
+
+    let mut limits = ConnectionLimits::new(&shared.config.rate_limit);
+    let logged_in = tokio::select! {
+        logged_in = get_username(&mut reader, &outbox, &mut limits, addr.ip(), &shared) => logged_in?,
+        _ = closed.cancelled() => return Ok(()),
+        _ = shared.shutdown.cancelled() => {
+            return outbox.close_with(&shutdown_notice(&shared.config));
+        }
+    };
+
+    let LoggedIn {
+        mut username,
+        resume_token,
+        resumed,
+    } = logged_in;
+    let Shared {
+        connections,
+        history,
+        accounts,
+        moderation,
+        rate_limiter,
+        sessions,
+        config,
+        shutdown,
+        ..
+    } = shared;
 
-    let username = get_username(&stream, &connections)?;
+    let (rooms, last_ids) = match resumed {
+        Some((rooms, last_ids)) => (rooms, Some(last_ids)),
+        // New users land in the default room
+        None => (vec![config.default_room.clone()], None),
+    };
 
+    // Operators named in the config must have logged in with their account's
+    // password, so a guest can't claim the name while it's free
+    let operator = config
+        .moderation
+        .operators
+        .iter()
+        .any(|operator| operator.eq_ignore_ascii_case(&username))
+        && accounts.lock().unwrap().is_registered(&username);
+
+    let total = {
+        let mut conn_map = connections.lock().unwrap();
+        conn_map.clients.insert(
+            addr,
+            Client {
+                outbox,
+                username: username.clone(),
+                rooms: IndexMap::new(),
+                operator,
+                resume_token: Some(resume_token),
+            },
+        );
+        sessions.lock().unwrap().release(&username);
+        conn_map.clients.len()
+    };
+    println!("{} connected from {} (Total: {})", username, addr, total);
+
+    // Errors end the session too, but the client still has to be cleaned up
+    // after, so they're only returned at the end
+    let result: io::Result<()> = async {
+        for room in &rooms {
+            // A room the client has seen nothing in is caught up from the start
+            let after_id = last_ids
+                .as_ref()
+                .map(|last_ids| last_ids.get(room).copied().unwrap_or(0));
+            // Joined under the history lock, so nothing is both replayed and sent live
+            let mut history = history.lock().unwrap();
+            join_room(&mut connections.lock().unwrap(), addr, room, &config);
+            announce_join(addr, &username, room, after_id, &connections, &mut history)?;
+        }
+
+        let reply = |error: &Frame| send_to(addr, error, &connections);
+        let period = Duration::from_secs(config.heartbeat.interval_secs);
+        let mut heartbeat = time::interval_at(time::Instant::now() + period, period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut next_ping = 0;
+        // Pings sent since the client last answered one
+        let mut unanswered = 0;
+        loop {
+            let frame = tokio::select! {
+                frame = next_frame(&mut reader, reply) => frame?,
+                _ = heartbeat.tick() => {
+                    if unanswered >= config.heartbeat.missed_pongs {
+                        println!("{} stopped answering pings", username);
+                        break;
+                    }
+                    send_to(addr, &Frame::Ping { id: next_ping }, &connections)?;
+                    next_ping += 1;
+                    unanswered += 1;
+                    continue;
+                }
+                _ = closed.cancelled() => break,
+                _ = shutdown.cancelled() => {
+                    if let Some(client) = connections.lock().unwrap().clients.get(&addr) {
+                        client.outbox.close_with(&shutdown_notice(&config))?;
+                    }
+                    break;
+                }
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+
+            // Heartbeats skip the rate limit (their bytes count towards the next
+            // message's), so a throttled client still answers pings and isn't
+            // taken for dead
+            match frame {
+                Frame::Ping { id } => {
+                    send_to(addr, &Frame::Pong { id }, &connections)?;
+                    continue;
+                }
+                Frame::Pong { .. } => {
+                    unanswered = 0;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let allowed =
+                rate_limiter
+                    .lock()
+                    .unwrap()
+                    .check(addr.ip(), &mut limits, reader.bytes_read());
+            if !allowed {
+                let keep = handle_flood(
+                    addr,
+                    &username,
+                    &mut limits,
+                    &connections,
+                    &moderation,
+                    &config.rate_limit,
+                )?;
+                if keep {
+                    if let Frame::Say {
+                        client_id: Some(client_id),
+                        ..
+                    }
+                    | Frame::DirectMessage {
+                        client_id: Some(client_id),
+                        ..
+                    } = frame
+                    {
+                        let message = "You are sending messages too fast.".to_string();
+                        reject(addr, Some(client_id), message, &connections)?;
+                    }
+                    continue;
+                }
+                break;
+            }
+
+            match frame {
+                Frame::Say {
+                    room,
+                    text,
+                    client_id,
+                    action,
+                } => {
+                    let room = normalize_room_name(&room).unwrap_or(room);
+                    let is_member = connections
+                        .lock()
+                        .unwrap()
+                        .clients
+                        .get(&addr)
+                        .is_some_and(|client| client.rooms.contains_key(&room));
+                    if !is_member {
+                        let message = format!("You are not in {}.", room);
+                        reject(addr, client_id, message, &connections)?;
+                        continue;
+                    }
+                    if let Err(message) = moderation.lock().unwrap().check_mute(&username) {
+                        reject(addr, client_id, message, &connections)?;
+                        continue;
+                    }
+
+                    if action {
+                        println!("[{}] * {} {}", room, username, text);
+                    } else {
+                        println!("[{}] {}: {}", room, username, text);
+                    }
+                    // Queued for the room before the history lock is released, so
+                    // each room's members get its messages in ID order. Rooms are
+                    // delivered independently, which is why clients resume from
+                    // the last ID they saw in each room.
+                    let mut history = history.lock().unwrap();
+                    let message = history.record(&room, &username, &text, action, client_id);
+
+                    let chat = |client_id| Frame::Chat {
+                        id: message.id,
+                        timestamp: message.timestamp,
+                        room: room.clone(),
+                        author: message.author.clone(),
+                        text: message.text.clone(),
+                        client_id,
+                        action,
+                    };
+                    // A client that numbers its messages gets them back, so it can
+                    // show them where everyone else sees them
+                    match client_id {
+                        Some(client_id) => broadcast_with_echo(
+                            &chat(None),
+                            &chat(Some(client_id)),
+                            &room,
+                            addr,
+                            &connections,
+                        )?,
+                        None => broadcast_message(&chat(None), &room, addr, &connections, false)?,
+                    }
+                    drop(history);
+                }
+                frame @ Frame::DirectMessage { client_id, .. } => {
+                    if let Err(message) = moderation.lock().unwrap().check_mute(&username) {
+                        reject(addr, client_id, message, &connections)?;
+                        continue;
+                    }
+                    send_direct(addr, &username, frame, &connections, &history)?
+                }
+                Frame::Nick {
+                    username: requested,
+                } => {
+                    let renamed = rename(
+                        addr,
+                        &requested,
+                        &connections,
+                        &accounts,
+                        &moderation,
+                        &sessions,
+                        &config,
+                    );
+                    let (new_username, rooms) = match renamed {
+                        Ok(renamed) => renamed,
+                        Err(message) => {
+                            send_to(addr, &Frame::Error { message }, &connections)?;
+                            continue;
+                        }
+                    };
+                    println!("{} is now known as {}", username, new_username);
+                    let old_username = mem::replace(&mut username, new_username);
+                    let renamed = Frame::Renamed {
+                        username: username.clone(),
+                    };
+                    send_to(addr, &renamed, &connections)?;
+                    let text = format!("{} is now known as {}", old_username, username);
+                    for room in rooms {
+                        let mut history = history.lock().unwrap();
+                        let system = Frame::System {
+                            id: history.next_id(),
+                            timestamp: now_millis(),
+                            room: room.clone(),
+                            text: text.clone(),
+                        };
+                        broadcast_message(&system, &room, addr, &connections, true)?;
+                        drop(history);
+                        broadcast_user_list(&room, &connections)?;
+                    }
+                }
+                Frame::ListUsers { room } => handle_user_list_request(addr, &room, &connections)?,
+                Frame::ListRooms => handle_room_list_request(addr, &connections, &config)?,
+                frame @ (Frame::CreateRoom { .. }
+                | Frame::JoinRoom { .. }
+                | Frame::LeaveRoom { .. }) => {
+                    handle_room_request(addr, frame, &connections, &history, &config)?
+                }
+                Frame::Oper { password } => task::block_in_place(|| {
+                    handle_oper_request(addr, &password, &connections, &config)
+                })?,
+                frame @ (Frame::Kick { .. }
+                | Frame::Ban { .. }
+                | Frame::Unban { .. }
+                | Frame::Mute { .. }
+                | Frame::Unmute { .. }) => task::block_in_place(|| {
+                    // Bans are saved to disk before the operator is answered
+                    handle_moderation_request(
+                        addr,
+                        &username,
+                        frame,
+                        &connections,
+                        &moderation,
+                        &history,
+                    )
+                })?,
+                _ => {
+                    let error = Frame::Error {
+                        message: "Unexpected frame from client.".to_string(),
+                    };
+                    send_to(addr, &error, &connections)?;
+                }
+            }
+
+            // Frames that arrived together are decoded without waiting on the socket,
+            // so give the writers a turn before a burst overflows the room channels
+            task::yield_now().await;
+        }
+        Ok(())
+    }
+    .await;
+
+    // Remove client first, then tell each of its rooms it left. It's already gone
+    // if a new connection resumed its session.
     let mut conn_map = connections.lock().unwrap();
-    conn_map.insert(
-        addr,
-        Client {
-            stream: stream.try_clone()?,
+    let (rooms, resume_token) = conn_map
+        .remove(&addr)
+        .map(|(client, rooms)| (rooms, client.resume_token))
+        .unwrap_or_default();
+    // Held before the lock is released, so the name is never free in between
+    if let Some(token) = resume_token.filter(|_| !shutdown.is_cancelled()) {
+        let session = Session {
             username: username.clone(),
-        },
-    );
-    let total = conn_map.len();
+            rooms: rooms.clone(),
+        };
+        sessions.lock().unwrap().hold(token, session);
+    }
+    let total = conn_map.clients.len();
     drop(conn_map);
-    println!("{} connected from {} (Total: {})", username, addr, total);
+    println!("{} disconnected from {} (Total: {})", username, addr, total);
 
-    let join_message = format!("{} has joined the chat\n", username);
-    broadcast_message(join_message.as_bytes(), addr, &connections, false)?; // Don't send to sender
+    // Everyone is leaving when the server shuts down, so don't announce it
+    if !shutdown.is_cancelled() {
+        for room in rooms {
+            announce_leave(&username, &room, &connections, &mut history.lock().unwrap())?;
+        }
+    }
 
-    // Broadcast updated user list to all clients (including the new one)
-    broadcast_user_list(&connections)?;
+    result
+}
 
-    let mut buf = [0u8; 4096];
+// Gives back a connection's place in the server-wide and per-IP connection
+// counts when its handler finishes
+struct ConnectionSlot {
+    open_connections: Arc<AtomicUsize>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+        self.rate_limiter.lock().unwrap().close(self.ip);
+    }
+}
+
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        // Keep IPv6 listeners from also claiming the IPv4 port, so "0.0.0.0" and "::"
+        // can both be configured
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn accept_connections(listener: TcpListener, shared: Shared) {
     loop {
-        let n = stream.read(&mut buf)?;
-        if n == 0 {
-            break;
+        let (stream, peer) = tokio::select! {
+            connection = listener.accept() => match connection {
+                Ok(connection) => connection,
+                Err(err) => {
+                    eprintln!("Accept error: {}", err);
+                    continue;
+                }
+            },
+            _ = shared.shutdown.cancelled() => break,
+        };
+
+        // Plaintext clients are told why they're turned away; a TLS client
+        // couldn't read a plaintext error, so it's just disconnected. The socket
+        // is brand new, so the error fits in its buffer without waiting.
+        let refuse = |message: &str| {
+            if shared.tls.is_none() {
+                let error = Frame::Error {
+                    message: message.to_string(),
+                };
+                if let Ok(bytes) = codec::encode_frame(&error) {
+                    let _ = stream.try_write(&bytes);
+                }
+            }
+        };
+
+        if shared.moderation.lock().unwrap().is_ip_banned(peer.ip()) {
+            refuse("You are banned from this server.");
+            continue;
         }
 
-        let message = String::from_utf8_lossy(&buf[..n]);
+        if !shared.rate_limiter.lock().unwrap().open(peer.ip()) {
+            refuse("Too many connections from your address.");
+            continue;
+        }
+        let slot = ConnectionSlot {
+            open_connections: Arc::clone(&shared.open_connections),
+            rate_limiter: Arc::clone(&shared.rate_limiter),
+            ip: peer.ip(),
+        };
 
-        // Check for special commands
-        if message.trim() == "GET_USERS" {
-            handle_user_list_request(stream.try_clone()?, &connections)?;
+        if shared.open_connections.fetch_add(1, Ordering::SeqCst) >= shared.config.max_connections {
+            // Dropping the slot gives both counts back
+            refuse("Server is full. Please try again later.");
+            drop(slot);
             continue;
         }
 
-        let formatted_msg = format!("{}: {}", username, message);
+        let shared = shared.clone();
+        shared.tasks.clone().spawn(async move {
+            let _slot = slot;
+            let result = match shared.tls.clone() {
+                Some(tls) => match tls::accept(stream, tls).await {
+                    Ok(stream) => handle_client(stream, peer, shared).await,
+                    Err(err) => {
+                        eprintln!("TLS handshake failed: {}", err);
+                        return;
+                    }
+                },
+                None => handle_client(stream, peer, shared).await,
+            };
+            if let Err(err) = result {
+                eprintln!("Client handler error: {}", err);
+            }
+        });
+    }
+}
+
+// Waits for Ctrl+C, or SIGTERM on Unix
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args = Args::parse();
+    if args.hash_password {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        match accounts::hash_password(password) {
+            Ok(hash) => println!("{}", hash),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
-        std::io::stdout().write_all(formatted_msg.as_bytes())?;
-        std::io::stdout().flush()?;
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(1);
+        }
+    };
 
-        broadcast_message(formatted_msg.as_bytes(), addr, &connections, false)?;
+    if args.check_config {
+        println!("Configuration OK");
+        println!("{:#?}", config);
+        return Ok(());
     }
 
-    let leave_message = format!("{} has left the chat\n", username);
-    broadcast_message(leave_message.as_bytes(), addr, &connections, false)?;
+    let history = match History::open(&config.history) {
+        Ok(history) => Arc::new(Mutex::new(history)),
+        Err(err) => {
+            eprintln!("Failed to open message history: {}", err);
+            process::exit(1);
+        }
+    };
 
-    // Remove client first, then broadcast updated user list
-    let mut conn_map = connections.lock().unwrap();
-    conn_map.shift_remove(&addr);
-    let total = conn_map.len();
-    drop(conn_map);
-    println!("{} disconnected from {} (Total: {})", username, addr, total);
+    let accounts = match Accounts::open(&config.accounts) {
+        Ok(accounts) => Arc::new(Mutex::new(accounts)),
+        Err(err) => {
+            eprintln!("Failed to open accounts: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let moderation = match Moderation::open(config.moderation.bans_path.clone()) {
+        Ok(moderation) => Arc::new(Mutex::new(moderation)),
+        Err(err) => {
+            eprintln!("Failed to load bans: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let tls = if config.tls.enabled {
+        match tls::load_server_config(&config.tls) {
+            Ok(tls) => Some(tls),
+            Err(err) => {
+                eprintln!("Failed to set up TLS: {}", err);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let shared = Shared {
+        connections: Arc::new(Mutex::new(Connections::default())),
+        history,
+        accounts,
+        moderation,
+        rate_limiter: Arc::new(Mutex::new(RateLimiter::new(&config.rate_limit))),
+        sessions: Arc::new(Mutex::new(Sessions::new(&config.resume))),
+        config: Arc::new(config),
+        open_connections: Arc::new(AtomicUsize::new(0)),
+        tls,
+        shutdown: CancellationToken::new(),
+        tasks: TaskTracker::new(),
+    };
+
+    let mut listeners = Vec::new();
+    for ip in &shared.config.bind {
+        let address = SocketAddr::new(*ip, shared.config.port);
+        println!("Binding to port {}", address);
+        listeners.push(bind(address)?);
+    }
+
+    let accept_loops = TaskTracker::new();
+    for listener in listeners {
+        accept_loops.spawn(accept_connections(listener, shared.clone()));
+    }
+    accept_loops.close();
+
+    // Registering the handlers only fails at startup, before any client could
+    // have sent anything worth draining
+    if let Err(err) = shutdown_signal().await {
+        eprintln!("Failed to listen for shutdown signals: {}", err);
+        return Err(err);
+    }
+
+    // Stop accepting, then let every connection wind down: handlers stop
+    // reading and queue a shutdown notice, and writers send what's queued
+    // before closing
+    let drain_timeout = shared.config.shutdown.drain_timeout_secs;
+    println!(
+        "Shutting down, giving clients up to {}s to receive queued messages",
+        drain_timeout
+    );
+    shared.shutdown.cancel();
+    accept_loops.wait().await;
+    shared.tasks.close();
+    if tokio::time::timeout(Duration::from_secs(drain_timeout), shared.tasks.wait())
+        .await
+        .is_err()
+    {
+        eprintln!("Some connections did not close in time");
+    }
 
-    broadcast_user_list(&connections)?;
+    if let Err(err) = task::block_in_place(|| shared.history.lock().unwrap().flush()) {
+        eprintln!("Failed to flush message history: {}", err);
+    }
 
     Ok(())
 }
 
-fn main() -> io::Result<()> {
-    let address = "0.0.0.0:2133";
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BucketLimits;
+    use crate::test_support::{self, connect, log_in, memory_history, next, send, shared};
+    use tokio_util::task::TaskTracker;
 
-    println!("Binding to port {}", address);
+    fn say(text: &str) -> Frame {
+        Frame::Say {
+            room: "#lobby".to_string(),
+            text: text.to_string(),
+            client_id: None,
+            action: false,
+        }
+    }
 
-    let listener = TcpListener::bind(address)?;
-    let connections: Arc<Mutex<IndexMap<SocketAddr, Client>>> =
-        Arc::new(Mutex::new(IndexMap::new()));
+    #[tokio::test(start_paused = true)]
+    async fn a_throttled_client_still_answers_pings() {
+        let mut config = Config::default();
+        config.heartbeat.interval_secs = 1;
+        config.heartbeat.missed_pongs = 2;
+        // Enough for the login and one message
+        config.rate_limit.connection = BucketLimits {
+            messages_per_second: 0.001,
+            message_burst: 2,
+            ..config.rate_limit.connection
+        };
+        config.rate_limit.action = FloodAction::Drop;
+        let shared = shared(config);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let (mut reader, mut writer) = log_in(&shared, addr, "alice").await;
 
-    for connection in listener.incoming() {
-        match connection {
-            Ok(stream) => {
-                let connections_clone = Arc::clone(&connections);
-                thread::spawn(move || {
-                    if let Err(err) = handle_client(stream, connections_clone) {
-                        eprintln!("Client handler error: {}", err);
-                    }
-                });
-            }
-            Err(err) => eprintln!("Accept error: {}", err),
+        send(&mut writer, &say("one")).await;
+        send(&mut writer, &say("two")).await;
+        send(&mut writer, &Frame::Ping { id: 99 }).await;
+        next(&mut reader, |frame| frame == &Frame::Pong { id: 99 }).await;
+        let texts: Vec<String> = shared
+            .history
+            .lock()
+            .unwrap()
+            .replay("#lobby")
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(texts, ["one"]);
+
+        // Twice as many pings as it may miss, all answered while throttled
+        for id in 0..4 {
+            next(&mut reader, |frame| frame == &Frame::Ping { id }).await;
+            send(&mut writer, &Frame::Pong { id }).await;
         }
+        assert!(
+            shared
+                .connections
+                .lock()
+                .unwrap()
+                .clients
+                .contains_key(&addr)
+        );
     }
 
-    Ok(())
+    #[tokio::test(start_paused = true)]
+    async fn clients_that_stop_answering_pings_are_reaped() {
+        let mut config = Config::default();
+        config.heartbeat.interval_secs = 1;
+        config.heartbeat.missed_pongs = 2;
+        let shared = shared(config);
+        let alice = "127.0.0.1:5000".parse().unwrap();
+        let bob = "127.0.0.1:5001".parse().unwrap();
+        let (mut alice_reader, mut alice_writer) = log_in(&shared, alice, "alice").await;
+        let (mut bob_reader, _bob_writer) = log_in(&shared, bob, "bob").await;
+
+        // Bob is sent the pings he may miss, then disconnected
+        let bob_pings = async {
+            let mut pings = Vec::new();
+            while let Some(frame) = bob_reader.read_frame().await.unwrap() {
+                if let Frame::Ping { id } = frame {
+                    pings.push(id);
+                }
+            }
+            pings
+        };
+        // Alice answers hers, so she stays to hear that he left
+        let alice_hears = async {
+            loop {
+                match alice_reader.read_frame().await.unwrap().unwrap() {
+                    Frame::Ping { id } => send(&mut alice_writer, &Frame::Pong { id }).await,
+                    Frame::Leave { username, .. } => return username,
+                    _ => {}
+                }
+            }
+        };
+        let (pings, left) = tokio::join!(bob_pings, alice_hears);
+        assert_eq!(pings, [0, 1]);
+        assert_eq!(left, "bob");
+        let conn_map = shared.connections.lock().unwrap();
+        assert!(conn_map.clients.contains_key(&alice));
+        assert!(!conn_map.clients.contains_key(&bob));
+    }
+
+    #[tokio::test]
+    async fn direct_messages_reach_only_their_recipient() {
+        let connections = Arc::new(Mutex::new(Connections::default()));
+        let history = memory_history();
+        let tasks = TaskTracker::new();
+        let alice = "127.0.0.1:5000".parse().unwrap();
+        let bob = "127.0.0.1:5001".parse().unwrap();
+        let carol = "127.0.0.1:5002".parse().unwrap();
+        let alice_reader = connect(&connections, alice, "alice", &[], &tasks);
+        let bob_reader = connect(&connections, bob, "bob", &[], &tasks);
+        let carol_reader = connect(&connections, carol, "carol", &[], &tasks);
+        let dm = |to: &str, text: &str, client_id| Frame::DirectMessage {
+            to: to.to_string(),
+            text: text.to_string(),
+            client_id,
+            action: false,
+        };
+
+        // Names match whatever their case
+        send_direct(
+            alice,
+            "alice",
+            dm("BOB", "hi", None),
+            &connections,
+            &history,
+        )
+        .unwrap();
+        send_direct(
+            alice,
+            "alice",
+            dm("bob", "again", Some(1)),
+            &connections,
+            &history,
+        )
+        .unwrap();
+        send_direct(
+            alice,
+            "alice",
+            dm("dave", "hello?", Some(2)),
+            &connections,
+            &history,
+        )
+        .unwrap();
+
+        let texts: Vec<_> = test_support::received(&connections, bob, bob_reader)
+            .await
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::DirectChat { from, text, .. } => format!("{}: {}", from, text),
+                frame => panic!("unexpected frame {:?}", frame),
+            })
+            .collect();
+        assert_eq!(texts, ["alice: hi", "alice: again"]);
+        assert!(
+            test_support::received(&connections, carol, carol_reader)
+                .await
+                .is_empty()
+        );
+
+        let replies = test_support::received(&connections, alice, alice_reader).await;
+        assert!(matches!(replies[0], Frame::Ack { client_id: 1, .. }));
+        assert_eq!(
+            replies[1..],
+            [Frame::Rejected {
+                client_id: 2,
+                message: "dave is not online.".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn shutting_down_tells_clients_and_waits_for_them() {
+        let mut config = Config::default();
+        config.shutdown.reason = Some("Upgrading.".to_string());
+        config.shutdown.restart_eta_secs = Some(30);
+        let shared = shared(config);
+        let mut readers = Vec::new();
+        for (port, username) in [(5000, "alice"), (5001, "bob")] {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            readers.push(log_in(&shared, addr, username).await);
+        }
+
+        shared.shutdown.cancel();
+        for (mut reader, _writer) in readers {
+            let mut last = None;
+            while let Some(frame) = reader.read_frame().await.unwrap() {
+                assert!(!matches!(frame, Frame::Leave { .. }));
+                last = Some(frame);
+            }
+            let notice = Frame::ShuttingDown {
+                reason: Some("Upgrading.".to_string()),
+                restart_in_secs: Some(30),
+            };
+            assert_eq!(last, Some(notice));
+        }
+
+        shared.tasks.close();
+        let drained = time::timeout(Duration::from_secs(5), shared.tasks.wait()).await;
+        assert!(drained.is_ok());
+        assert!(shared.connections.lock().unwrap().clients.is_empty());
+    }
 }
@@ -0,0 +1,693 @@
+use crate::{
+    Client, Connections,
+    accounts::verify_password,
+    broadcast_message,
+    config::Config,
+    history::{History, now_millis},
+    send_to,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tcptalk_protocol::Frame;
+
+// What a ban applies to. IP addresses and CIDR networks are banned by address,
+// anything else is a username.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum BanTarget {
+    Username(String),
+    Network(IpNet),
+}
+
+impl From<String> for BanTarget {
+    fn from(target: String) -> Self {
+        if let Ok(network) = target.parse::<IpNet>() {
+            BanTarget::Network(network)
+        } else if let Ok(ip) = target.parse::<IpAddr>() {
+            BanTarget::Network(IpNet::from(ip))
+        } else {
+            BanTarget::Username(target)
+        }
+    }
+}
+
+impl From<BanTarget> for String {
+    fn from(target: BanTarget) -> Self {
+        target.to_string()
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Username(username) => write!(f, "{}", username),
+            // Single addresses read better without the "/32"
+            BanTarget::Network(network) if network.prefix_len() == network.max_prefix_len() => {
+                write!(f, "{}", network.addr())
+            }
+            BanTarget::Network(network) => write!(f, "{}", network),
+        }
+    }
+}
+
+impl BanTarget {
+    fn matches(&self, username: &str, ip: IpAddr) -> bool {
+        match self {
+            BanTarget::Username(banned) => banned.eq_ignore_ascii_case(username),
+            BanTarget::Network(network) => network.contains(&ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Milliseconds since the Unix epoch; permanent if missing
+    pub expires_at: Option<u64>,
+    pub reason: Option<String>,
+    pub banned_by: String,
+}
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+// Bans, saved to disk whenever they change, and mutes, which only last until
+// the server restarts
+pub struct Moderation {
+    bans_path: PathBuf,
+    bans: Vec<Ban>,
+    // Lowercased username -> when the mute ends, or `None` if it doesn't
+    mutes: HashMap<String, Option<u64>>,
+}
+
+impl Moderation {
+    pub fn open(bans_path: PathBuf) -> io::Result<Self> {
+        let bans = match fs::read_to_string(&bans_path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", bans_path.display(), e),
+                )
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            bans_path,
+            bans,
+            mutes: HashMap::new(),
+        })
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        let now = now_millis();
+        self.bans.iter().any(|ban| {
+            ban.is_active(now)
+                && matches!(&ban.target, BanTarget::Network(network) if network.contains(&ip))
+        })
+    }
+
+    pub fn check_login(&self, username: &str, ip: IpAddr) -> Result<(), String> {
+        let now = now_millis();
+        match self
+            .bans
+            .iter()
+            .find(|ban| ban.is_active(now) && ban.target.matches(username, ip))
+        {
+            Some(ban) => Err(format!(
+                "You are banned from this server{}.",
+                remaining(ban.expires_at, now)
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_mute(&self, username: &str) -> Result<(), String> {
+        let now = now_millis();
+        match self.mutes.get(&username.to_lowercase()) {
+            Some(&until) if until.is_none_or(|until| until > now) => {
+                Err(format!("You are muted{}.", remaining(until, now)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Mutes `username` until `until`, or until the server restarts if `None`
+    pub fn mute(&mut self, username: &str, until: Option<u64>) {
+        self.mutes.insert(username.to_lowercase(), until);
+    }
+
+    fn ban(&mut self, ban: Ban) -> io::Result<()> {
+        self.bans.retain(|existing| existing.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    fn unban(&mut self, target: &BanTarget) -> io::Result<bool> {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        let removed = self.bans.len() != before;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    // Writes the active bans to a temporary file first, so a crash can't leave a
+    // half-written ban list behind
+    fn save(&mut self) -> io::Result<()> {
+        let now = now_millis();
+        self.bans.retain(|ban| ban.is_active(now));
+
+        let contents = serde_json::to_vec_pretty(&self.bans)?;
+        let temp_path = self.bans_path.with_extension("tmp");
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.bans_path)
+    }
+}
+
+// Longest a ban or mute can last without being permanent, about ten years
+pub const MAX_DURATION_SECS: u64 = 3650 * 24 * 60 * 60;
+
+// When a ban or mute lasting `secs` from now ends, in milliseconds since the
+// Unix epoch
+pub fn expires_at(secs: u64) -> Result<u64, String> {
+    secs.checked_mul(1000)
+        .and_then(|ms| now_millis().checked_add(ms))
+        .filter(|_| secs <= MAX_DURATION_SECS)
+        .ok_or_else(|| {
+            format!(
+                "Bans and mutes last at most {}d; leave out the duration to make one permanent.",
+                MAX_DURATION_SECS / 86400
+            )
+        })
+}
+
+// " for another 5m" for something that ends at `until`, or "" if it doesn't end
+fn remaining(until: Option<u64>, now: u64) -> String {
+    match until {
+        Some(until) => format!(
+            " for another {}",
+            format_duration(until.saturating_sub(now) / 1000)
+        ),
+        None => String::new(),
+    }
+}
+
+// "1d 2h", "5m", "30s": the two largest units
+fn format_duration(secs: u64) -> String {
+    let units = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(secs, |left, &(unit, size)| {
+            let count = *left / size;
+            *left %= size;
+            Some((count, unit))
+        })
+        .filter(|(count, _)| *count > 0)
+        .take(2)
+        .map(|(count, unit)| format!("{}{}", count, unit))
+        .collect();
+
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+// "alice was banned by bob for 1h (spamming)"
+fn describe(
+    subject: &str,
+    action: &str,
+    operator: &str,
+    secs: Option<u64>,
+    reason: &Option<String>,
+) -> String {
+    let mut text = format!("{} was {} by {}", subject, action, operator);
+    if let Some(secs) = secs {
+        text.push_str(&format!(" for {}", format_duration(secs)));
+    }
+    if let Some(reason) = reason {
+        text.push_str(&format!(" ({})", reason));
+    }
+    text
+}
+
+// Announces `text` as a System message in every room `target` is in. A target
+// that isn't online, like an address nobody is connected from, has no rooms, so
+// it goes to the rooms of the operator at `addr` instead.
+fn announce(
+    addr: SocketAddr,
+    target: &str,
+    text: &str,
+    connections: &Arc<Mutex<Connections>>,
+    history: &Arc<Mutex<History>>,
+) -> io::Result<()> {
+    let rooms: Vec<String> = {
+        let conn_map = connections.lock().unwrap();
+        conn_map
+            .clients
+            .values()
+            .find(|client| client.username.eq_ignore_ascii_case(target))
+            .or_else(|| conn_map.clients.get(&addr))
+            .map(|client| client.rooms.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+
+    for room in rooms {
+        // Held until the message is queued, so the room gets IDs in order
+        let mut history = history.lock().unwrap();
+        let system = Frame::System {
+            id: history.next_id(),
+            timestamp: now_millis(),
+            room: room.clone(),
+            text: text.to_string(),
+        };
+        broadcast_message(
+            &system,
+            &room,
+            "0.0.0.0:0".parse().unwrap(),
+            connections,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+// Tells the clients matching `matches` why they're being removed, then closes
+// their connections. Their handler tasks clean up and announce the leave.
+fn disconnect(
+    matches: impl Fn(&SocketAddr, &Client) -> bool,
+    notice: &str,
+    connections: &Arc<Mutex<Connections>>,
+) {
+    let mut conn_map = connections.lock().unwrap();
+    for (addr, client) in conn_map.clients.iter_mut() {
+        if matches(addr, client) {
+            client.disconnect(notice);
+        }
+    }
+}
+
+fn online_username(username: &str, connections: &Arc<Mutex<Connections>>) -> Option<String> {
+    connections
+        .lock()
+        .unwrap()
+        .clients
+        .values()
+        .find(|client| client.username.eq_ignore_ascii_case(username))
+        .map(|client| client.username.clone())
+}
+
+pub fn handle_oper_request(
+    addr: SocketAddr,
+    password: &str,
+    connections: &Arc<Mutex<Connections>>,
+    config: &Config,
+) -> io::Result<()> {
+    let reply = match &config.moderation.operator_password_hash {
+        Some(hash) if verify_password(password, hash) => {
+            if let Some(client) = connections.lock().unwrap().clients.get_mut(&addr) {
+                client.operator = true;
+            }
+            Frame::Notice {
+                text: "You are now an operator.".to_string(),
+            }
+        }
+        Some(_) => Frame::Error {
+            message: "Wrong operator password.".to_string(),
+        },
+        None => Frame::Error {
+            message: "This server has no operator password.".to_string(),
+        },
+    };
+    send_to(addr, &reply, connections)
+}
+
+pub fn handle_moderation_request(
+    addr: SocketAddr,
+    operator: &str,
+    frame: Frame,
+    connections: &Arc<Mutex<Connections>>,
+    moderation: &Arc<Mutex<Moderation>>,
+    history: &Arc<Mutex<History>>,
+) -> io::Result<()> {
+    let is_operator = connections
+        .lock()
+        .unwrap()
+        .clients
+        .get(&addr)
+        .is_some_and(|client| client.operator);
+    if !is_operator {
+        let error = Frame::Error {
+            message: "Only operators can do that.".to_string(),
+        };
+        return send_to(addr, &error, connections);
+    }
+
+    let result = match frame {
+        Frame::Kick { username, reason } => {
+            kick(addr, operator, &username, reason, connections, history)
+        }
+        Frame::Ban {
+            target,
+            duration_secs,
+            reason,
+        } => duration_secs
+            .map(expires_at)
+            .transpose()
+            .and_then(|expires_at| {
+                let target = BanTarget::from(target);
+                let banned_by = operator.to_string();
+                ban(
+                    addr,
+                    Ban {
+                        target,
+                        expires_at,
+                        reason,
+                        banned_by,
+                    },
+                    duration_secs,
+                    connections,
+                    moderation,
+                    history,
+                )
+            }),
+        Frame::Unban { target } => {
+            let target = BanTarget::from(target);
+            let unbanned = moderation.lock().unwrap().unban(&target);
+            match unbanned {
+                Ok(true) => {
+                    let target = target.to_string();
+                    let text = describe(&target, "unbanned", operator, None, &None);
+                    announce(addr, &target, &text, connections, history)?;
+                    Ok(format!("Unbanned {}.", target))
+                }
+                Ok(false) => Err(format!("{} is not banned.", target)),
+                Err(err) => Err(format!("Failed to save bans: {}", err)),
+            }
+        }
+        Frame::Mute {
+            username,
+            duration_secs,
+        } => match duration_secs.map(expires_at).transpose() {
+            Ok(until) => {
+                moderation.lock().unwrap().mute(&username, until);
+                let text = describe(&username, "muted", operator, duration_secs, &None);
+                announce(addr, &username, &text, connections, history)?;
+                Ok(format!("Muted {}.", username))
+            }
+            Err(message) => Err(message),
+        },
+        Frame::Unmute { username } => {
+            let removed = moderation
+                .lock()
+                .unwrap()
+                .mutes
+                .remove(&username.to_lowercase())
+                .is_some();
+            if removed {
+                let text = describe(&username, "unmuted", operator, None, &None);
+                announce(addr, &username, &text, connections, history)?;
+                Ok(format!("Unmuted {}.", username))
+            } else {
+                Err(format!("{} is not muted.", username))
+            }
+        }
+        _ => return Ok(()),
+    };
+
+    let reply = match result {
+        Ok(text) => Frame::Notice { text },
+        Err(message) => Frame::Error { message },
+    };
+    send_to(addr, &reply, connections)
+}
+
+fn kick(
+    addr: SocketAddr,
+    operator: &str,
+    username: &str,
+    reason: Option<String>,
+    connections: &Arc<Mutex<Connections>>,
+    history: &Arc<Mutex<History>>,
+) -> Result<String, String> {
+    let Some(username) = online_username(username, connections) else {
+        return Err(format!("{} is not online.", username));
+    };
+    if username == operator {
+        return Err("You can't kick yourself.".to_string());
+    }
+
+    let text = describe(&username, "kicked", operator, None, &reason);
+    announce(addr, &username, &text, connections, history).map_err(|e| e.to_string())?;
+    let notice = match &reason {
+        Some(reason) => format!("You were kicked by {}: {}", operator, reason),
+        None => format!("You were kicked by {}.", operator),
+    };
+    disconnect(
+        |_, client| client.username == username,
+        &notice,
+        connections,
+    );
+    Ok(format!("Kicked {}.", username))
+}
+
+fn ban(
+    addr: SocketAddr,
+    ban: Ban,
+    duration_secs: Option<u64>,
+    connections: &Arc<Mutex<Connections>>,
+    moderation: &Arc<Mutex<Moderation>>,
+    history: &Arc<Mutex<History>>,
+) -> Result<String, String> {
+    let target = ban.target.clone();
+    let operator = ban.banned_by.clone();
+    let reason = ban.reason.clone();
+    let banned_users: Vec<String> = connections
+        .lock()
+        .unwrap()
+        .clients
+        .iter()
+        .filter(|(addr, client)| target.matches(&client.username, addr.ip()))
+        .map(|(_, client)| client.username.clone())
+        .collect();
+    if banned_users.contains(&operator) {
+        return Err("You can't ban yourself.".to_string());
+    }
+
+    moderation
+        .lock()
+        .unwrap()
+        .ban(ban)
+        .map_err(|e| format!("Failed to save bans: {}", e))?;
+
+    // Nobody it applies to is online, but the ban is still worth announcing
+    let subjects = if banned_users.is_empty() {
+        vec![target.to_string()]
+    } else {
+        banned_users
+    };
+    for subject in &subjects {
+        let text = describe(subject, "banned", &operator, duration_secs, &reason);
+        announce(addr, subject, &text, connections, history).map_err(|e| e.to_string())?;
+    }
+    disconnect(
+        |addr, client| target.matches(&client.username, addr.ip()),
+        "You have been banned from this server.",
+        connections,
+    );
+
+    Ok(match duration_secs {
+        Some(secs) => format!("Banned {} for {}.", target, format_duration(secs)),
+        None => format!("Banned {}.", target),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reader::FrameReader,
+        test_support::{self, connect, memory_history, temp_dir},
+    };
+    use tokio::io::DuplexStream;
+    use tokio_util::task::TaskTracker;
+
+    fn ban(target: &str, expires_at: Option<u64>) -> Ban {
+        Ban {
+            target: BanTarget::from(target.to_string()),
+            expires_at,
+            reason: Some("spam".to_string()),
+            banned_by: "op".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_ips_networks_and_usernames() {
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        let target = BanTarget::from("10.0.0.7".to_string());
+        assert_eq!(target, BanTarget::Network("10.0.0.7/32".parse().unwrap()));
+        assert_eq!(target.to_string(), "10.0.0.7");
+        assert!(target.matches("anyone", ip));
+
+        let network = BanTarget::from("10.0.0.0/24".to_string());
+        assert_eq!(network.to_string(), "10.0.0.0/24");
+        assert!(network.matches("anyone", ip));
+        assert!(!network.matches("anyone", "10.0.1.7".parse().unwrap()));
+        assert!(
+            BanTarget::from("2001:db8::/32".to_string())
+                .matches("", "2001:db8::1".parse().unwrap())
+        );
+
+        let username = BanTarget::from("Mallory".to_string());
+        assert_eq!(username, BanTarget::Username("Mallory".to_string()));
+        assert!(username.matches("mallory", ip));
+        assert!(!username.matches("alice", ip));
+    }
+
+    #[test]
+    fn expired_bans_stop_applying_and_are_pruned_on_save() {
+        let dir = temp_dir("bans-expiry");
+        let mut moderation = Moderation::open(dir.join("bans.json")).unwrap();
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        moderation.bans.push(ban("mallory", Some(now_millis() - 1)));
+        assert!(moderation.check_login("mallory", ip).is_ok());
+
+        moderation
+            .ban(ban("eve", Some(now_millis() + 60_000)))
+            .unwrap();
+        assert_eq!(moderation.bans.len(), 1);
+        assert!(moderation.check_login("eve", ip).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bans_survive_a_restart() {
+        let dir = temp_dir("bans-reload");
+        let path = dir.join("bans.json");
+        let mut moderation = Moderation::open(path.clone()).unwrap();
+        moderation.ban(ban("mallory", None)).unwrap();
+        moderation
+            .ban(ban("10.0.0.0/24", Some(now_millis() + 60_000)))
+            .unwrap();
+        moderation.ban(ban("eve", None)).unwrap();
+        assert!(
+            moderation
+                .unban(&BanTarget::from("eve".to_string()))
+                .unwrap()
+        );
+
+        let moderation = Moderation::open(path).unwrap();
+        let targets: Vec<String> = moderation
+            .bans
+            .iter()
+            .map(|ban| ban.target.to_string())
+            .collect();
+        assert_eq!(targets, ["mallory", "10.0.0.0/24"]);
+        assert!(moderation.is_ip_banned("10.0.0.200".parse().unwrap()));
+        assert!(!moderation.is_ip_banned("10.0.1.1".parse().unwrap()));
+        assert_eq!(moderation.bans[0].reason.as_deref(), Some("spam"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn durations_past_the_maximum_are_rejected() {
+        assert!(expires_at(60).is_ok_and(|until| until > now_millis()));
+        assert!(expires_at(MAX_DURATION_SECS + 1).is_err());
+        assert!(expires_at(u64::MAX).is_err());
+    }
+
+    // Closes the connection at `addr` and returns the System messages it got
+    async fn announcements(
+        connections: &Arc<Mutex<Connections>>,
+        addr: SocketAddr,
+        reader: FrameReader<DuplexStream>,
+    ) -> Vec<String> {
+        test_support::received(connections, addr, reader)
+            .await
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::System { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn actions_on_targets_who_are_not_online_are_announced_to_the_operator() {
+        let dir = temp_dir("bans-announce");
+        let moderation = Arc::new(Mutex::new(Moderation::open(dir.join("bans.json")).unwrap()));
+        let history = memory_history();
+        let connections = Arc::new(Mutex::new(Connections::default()));
+        let tasks = TaskTracker::new();
+        let op: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let bob: SocketAddr = "127.0.0.2:4000".parse().unwrap();
+        let op_reader = connect(&connections, op, "op", &["#lobby"], &tasks);
+        let bob_reader = connect(&connections, bob, "bob", &["#ops"], &tasks);
+        connections.lock().unwrap().clients[&op].operator = true;
+
+        let requests = [
+            Frame::Ban {
+                target: "mallory".to_string(),
+                duration_secs: Some(3600),
+                reason: None,
+            },
+            Frame::Ban {
+                target: "10.9.0.0/16".to_string(),
+                duration_secs: None,
+                reason: Some("spam".to_string()),
+            },
+            Frame::Unban {
+                target: "mallory".to_string(),
+            },
+            Frame::Mute {
+                username: "carol".to_string(),
+                duration_secs: None,
+            },
+            Frame::Unmute {
+                username: "carol".to_string(),
+            },
+            // Online targets hear about it in their own rooms
+            Frame::Mute {
+                username: "bob".to_string(),
+                duration_secs: None,
+            },
+        ];
+        for request in requests {
+            handle_moderation_request(op, "op", request, &connections, &moderation, &history)
+                .unwrap();
+        }
+
+        assert_eq!(
+            announcements(&connections, op, op_reader).await,
+            [
+                "mallory was banned by op for 1h",
+                "10.9.0.0/16 was banned by op (spam)",
+                "mallory was unbanned by op",
+                "carol was muted by op",
+                "carol was unmuted by op",
+            ]
+        );
+        assert_eq!(
+            announcements(&connections, bob, bob_reader).await,
+            ["bob was muted by op"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+use crate::config::{OutboundConfig, SlowConsumerPolicy};
+use std::{
+    collections::VecDeque,
+    future, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::Duration,
+};
+use tcptalk_protocol::{Frame, codec};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{Notify, broadcast},
+    task, time,
+};
+use tokio_stream::{
+    Stream, StreamExt, StreamMap,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+// A frame sent to a room's broadcast channel
+#[derive(Clone)]
+pub struct RoomFrame {
+    pub bytes: Arc<[u8]>,
+    // The member it shouldn't be delivered to, usually whoever caused it
+    pub exclude: Option<SocketAddr>,
+    // What `exclude` gets instead, e.g. a chat message echoed back to its sender
+    pub echo: Option<Arc<[u8]>>,
+}
+
+impl RoomFrame {
+    // The bytes to write to the member at `addr`, if any
+    fn bytes_for(self, addr: SocketAddr) -> Option<Arc<[u8]>> {
+        if self.exclude == Some(addr) {
+            self.echo
+        } else {
+            Some(self.bytes)
+        }
+    }
+}
+
+// What became of a frame given to an `Outbox`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sent {
+    Queued,
+    // Left out because the client has fallen behind, under the "disconnect"
+    // policy while it still has time to catch up
+    Dropped,
+    // The connection is closed, e.g. because the client fell behind for too long
+    Closed,
+}
+
+enum Outgoing {
+    Frame(Arc<[u8]>),
+    // Room membership changes travel through the queue too, so they take effect
+    // in order with the frames around them
+    Subscribe(String, broadcast::Receiver<RoomFrame>),
+    Unsubscribe(String),
+}
+
+struct Queue {
+    items: VecDeque<Outgoing>,
+    // Number of `Outgoing::Frame`s in `items`
+    frames: usize,
+    // Set when nothing more will be queued; the writer sends what's left and
+    // then closes the connection
+    closed: bool,
+    // Written last, after frames already sent to the client's rooms, when the
+    // connection is closed by `close_with`
+    farewell: Option<Arc<[u8]>>,
+}
+
+struct Inner {
+    queue: Mutex<Queue>,
+    ready: Notify,
+    // Cancelled once the writer has finished and the connection is closed
+    done: CancellationToken,
+}
+
+// A client's outbound frames: its own queue, plus the rooms it's subscribed to.
+// A task of its own writes them to the socket, so a slow reader only ever
+// holds itself up. Under the "disconnect" policy the writer also decides when
+// the client has been backed up for too long, since nothing else may be sent
+// to it that would notice.
+pub struct Outbox {
+    inner: Arc<Inner>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+impl Outbox {
+    // Starts the writer task for the client at `addr`
+    pub fn spawn<W>(
+        writer: W,
+        addr: SocketAddr,
+        config: &OutboundConfig,
+        tasks: &TaskTracker,
+    ) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let inner = Arc::new(Inner {
+            queue: Mutex::new(Queue {
+                items: VecDeque::new(),
+                frames: 0,
+                closed: false,
+                farewell: None,
+            }),
+            ready: Notify::new(),
+            done: CancellationToken::new(),
+        });
+        let outbox = Self {
+            inner: Arc::clone(&inner),
+            capacity: config.queue_len,
+            policy: config.slow_consumer,
+        };
+        tasks.spawn(write_frames(
+            writer,
+            addr,
+            inner,
+            outbox.policy,
+            Duration::from_secs(config.slow_consumer_timeout_secs),
+        ));
+        outbox
+    }
+
+    pub fn send(&self, frame: &Frame) -> io::Result<Sent> {
+        Ok(self.send_encoded(Arc::from(codec::encode_frame(frame)?)))
+    }
+
+    // Queues an already encoded frame
+    pub fn send_encoded(&self, bytes: Arc<[u8]>) -> Sent {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if queue.closed {
+            return Sent::Closed;
+        }
+
+        if queue.frames >= self.capacity {
+            match self.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    let oldest = queue
+                        .items
+                        .iter()
+                        .position(|item| matches!(item, Outgoing::Frame(_)));
+                    if let Some(oldest) = oldest {
+                        queue.items.remove(oldest);
+                        queue.frames -= 1;
+                    }
+                }
+                // Nothing can be queued until the client catches up. The queue
+                // only stays full while a write is blocked, and the writer
+                // closes the connection if that lasts too long.
+                SlowConsumerPolicy::Disconnect => return Sent::Dropped,
+            }
+        }
+
+        queue.items.push_back(Outgoing::Frame(bytes));
+        queue.frames += 1;
+        self.inner.ready.notify_one();
+        Sent::Queued
+    }
+
+    pub fn subscribe(&self, room: &str, receiver: broadcast::Receiver<RoomFrame>) {
+        self.push(Outgoing::Subscribe(room.to_string(), receiver));
+    }
+
+    pub fn unsubscribe(&self, room: &str) {
+        self.push(Outgoing::Unsubscribe(room.to_string()));
+    }
+
+    fn push(&self, item: Outgoing) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if !queue.closed {
+            queue.items.push_back(item);
+            self.inner.ready.notify_one();
+        }
+    }
+
+    // Sends whatever is still queued, then closes the connection
+    pub fn close(&self) {
+        self.inner.queue.lock().unwrap().closed = true;
+        self.inner.ready.notify_one();
+    }
+
+    // Like `close`, but first delivers what's already been sent to the client's
+    // rooms, then `frame` as the last thing the client hears
+    pub fn close_with(&self, frame: &Frame) -> io::Result<()> {
+        let bytes = Arc::from(codec::encode_frame(frame)?);
+        let mut queue = self.inner.queue.lock().unwrap();
+        if !queue.closed {
+            queue.closed = true;
+            queue.farewell = Some(bytes);
+            self.inner.ready.notify_one();
+        }
+        Ok(())
+    }
+
+    // Cancelled once the connection has been closed, whether by `close` or
+    // because writing to it failed
+    pub fn closed(&self) -> CancellationToken {
+        self.inner.done.clone()
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+enum Next {
+    Item(Outgoing),
+    Empty,
+    Closed,
+}
+
+impl Inner {
+    fn next(&self) -> Next {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.items.pop_front() {
+            Some(item) => {
+                if let Outgoing::Frame(_) = item {
+                    queue.frames -= 1;
+                }
+                Next::Item(item)
+            }
+            None if queue.closed => Next::Closed,
+            None => Next::Empty,
+        }
+    }
+}
+
+// Writes one frame. Under the "disconnect" policy, a write that blocks for
+// `timeout` means the client has stopped reading.
+async fn write<W>(
+    writer: &mut W,
+    bytes: &[u8],
+    policy: SlowConsumerPolicy,
+    timeout: Duration,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let write = async {
+        writer.write_all(bytes).await?;
+        writer.flush().await
+    };
+    match policy {
+        SlowConsumerPolicy::Disconnect => time::timeout(timeout, write)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        SlowConsumerPolicy::DropOldest => write.await,
+    }
+}
+
+// Writes the frames already waiting in `rooms`, without waiting for more
+async fn write_ready_room_frames<W>(
+    writer: &mut W,
+    rooms: &mut StreamMap<String, BroadcastStream<RoomFrame>>,
+    addr: SocketAddr,
+    policy: SlowConsumerPolicy,
+    timeout: Duration,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        // Unconstrained, so tokio's cooperative budget can't make a waiting frame
+        // look like there's nothing left
+        let next = task::unconstrained(future::poll_fn(|cx| {
+            match Pin::new(&mut *rooms).poll_next(cx) {
+                Poll::Pending => Poll::Ready(None),
+                ready => ready,
+            }
+        }))
+        .await;
+        match next {
+            Some((_, Ok(frame))) => {
+                if let Some(bytes) = frame.bytes_for(addr) {
+                    write(writer, &bytes, policy, timeout).await?
+                }
+            }
+            Some(_) => {}
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn write_frames<W>(
+    mut writer: W,
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+    policy: SlowConsumerPolicy,
+    timeout: Duration,
+) where
+    W: AsyncWrite + Unpin,
+{
+    let mut rooms: StreamMap<String, BroadcastStream<RoomFrame>> = StreamMap::new();
+
+    // Whether everything queued was written before the connection was closed
+    let finished = loop {
+        // The client's own queue goes first, so it hears that it joined a room
+        // before anything said in it
+        let bytes = match inner.next() {
+            Next::Item(Outgoing::Frame(bytes)) => bytes,
+            Next::Item(Outgoing::Subscribe(room, receiver)) => {
+                rooms.insert(room, BroadcastStream::new(receiver));
+                continue;
+            }
+            Next::Item(Outgoing::Unsubscribe(room)) => {
+                rooms.remove(&room);
+                continue;
+            }
+            Next::Closed => break true,
+            Next::Empty => tokio::select! {
+                biased;
+                _ = inner.ready.notified() => continue,
+                Some((room, frame)) = rooms.next() => match frame {
+                    Ok(frame) => match frame.bytes_for(addr) {
+                        Some(bytes) => bytes,
+                        None => continue,
+                    },
+                    // The room's channel dropped frames this client hadn't read
+                    // yet, oldest first. The client carries on from the ones that
+                    // are left; if it has stopped reading altogether, a write
+                    // blocks and the timeout catches it.
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        eprintln!("{} fell {} messages behind in {}", addr, missed, room);
+                        continue;
+                    }
+                },
+            },
+        };
+
+        if write(&mut writer, &bytes, policy, timeout).await.is_err() {
+            break false;
+        }
+    };
+
+    let farewell = {
+        let mut queue = inner.queue.lock().unwrap();
+        queue.closed = true;
+        queue.items.clear();
+        queue.farewell.take()
+    };
+    if let Some(farewell) = farewell.filter(|_| finished) {
+        let drained = write_ready_room_frames(&mut writer, &mut rooms, addr, policy, timeout).await;
+        if drained.is_ok() {
+            let _ = write(&mut writer, &farewell, policy, timeout).await;
+        }
+    }
+    let _ = time::timeout(timeout, writer.shutdown()).await;
+    inner.done.cancel();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::FrameReader;
+
+    const ADDR: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 4000);
+
+    fn notice(text: &str) -> Frame {
+        Frame::Notice {
+            text: text.to_string(),
+        }
+    }
+
+    fn config(queue_len: usize, slow_consumer: SlowConsumerPolicy) -> OutboundConfig {
+        OutboundConfig {
+            queue_len,
+            slow_consumer,
+            slow_consumer_timeout_secs: 1,
+        }
+    }
+
+    // The writer task only runs once the test awaits something, so until then
+    // the client looks like it isn't reading at all
+    #[tokio::test]
+    async fn drop_oldest_makes_room_for_new_frames() {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let tasks = TaskTracker::new();
+        let outbox = Outbox::spawn(
+            writer,
+            ADDR,
+            &config(2, SlowConsumerPolicy::DropOldest),
+            &tasks,
+        );
+
+        for text in ["one", "two", "three"] {
+            assert_eq!(outbox.send(&notice(text)).unwrap(), Sent::Queued);
+        }
+        outbox.close();
+        assert_eq!(outbox.send(&notice("four")).unwrap(), Sent::Closed);
+
+        let mut reader = FrameReader::with_max_frame_len(reader, 1024);
+        assert_eq!(reader.read_frame().await.unwrap(), Some(notice("two")));
+        assert_eq!(reader.read_frame().await.unwrap(), Some(notice("three")));
+        assert_eq!(reader.read_frame().await.unwrap(), None);
+    }
+
+    // Time only moves when every task is waiting, so the writer is as stuck as
+    // a client that stopped reading would leave it
+    #[tokio::test(start_paused = true)]
+    async fn disconnect_drops_frames_then_closes_after_the_timeout() {
+        // Too small for a frame, so the first write never finishes
+        let (writer, _reader) = tokio::io::duplex(1);
+        let tasks = TaskTracker::new();
+        let outbox = Outbox::spawn(
+            writer,
+            ADDR,
+            &config(1, SlowConsumerPolicy::Disconnect),
+            &tasks,
+        );
+
+        assert_eq!(outbox.send(&notice("one")).unwrap(), Sent::Queued);
+        task::yield_now().await;
+        assert_eq!(outbox.send(&notice("two")).unwrap(), Sent::Queued);
+        assert_eq!(outbox.send(&notice("three")).unwrap(), Sent::Dropped);
+        time::sleep(Duration::from_millis(900)).await;
+        assert_eq!(outbox.send(&notice("four")).unwrap(), Sent::Dropped);
+
+        // Nothing else is sent, but the client is still disconnected in time
+        let started = time::Instant::now();
+        outbox.closed().cancelled().await;
+        assert!(started.elapsed() <= Duration::from_secs(2));
+        assert_eq!(outbox.send(&notice("five")).unwrap(), Sent::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lagging_room_skips_ahead_instead_of_disconnecting() {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let tasks = TaskTracker::new();
+        let outbox = Outbox::spawn(
+            writer,
+            ADDR,
+            &config(4, SlowConsumerPolicy::Disconnect),
+            &tasks,
+        );
+        let (room, receiver) = broadcast::channel(2);
+        outbox.subscribe("#lobby", receiver);
+        let room_frame = |text| RoomFrame {
+            bytes: Arc::from(codec::encode_frame(&notice(text)).unwrap()),
+            exclude: None,
+            echo: None,
+        };
+
+        // Sent before the writer gets to run, so the oldest fall out of the channel
+        for text in ["one", "two", "three", "four"] {
+            assert!(room.send(room_frame(text)).is_ok());
+        }
+        let mut reader = FrameReader::with_max_frame_len(reader, 1024);
+        assert_eq!(reader.read_frame().await.unwrap(), Some(notice("three")));
+        assert_eq!(reader.read_frame().await.unwrap(), Some(notice("four")));
+
+        // Still connected well after the slow consumer timeout
+        time::sleep(Duration::from_secs(5)).await;
+        assert!(room.send(room_frame("five")).is_ok());
+        assert_eq!(reader.read_frame().await.unwrap(), Some(notice("five")));
+        assert!(!outbox.closed().is_cancelled());
+    }
+}
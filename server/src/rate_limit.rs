@@ -0,0 +1,393 @@
+use crate::{
+    Connections,
+    config::{BucketLimits, FloodAction, RateLimitConfig},
+    history::now_millis,
+    moderation::Moderation,
+    send_to,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tcptalk_protocol::Frame;
+
+// Refills at `rate` tokens per second, up to `capacity`. Starts full, so a
+// client can send a short burst before the rate applies.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+// A message bucket and a byte bucket that are only ever drawn from together
+struct Buckets {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limits: &BucketLimits) -> Self {
+        Self {
+            messages: TokenBucket::new(limits.messages_per_second, limits.message_burst as f64),
+            bytes: TokenBucket::new(limits.bytes_per_second as f64, limits.byte_burst as f64),
+        }
+    }
+
+    fn has(&mut self, bytes: f64, now: Instant) -> bool {
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        self.messages.tokens >= 1.0 && self.bytes.tokens >= bytes
+    }
+
+    // Bytes are always charged, since they've been received whether or not the
+    // frame is let through. Going into debt makes a flooder wait it off.
+    fn take(&mut self, bytes: f64, allowed: bool) {
+        self.bytes.tokens -= bytes;
+        if allowed {
+            self.messages.tokens -= 1.0;
+        }
+    }
+}
+
+// Limits for a single connection, owned by its handler task
+pub struct ConnectionLimits {
+    buckets: Buckets,
+    // `FrameReader::bytes_read` at the last check
+    counted: u64,
+    // Whether the previous frame was over the limit, so a client is only told
+    // about a flood once rather than for every frame in it
+    flooding: bool,
+    failed_logins: u32,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            buckets: Buckets::new(&config.connection),
+            counted: 0,
+            flooding: false,
+            failed_logins: 0,
+        }
+    }
+}
+
+struct IpState {
+    connections: usize,
+    buckets: Buckets,
+}
+
+// Limits shared by every connection from the same IP address
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    ips: HashMap<IpAddr, IpState>,
+    // A token for each failed login an IP address may still make. Kept after
+    // its connections close, until the bucket has refilled.
+    failed_logins: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            ips: HashMap::new(),
+            failed_logins: HashMap::new(),
+        }
+    }
+
+    // Counts a new connection from `ip`, unless it already has as many as allowed
+    pub fn open(&mut self, ip: IpAddr) -> bool {
+        let limits = &self.config.ip;
+        let state = self.ips.entry(ip).or_insert_with(|| IpState {
+            connections: 0,
+            buckets: Buckets::new(limits),
+        });
+        if state.connections >= self.config.max_connections_per_ip {
+            return false;
+        }
+        state.connections += 1;
+        true
+    }
+
+    pub fn close(&mut self, ip: IpAddr) {
+        if let Some(state) = self.ips.get_mut(&ip) {
+            state.connections -= 1;
+            if state.connections == 0 {
+                self.ips.remove(&ip);
+            }
+        }
+    }
+
+    // Charges a frame, and everything read since the last one, to the connection
+    // and its IP address. Returns whether the frame is within both limits.
+    pub fn check(&mut self, ip: IpAddr, limits: &mut ConnectionLimits, bytes_read: u64) -> bool {
+        self.check_at(ip, limits, bytes_read, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        ip: IpAddr,
+        limits: &mut ConnectionLimits,
+        bytes_read: u64,
+        now: Instant,
+    ) -> bool {
+        let bytes = bytes_read.saturating_sub(limits.counted) as f64;
+        limits.counted = bytes_read;
+
+        let connection = &mut limits.buckets;
+        let allowed = match self.ips.get_mut(&ip) {
+            Some(state) => {
+                let allowed = connection.has(bytes, now) && state.buckets.has(bytes, now);
+                state.buckets.take(bytes, allowed);
+                allowed
+            }
+            None => connection.has(bytes, now),
+        };
+        connection.take(bytes, allowed);
+        if allowed {
+            limits.flooding = false;
+        }
+        allowed
+    }
+
+    // Whether `ip` has used up its failed logins, so shouldn't try another password
+    pub fn login_blocked(&mut self, ip: IpAddr) -> bool {
+        self.login_blocked_at(ip, Instant::now())
+    }
+
+    fn login_blocked_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.prune_failed_logins(now);
+        self.failed_logins
+            .get(&ip)
+            .is_some_and(|bucket| bucket.tokens < 1.0)
+    }
+
+    // Counts a failed login against the connection and its IP address. Returns
+    // whether either has now run out of attempts.
+    pub fn login_failed(&mut self, ip: IpAddr, limits: &mut ConnectionLimits) -> bool {
+        self.login_failed_at(ip, limits, Instant::now())
+    }
+
+    fn login_failed_at(&mut self, ip: IpAddr, limits: &mut ConnectionLimits, now: Instant) -> bool {
+        limits.failed_logins += 1;
+        self.prune_failed_logins(now);
+        let allowed = self.config.max_failed_logins_per_ip as f64;
+        let rate = allowed / self.config.failed_login_window_secs as f64;
+        let bucket = self
+            .failed_logins
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(rate, allowed));
+        bucket.refill(now);
+        bucket.tokens -= 1.0;
+        limits.failed_logins >= self.config.max_failed_logins || bucket.tokens < 1.0
+    }
+
+    // Forgets addresses whose failed logins have all been refilled
+    fn prune_failed_logins(&mut self, now: Instant) {
+        self.failed_logins.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.capacity
+        });
+    }
+}
+
+// Applies the configured flood response to a client whose frame was over the
+// limit. The frame itself is always dropped; returns false if the client should
+// be disconnected.
+pub fn handle_flood(
+    addr: SocketAddr,
+    username: &str,
+    limits: &mut ConnectionLimits,
+    connections: &Arc<Mutex<Connections>>,
+    moderation: &Arc<Mutex<Moderation>>,
+    config: &RateLimitConfig,
+) -> io::Result<bool> {
+    let first = !limits.flooding;
+    limits.flooding = true;
+
+    let message = match config.action {
+        FloodAction::Drop => return Ok(true),
+        FloodAction::Warn if first => {
+            "You are sending messages too fast. Messages are being dropped.".to_string()
+        }
+        FloodAction::Warn => return Ok(true),
+        FloodAction::Mute => {
+            let mut moderation = moderation.lock().unwrap();
+            if moderation.check_mute(username).is_err() {
+                return Ok(true);
+            }
+            // `mute_secs` is capped when the config is loaded, so this can't overflow
+            let until = now_millis().saturating_add(config.mute_secs.saturating_mul(1000));
+            moderation.mute(username, Some(until));
+            format!(
+                "You were muted for {} seconds for flooding.",
+                config.mute_secs
+            )
+        }
+        FloodAction::Disconnect => {
+            if let Some(client) = connections.lock().unwrap().clients.get_mut(&addr) {
+                client.disconnect("Disconnected for flooding.");
+            }
+            return Ok(false);
+        }
+    };
+
+    send_to(addr, &Frame::Error { message }, connections)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+    use std::time::Duration;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn config(action: FloodAction) -> RateLimitConfig {
+        RateLimitConfig {
+            connection: BucketLimits {
+                messages_per_second: 4.0,
+                message_burst: 3,
+                bytes_per_second: 1000,
+                byte_burst: 1000,
+            },
+            ip: BucketLimits {
+                messages_per_second: 4.0,
+                message_burst: 5,
+                bytes_per_second: 1000,
+                byte_burst: 1000,
+            },
+            action,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    // Sends `count` frames of 10 bytes at `now`, returning how many got through
+    fn send(
+        limiter: &mut RateLimiter,
+        limits: &mut ConnectionLimits,
+        count: usize,
+        now: Instant,
+    ) -> usize {
+        (0..count)
+            .filter(|_| {
+                let bytes_read = limits.counted + 10;
+                limiter.check_at(IP, limits, bytes_read, now)
+            })
+            .count()
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_capacity() {
+        let mut bucket = TokenBucket::new(2.0, 5.0);
+        let start = bucket.updated;
+        bucket.tokens = 0.0;
+        bucket.refill(start + Duration::from_secs(1));
+        assert_eq!(bucket.tokens, 2.0);
+        bucket.refill(start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 5.0);
+    }
+
+    #[test]
+    fn allows_a_burst_then_the_rate() {
+        let mut limiter = RateLimiter::new(&config(FloodAction::Drop));
+        assert!(limiter.open(IP));
+        let mut limits = ConnectionLimits::new(&limiter.config);
+        let now = Instant::now();
+
+        assert_eq!(send(&mut limiter, &mut limits, 5, now), 3);
+        // One message's worth refills every quarter of a second
+        let later = now + Duration::from_millis(250);
+        assert_eq!(send(&mut limiter, &mut limits, 5, later), 1);
+        let much_later = now + Duration::from_secs(60);
+        assert_eq!(send(&mut limiter, &mut limits, 5, much_later), 3);
+    }
+
+    #[test]
+    fn connections_from_one_ip_share_its_limit() {
+        let mut limiter = RateLimiter::new(&config(FloodAction::Drop));
+        assert!(limiter.open(IP));
+        assert!(limiter.open(IP));
+        let mut first = ConnectionLimits::new(&limiter.config);
+        let mut second = ConnectionLimits::new(&limiter.config);
+        let now = Instant::now();
+
+        assert_eq!(send(&mut limiter, &mut first, 3, now), 3);
+        assert_eq!(send(&mut limiter, &mut second, 3, now), 2);
+    }
+
+    #[test]
+    fn flooding_mutes_the_client_once() {
+        let config = config(FloodAction::Mute);
+        let connections = Arc::new(Mutex::new(Connections::default()));
+        let bans = temp_file("flood-bans.json");
+        let moderation = Arc::new(Mutex::new(Moderation::open(bans).unwrap()));
+        let mut limits = ConnectionLimits::new(&config);
+        let addr = SocketAddr::new(IP, 4000);
+
+        let flood = |limits: &mut ConnectionLimits| {
+            handle_flood(addr, "mallory", limits, &connections, &moderation, &config).unwrap()
+        };
+        assert!(moderation.lock().unwrap().check_mute("mallory").is_ok());
+        assert!(flood(&mut limits));
+        let muted = moderation
+            .lock()
+            .unwrap()
+            .check_mute("mallory")
+            .unwrap_err();
+        assert!(muted.starts_with("You are muted for another"));
+        // Flooding on while muted keeps the client connected and muted
+        assert!(flood(&mut limits));
+        assert!(moderation.lock().unwrap().check_mute("mallory").is_err());
+    }
+
+    #[test]
+    fn failed_logins_are_capped_per_connection_and_per_ip() {
+        let config = RateLimitConfig {
+            max_failed_logins: 3,
+            max_failed_logins_per_ip: 5,
+            failed_login_window_secs: 50,
+            ..RateLimitConfig::default()
+        };
+        let mut limiter = RateLimiter::new(&config);
+        let now = Instant::now();
+
+        let mut first = ConnectionLimits::new(&config);
+        assert!(!limiter.login_failed_at(IP, &mut first, now));
+        assert!(!limiter.login_failed_at(IP, &mut first, now));
+        assert!(limiter.login_failed_at(IP, &mut first, now));
+        assert!(!limiter.login_blocked_at(IP, now));
+
+        // A new connection starts over, but the address runs out
+        let mut second = ConnectionLimits::new(&config);
+        assert!(!limiter.login_failed_at(IP, &mut second, now));
+        assert!(limiter.login_failed_at(IP, &mut second, now));
+        assert!(limiter.login_blocked_at(IP, now));
+
+        // One attempt comes back every 10 seconds, and a full address is forgotten
+        assert!(!limiter.login_blocked_at(IP, now + Duration::from_secs(10)));
+        assert!(!limiter.login_blocked_at(IP, now + Duration::from_secs(60)));
+        assert!(limiter.failed_logins.is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+use tcptalk_protocol::{Frame, FrameDecoder, FrameError, codec::READ_CHUNK_LEN};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Async counterpart of the protocol crate's `FrameReader`
+pub struct FrameReader<R> {
+    inner: R,
+    decoder: FrameDecoder,
+    bytes_read: u64,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn with_max_frame_len(inner: R, max_frame_len: usize) -> Self {
+        Self {
+            inner,
+            decoder: FrameDecoder::new(max_frame_len),
+            bytes_read: 0,
+        }
+    }
+
+    // Total bytes read from the socket so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    // Reads the next frame, returning `Ok(None)` if the peer closed the connection
+    // cleanly between two frames. Safe to cancel: a partly received frame stays
+    // buffered for the next call.
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        let mut chunk = [0u8; READ_CHUNK_LEN];
+        loop {
+            if let Some(frame) = self.decoder.decode()? {
+                return Ok(Some(frame));
+            }
+
+            match self.inner.read(&mut chunk).await? {
+                0 if self.decoder.is_empty() => return Ok(None),
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                n => {
+                    self.bytes_read += n as u64;
+                    self.decoder.extend(&chunk[..n]);
+                }
+            }
+        }
+    }
+}
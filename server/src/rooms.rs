@@ -0,0 +1,425 @@
+use crate::{
+    Connections, broadcast_message,
+    config::Config,
+    history::{History, now_millis},
+    send_to,
+};
+use indexmap::IndexMap;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tcptalk_protocol::{Frame, RoomSummary};
+use tokio::sync::broadcast;
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+// Room names are case-insensitive and always start with '#'. A missing '#' is added,
+// so "/join lobby" and "/join #Lobby" name the same room.
+pub fn normalize_room_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    let bare = name.strip_prefix('#').unwrap_or(name);
+
+    if bare.is_empty() {
+        return Err("Room name cannot be empty.".to_string());
+    }
+    if bare.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(format!(
+            "Room names may be at most {} characters long.",
+            MAX_ROOM_NAME_LEN
+        ));
+    }
+    if let Some(c) = bare
+        .chars()
+        .find(|c| !c.is_alphanumeric() && *c != '-' && *c != '_')
+    {
+        return Err(format!("Room names may not contain {:?}.", c));
+    }
+
+    Ok(format!("#{}", bare.to_lowercase()))
+}
+
+// A room exists while it has members; the default room always exists.
+fn room_exists(conn_map: &Connections, room: &str, config: &Config) -> bool {
+    room == config.default_room || conn_map.rooms.contains_key(room)
+}
+
+// Adds the client at `addr` to `room` and subscribes it to the room's broadcast
+// channel, which is created by the room's first member. Every member holds a
+// sender for the channel as well as the registry, so the registry's sender is
+// the last one left once everyone has gone.
+pub fn join_room(conn_map: &mut Connections, addr: SocketAddr, room: &str, config: &Config) {
+    let Some(client) = conn_map.clients.get_mut(&addr) else {
+        return;
+    };
+    let sender = conn_map
+        .rooms
+        .entry(room.to_string())
+        .or_insert_with(|| broadcast::channel(config.outbound.queue_len).0);
+    client.outbox.subscribe(room, sender.subscribe());
+    client.rooms.insert(room.to_string(), sender.clone());
+}
+
+pub fn leave_room(conn_map: &mut Connections, addr: SocketAddr, room: &str) {
+    if let Some(client) = conn_map.clients.get_mut(&addr) {
+        client.rooms.shift_remove(room);
+        client.outbox.unsubscribe(room);
+    }
+    release_room(conn_map, room);
+}
+
+// Drops `room`'s channel once its last member has left
+pub fn release_room(conn_map: &mut Connections, room: &str) {
+    if conn_map
+        .rooms
+        .get(room)
+        .is_some_and(|sender| sender.strong_count() == 1)
+    {
+        conn_map.rooms.remove(room);
+    }
+}
+
+fn user_list(connections: &Arc<Mutex<Connections>>, room: &str) -> Frame {
+    let conn_map = connections.lock().unwrap();
+    let users = conn_map
+        .clients
+        .values()
+        .filter(|client| client.rooms.contains_key(room))
+        .map(|client| client.username.clone())
+        .collect();
+    Frame::UserList {
+        room: room.to_string(),
+        users,
+    }
+}
+
+pub fn broadcast_user_list(room: &str, connections: &Arc<Mutex<Connections>>) -> io::Result<()> {
+    let frame = user_list(connections, room);
+    broadcast_message(
+        &frame,
+        room,
+        "0.0.0.0:0".parse().unwrap(),
+        connections,
+        true,
+    )
+}
+
+pub fn handle_user_list_request(
+    addr: SocketAddr,
+    room: &str,
+    connections: &Arc<Mutex<Connections>>,
+) -> io::Result<()> {
+    send_to(addr, &user_list(connections, room), connections)
+}
+
+pub fn handle_room_list_request(
+    addr: SocketAddr,
+    connections: &Arc<Mutex<Connections>>,
+    config: &Config,
+) -> io::Result<()> {
+    let conn_map = connections.lock().unwrap();
+    let mut rooms: IndexMap<&str, usize> = IndexMap::new();
+    rooms.insert(&config.default_room, 0);
+    for client in conn_map.clients.values() {
+        for room in client.rooms.keys() {
+            *rooms.entry(room).or_default() += 1;
+        }
+    }
+    let rooms = rooms
+        .into_iter()
+        .map(|(name, members)| RoomSummary {
+            name: name.to_string(),
+            members,
+        })
+        .collect();
+    drop(conn_map);
+
+    send_to(addr, &Frame::RoomList { rooms }, connections)
+}
+
+// Tells a client it joined `room` and replays the room's recent messages, or
+// every stored message after `after_id` when it's resuming a session. Then
+// announces it to the other members and refreshes the room's user list. The
+// caller holds the history lock from before the client joined the room, so
+// each message reaches it once: in the replay or live.
+pub fn announce_join(
+    addr: SocketAddr,
+    username: &str,
+    room: &str,
+    after_id: Option<u64>,
+    connections: &Arc<Mutex<Connections>>,
+    history: &mut History,
+) -> io::Result<()> {
+    let joined = Frame::RoomJoined {
+        room: room.to_string(),
+    };
+    send_to(addr, &joined, connections)?;
+
+    let mut messages = match after_id {
+        Some(after_id) => history.since(room, after_id),
+        None => history.replay(room),
+    };
+    // A resuming client gets back the numbers it gave its own messages, to match
+    // up those it never heard back about. Nobody else sees them.
+    for message in &mut messages {
+        if after_id.is_none() || !message.author.eq_ignore_ascii_case(username) {
+            message.client_id = None;
+        }
+    }
+    if !messages.is_empty() {
+        let replay = Frame::History {
+            room: room.to_string(),
+            messages,
+        };
+        send_to(addr, &replay, connections)?;
+    }
+
+    let join = Frame::Join {
+        id: history.next_id(),
+        timestamp: now_millis(),
+        room: room.to_string(),
+        username: username.to_string(),
+    };
+    broadcast_message(&join, room, addr, connections, false)?; // Don't send to sender
+
+    // Broadcast updated user list to everyone in the room (including the new member)
+    broadcast_user_list(room, connections)
+}
+
+// Announces that a client left `room` to the remaining members. The client must
+// already have been removed from the room, and the caller holds the history lock.
+pub fn announce_leave(
+    username: &str,
+    room: &str,
+    connections: &Arc<Mutex<Connections>>,
+    history: &mut History,
+) -> io::Result<()> {
+    let leave = Frame::Leave {
+        id: history.next_id(),
+        timestamp: now_millis(),
+        room: room.to_string(),
+        username: username.to_string(),
+    };
+    broadcast_message(
+        &leave,
+        room,
+        "0.0.0.0:0".parse().unwrap(),
+        connections,
+        false,
+    )?;
+    broadcast_user_list(room, connections)
+}
+
+pub fn handle_room_request(
+    addr: SocketAddr,
+    frame: Frame,
+    connections: &Arc<Mutex<Connections>>,
+    history: &Arc<Mutex<History>>,
+    config: &Config,
+) -> io::Result<()> {
+    let (name, create, join) = match &frame {
+        Frame::CreateRoom { room } => (room, true, true),
+        Frame::JoinRoom { room } => (room, false, true),
+        Frame::LeaveRoom { room } => (room, false, false),
+        _ => return Ok(()),
+    };
+
+    // Taken before the connections lock and held until the change is announced,
+    // so a joining client can't get a message both replayed and live
+    let mut history = history.lock().unwrap();
+
+    // Check and update membership under one lock so two clients can't both create
+    // the same room
+    let result = normalize_room_name(name).and_then(|room| {
+        let mut conn_map = connections.lock().unwrap();
+        let exists = room_exists(&conn_map, &room, config);
+        let Some(client) = conn_map.clients.get_mut(&addr) else {
+            return Err("You are not logged in.".to_string());
+        };
+        let is_member = client.rooms.contains_key(&room);
+        let username = client.username.clone();
+
+        if create && exists {
+            Err(format!("Room {} already exists.", room))
+        } else if join && !create && !exists {
+            Err(format!("Room {} does not exist. Create it first.", room))
+        } else if join && is_member {
+            Err(format!("You are already in {}.", room))
+        } else if !join && !is_member {
+            Err(format!("You are not in {}.", room))
+        } else {
+            if join {
+                join_room(&mut conn_map, addr, &room, config);
+            } else {
+                leave_room(&mut conn_map, addr, &room);
+            }
+            Ok((room, username))
+        }
+    });
+
+    match result {
+        Ok((room, username)) if join => {
+            announce_join(addr, &username, &room, None, connections, &mut history)
+        }
+        Ok((room, username)) => {
+            let left = Frame::RoomLeft { room: room.clone() };
+            send_to(addr, &left, connections)?;
+            announce_leave(&username, &room, connections, &mut history)
+        }
+        Err(message) => send_to(addr, &Frame::Error { message }, connections),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reader::FrameReader,
+        test_support::{self, connect, memory_history},
+    };
+    use tokio::io::DuplexStream;
+    use tokio_util::task::TaskTracker;
+
+    #[test]
+    fn room_names_are_normalized() {
+        assert_eq!(normalize_room_name("lobby").unwrap(), "#lobby");
+        assert_eq!(normalize_room_name("  #Dev-Ops_2 ").unwrap(), "#dev-ops_2");
+        assert_eq!(normalize_room_name("#Café").unwrap(), "#café");
+        let longest = "x".repeat(MAX_ROOM_NAME_LEN);
+        assert!(normalize_room_name(&longest).is_ok());
+    }
+
+    #[test]
+    fn bad_room_names_are_rejected() {
+        for name in ["", " ", "#"] {
+            assert_eq!(
+                normalize_room_name(name).unwrap_err(),
+                "Room name cannot be empty."
+            );
+        }
+        let too_long = "x".repeat(MAX_ROOM_NAME_LEN + 1);
+        assert!(normalize_room_name(&too_long).is_err());
+        assert_eq!(
+            normalize_room_name("two words").unwrap_err(),
+            "Room names may not contain ' '."
+        );
+        assert!(normalize_room_name("##lobby").is_err());
+    }
+
+    fn rooms(connections: &Arc<Mutex<Connections>>, addr: SocketAddr) -> Vec<String> {
+        connections.lock().unwrap().clients[&addr]
+            .rooms
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    // Describes what the client at `addr` was sent about rooms
+    async fn received(
+        connections: &Arc<Mutex<Connections>>,
+        addr: SocketAddr,
+        reader: FrameReader<DuplexStream>,
+    ) -> Vec<String> {
+        test_support::received(connections, addr, reader)
+            .await
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::RoomJoined { room } => Some(format!("joined {}", room)),
+                Frame::RoomLeft { room } => Some(format!("left {}", room)),
+                Frame::Join { room, username, .. } => Some(format!("{} joined {}", username, room)),
+                Frame::Leave { room, username, .. } => Some(format!("{} left {}", username, room)),
+                Frame::UserList { room, users } => Some(format!("{}: {}", room, users.join(", "))),
+                Frame::Error { message } => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn joining_and_leaving_keeps_track_of_members() {
+        let config = Config::default();
+        let history = memory_history();
+        let connections = Arc::new(Mutex::new(Connections::default()));
+        let tasks = TaskTracker::new();
+        let alice: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let bob: SocketAddr = "127.0.0.2:4000".parse().unwrap();
+        let carol: SocketAddr = "127.0.0.3:4000".parse().unwrap();
+        let alice_reader = connect(&connections, alice, "alice", &[], &tasks);
+        let bob_reader = connect(&connections, bob, "bob", &[], &tasks);
+        let carol_reader = connect(&connections, carol, "carol", &[], &tasks);
+        let request = |addr, frame| {
+            handle_room_request(addr, frame, &connections, &history, &config).unwrap()
+        };
+        let join = |room: &str| Frame::JoinRoom {
+            room: room.to_string(),
+        };
+        let create = |room: &str| Frame::CreateRoom {
+            room: room.to_string(),
+        };
+        let leave = |room: &str| Frame::LeaveRoom {
+            room: room.to_string(),
+        };
+
+        // The default room always exists
+        request(alice, join("lobby"));
+        request(bob, join("#LOBBY"));
+        request(alice, create("Dev"));
+        request(bob, create("#dev"));
+        request(bob, join("#nope"));
+        request(bob, join("dev"));
+        request(bob, join("#dev"));
+        assert_eq!(rooms(&connections, alice), ["#lobby", "#dev"]);
+        assert_eq!(rooms(&connections, bob), ["#lobby", "#dev"]);
+
+        request(alice, leave("#dev"));
+        request(alice, leave("#dev"));
+        assert_eq!(rooms(&connections, alice), ["#lobby"]);
+        // The room goes away with its last member
+        request(bob, leave("#dev"));
+        assert!(!connections.lock().unwrap().rooms.contains_key("#dev"));
+        request(carol, join("#dev"));
+        request(carol, join("bad name"));
+        request(carol, join("#lobby"));
+        assert_eq!(rooms(&connections, bob), ["#lobby"]);
+        assert_eq!(rooms(&connections, carol), ["#lobby"]);
+
+        assert_eq!(
+            received(&connections, alice, alice_reader).await,
+            [
+                "joined #lobby",
+                "joined #dev",
+                "left #dev",
+                "You are not in #dev.",
+                "#lobby: alice",
+                "bob joined #lobby",
+                "#lobby: alice, bob",
+                "carol joined #lobby",
+                "#lobby: alice, bob, carol",
+            ]
+        );
+        assert_eq!(
+            received(&connections, bob, bob_reader).await,
+            [
+                "joined #lobby",
+                "Room #dev already exists.",
+                "Room #nope does not exist. Create it first.",
+                "joined #dev",
+                "You are already in #dev.",
+                "left #dev",
+                "#lobby: alice, bob",
+                "carol joined #lobby",
+                "#lobby: alice, bob, carol",
+            ]
+        );
+        assert_eq!(
+            received(&connections, carol, carol_reader).await,
+            [
+                "Room #dev does not exist. Create it first.",
+                "Room names may not contain ' '.",
+                "joined #lobby",
+                "#lobby: alice, bob, carol",
+            ]
+        );
+    }
+}
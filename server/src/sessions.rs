@@ -0,0 +1,84 @@
+use crate::config::ResumeConfig;
+use rand_core::{OsRng, RngCore};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+// What a client needs to pick up where it left off
+pub struct Session {
+    pub username: String,
+    pub rooms: Vec<String>,
+}
+
+struct HeldSession {
+    session: Session,
+    expires: Instant,
+}
+
+// Sessions of recently disconnected clients, by resume token. While a session
+// is held, nobody else can log in with its username.
+pub struct Sessions {
+    held: HashMap<String, HeldSession>,
+    window: Duration,
+    // Lowercased names of clients partway through logging in
+    reserved: HashSet<String>,
+}
+
+impl Sessions {
+    pub fn new(config: &ResumeConfig) -> Self {
+        Self {
+            held: HashMap::new(),
+            window: Duration::from_secs(config.window_secs),
+            reserved: HashSet::new(),
+        }
+    }
+
+    pub fn hold(&mut self, token: String, session: Session) {
+        self.prune();
+        let expires = Instant::now() + self.window;
+        self.held.insert(token, HeldSession { session, expires });
+    }
+
+    pub fn take(&mut self, token: &str) -> Option<Session> {
+        self.prune();
+        self.held.remove(token).map(|held| held.session)
+    }
+
+    // Whether `username` belongs to a held session or a client logging in
+    pub fn is_reserved(&mut self, username: &str) -> bool {
+        self.prune();
+        self.reserved.contains(&username.to_lowercase())
+            || self
+                .held
+                .values()
+                .any(|held| held.session.username.eq_ignore_ascii_case(username))
+    }
+
+    // Returns false if the name was already reserved
+    pub fn reserve(&mut self, username: &str) -> bool {
+        self.reserved.insert(username.to_lowercase())
+    }
+
+    pub fn release(&mut self, username: &str) {
+        self.reserved.remove(&username.to_lowercase());
+    }
+
+    // Drops any session held for `username`, e.g. once its owner has logged in
+    // again with their password
+    pub fn drop_held(&mut self, username: &str) {
+        self.held
+            .retain(|_, held| !held.session.username.eq_ignore_ascii_case(username));
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.held.retain(|_, held| held.expires > now);
+    }
+}
+
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
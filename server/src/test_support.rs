@@ -0,0 +1,160 @@
+// Fixtures shared by the unit tests
+
+use crate::{
+    Client, Connections, Shared,
+    accounts::Accounts,
+    config::Config,
+    handle_client,
+    history::{History, MemoryStore},
+    moderation::Moderation,
+    outbox::Outbox,
+    rate_limit::RateLimiter,
+    reader::FrameReader,
+    rooms::join_room,
+    sessions::Sessions,
+};
+use indexmap::IndexMap;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::AtomicUsize},
+};
+use tcptalk_protocol::{Frame, codec};
+use tokio::io::{AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+// A path in the temp directory for the test called `name`, with nothing left at
+// it from an earlier run
+pub fn temp_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tcptalk-{}-{}", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+// An empty directory in the temp directory for the test called `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tcptalk-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn memory_history() -> Arc<Mutex<History>> {
+    Arc::new(Mutex::new(History::new(Box::new(MemoryStore::new(10)), 10)))
+}
+
+// Logs in `username` at `addr` and puts them in `rooms`. Returns what the
+// client would read from its socket.
+pub fn connect(
+    connections: &Arc<Mutex<Connections>>,
+    addr: SocketAddr,
+    username: &str,
+    rooms: &[&str],
+    tasks: &TaskTracker,
+) -> FrameReader<DuplexStream> {
+    let config = Config::default();
+    let (writer, reader) = tokio::io::duplex(16 * 1024);
+    let client = Client {
+        outbox: Outbox::spawn(writer, addr, &config.outbound, tasks),
+        username: username.to_string(),
+        rooms: IndexMap::new(),
+        operator: false,
+        resume_token: None,
+    };
+    let mut conn_map = connections.lock().unwrap();
+    conn_map.clients.insert(addr, client);
+    for room in rooms {
+        join_room(&mut conn_map, addr, room, &config);
+    }
+    FrameReader::with_max_frame_len(reader, 4096)
+}
+
+// Closes the connection at `addr` and returns everything it was sent: its own
+// queue first, then what was said in the rooms it's still in
+pub async fn received(
+    connections: &Arc<Mutex<Connections>>,
+    addr: SocketAddr,
+    mut reader: FrameReader<DuplexStream>,
+) -> Vec<Frame> {
+    let farewell = Frame::Disconnect {
+        reason: "The test is over.".to_string(),
+    };
+    if let Some(client) = connections.lock().unwrap().clients.get(&addr) {
+        client.outbox.close_with(&farewell).unwrap();
+    }
+    let mut received = Vec::new();
+    while let Some(frame) = reader.read_frame().await.unwrap() {
+        received.push(frame);
+    }
+    if received.last() == Some(&farewell) {
+        received.pop();
+    }
+    received
+}
+
+// Server state for running whole connections in the test, with nothing kept
+// on disk
+pub fn shared(mut config: Config) -> Shared {
+    config.moderation.bans_path = temp_file("unused-bans.json");
+    Shared {
+        connections: Arc::new(Mutex::new(Connections::default())),
+        history: Arc::new(Mutex::new(History::open(&config.history).unwrap())),
+        accounts: Arc::new(Mutex::new(Accounts::open(&config.accounts).unwrap())),
+        moderation: Arc::new(Mutex::new(
+            Moderation::open(config.moderation.bans_path.clone()).unwrap(),
+        )),
+        rate_limiter: Arc::new(Mutex::new(RateLimiter::new(&config.rate_limit))),
+        sessions: Arc::new(Mutex::new(Sessions::new(&config.resume))),
+        config: Arc::new(config),
+        open_connections: Arc::new(AtomicUsize::new(0)),
+        tls: None,
+        shutdown: CancellationToken::new(),
+        tasks: TaskTracker::new(),
+    }
+}
+
+pub type ClientReader = FrameReader<ReadHalf<DuplexStream>>;
+pub type ClientWriter = WriteHalf<DuplexStream>;
+
+// Connects a guest called `username` from `addr` to the server, the way the
+// accept loop would. Returns the client's ends once the login is accepted.
+pub async fn log_in(
+    shared: &Shared,
+    addr: SocketAddr,
+    username: &str,
+) -> (ClientReader, ClientWriter) {
+    let (client, server) = tokio::io::duplex(16 * 1024);
+    shared
+        .tasks
+        .spawn(handle_client(server, addr, shared.clone()));
+    let (reader, mut writer) = tokio::io::split(client);
+    let mut reader = FrameReader::with_max_frame_len(reader, 64 * 1024);
+    let login = Frame::Login {
+        username: username.to_string(),
+        password: None,
+    };
+    send(&mut writer, &login).await;
+    next(&mut reader, |frame| {
+        matches!(frame, Frame::LoginAccepted { .. })
+    })
+    .await;
+    (reader, writer)
+}
+
+pub async fn send(writer: &mut ClientWriter, frame: &Frame) {
+    let bytes = codec::encode_frame(frame).unwrap();
+    writer.write_all(&bytes).await.unwrap();
+}
+
+// Reads past everything else to the next frame that `matches`, or panics if
+// the connection closes first
+pub async fn next(reader: &mut ClientReader, matches: impl Fn(&Frame) -> bool) -> Frame {
+    loop {
+        match reader.read_frame().await.unwrap() {
+            Some(frame) if matches(&frame) => return frame,
+            Some(_) => continue,
+            None => panic!("the connection closed"),
+        }
+    }
+}
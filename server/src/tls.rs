@@ -0,0 +1,96 @@
+use crate::config::TlsConfig;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tcptalk_protocol::{
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    stream::fingerprint,
+};
+use tokio::{net::TcpStream, time};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+// How long a client gets to finish the handshake before it's dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Loads the certificate and key named in the config, generating a self-signed
+// pair first if neither file exists yet
+pub fn load_server_config(config: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+    match (config.cert.exists(), config.key.exists()) {
+        (false, false) => generate_self_signed(&config.cert, &config.key)?,
+        (true, false) => return Err(missing(&config.key)),
+        (false, true) => return Err(missing(&config.cert)),
+        (true, true) => {}
+    }
+
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(&config.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(|e| pem_error(&config.key, e))?;
+
+    let Some(leaf) = certs.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", config.cert.display()),
+        ));
+    };
+    println!("TLS certificate SHA256 fingerprint: {}", fingerprint(leaf));
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(server_config))
+}
+
+// Runs the server side of the handshake on a freshly accepted connection
+pub async fn accept(
+    socket: TcpStream,
+    config: Arc<ServerConfig>,
+) -> io::Result<TlsStream<TcpStream>> {
+    time::timeout(HANDSHAKE_TIMEOUT, TlsAcceptor::from(config).accept(socket))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> io::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(io::Error::other)?;
+
+    fs::write(cert_path, generated.cert.pem())?;
+
+    let mut key_file = OpenOptions::new();
+    key_file.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut key_file, 0o600);
+    key_file
+        .open(key_path)?
+        .write_all(generated.signing_key.serialize_pem().as_bytes())?;
+
+    println!(
+        "Generated a self-signed TLS certificate in {} and {}",
+        cert_path.display(),
+        key_path.display()
+    );
+    Ok(())
+}
+
+fn missing(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+fn pem_error(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), err),
+    )
+}
@@ -0,0 +1,107 @@
+// Helpers shared by the integration tests and the load benchmark, which run the
+// server binary and talk to it over real sockets. Each test crate only uses
+// some of them.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+use tcptalk_protocol::{Frame, FrameReader, Stream, write_frame};
+
+// Kills the server when the test ends, pass or fail
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tcptalk-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Starts the server in `dir` on 127.0.0.1:`port`, with `args` added to the
+// command line, and waits until it accepts connections
+pub fn start_server(dir: &Path, port: u16, args: &[&str]) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_tcptalk-server"))
+        .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server(child);
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server did not start listening on port {}", port);
+}
+
+// Opens a socket that gives up on reads after `timeout`, so a missing frame
+// fails the test instead of hanging it
+pub fn tcp_connect(port: u16, timeout: Duration) -> TcpStream {
+    let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    socket
+}
+
+// Splits a connection into the side frames are written to and a reader
+pub fn framed(stream: Stream) -> (Stream, FrameReader<Stream>) {
+    let reader = FrameReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+pub fn connect(port: u16) -> (Stream, FrameReader<Stream>) {
+    framed(Stream::Plain(tcp_connect(port, Duration::from_secs(5))))
+}
+
+// Logs in and returns the resume token
+pub fn login(stream: &Stream, reader: &mut FrameReader<Stream>, username: &str) -> String {
+    let login = Frame::Login {
+        username: username.to_string(),
+        password: None,
+    };
+    write_frame(&mut &*stream, &login).unwrap();
+    match reader.read_frame().unwrap() {
+        Some(Frame::LoginAccepted {
+            username: accepted,
+            resume_token,
+        }) if accepted == username => resume_token,
+        other => panic!("login failed: {:?}", other),
+    }
+}
+
+// Reads frames until one matches, so a test doesn't depend on the order of
+// user lists and other bookkeeping the server sends
+pub fn expect(reader: &mut FrameReader<Stream>, matches: impl Fn(&Frame) -> bool) -> Frame {
+    loop {
+        match reader.read_frame().unwrap() {
+            Some(frame) if matches(&frame) => return frame,
+            Some(_) => {}
+            None => panic!("server closed the connection"),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Runs the server binary and resumes a session after it missed messages in
+// more than one room.
+
+mod common;
+
+use common::{connect, free_port, login, start_server, temp_dir};
+use std::{collections::BTreeMap, fs, net::Shutdown};
+use tcptalk_protocol::{Frame, FrameReader, Stream, write_frame};
+
+// Reads frames until one matches, keeping track of the last message ID seen in
+// each room the way the client does
+fn expect(
+    reader: &mut FrameReader<Stream>,
+    last_ids: &mut BTreeMap<String, u64>,
+    matches: impl Fn(&Frame) -> bool,
+) -> Frame {
+    loop {
+        let frame = match reader.read_frame().unwrap() {
+            Some(frame) => frame,
+            None => panic!("server closed the connection"),
+        };
+        let seen = match &frame {
+            Frame::Chat { id, room, .. }
+            | Frame::Join { id, room, .. }
+            | Frame::Leave { id, room, .. }
+            | Frame::System { id, room, .. } => Some((room.clone(), *id)),
+            Frame::History { room, messages } => messages
+                .iter()
+                .map(|message| message.id)
+                .max()
+                .map(|id| (room.clone(), id)),
+            _ => None,
+        };
+        if let Some((room, id)) = seen {
+            let last_id = last_ids.entry(room).or_default();
+            *last_id = (*last_id).max(id);
+        }
+        if matches(&frame) {
+            return frame;
+        }
+    }
+}
+
+// Sends a numbered message and waits for its echo, so it has been recorded
+fn say(stream: &Stream, reader: &mut FrameReader<Stream>, room: &str, text: &str, client_id: u64) {
+    let say = Frame::Say {
+        room: room.to_string(),
+        text: text.to_string(),
+        client_id: Some(client_id),
+        action: false,
+    };
+    write_frame(&mut &*stream, &say).unwrap();
+    expect(
+        reader,
+        &mut BTreeMap::new(),
+        |frame| matches!(frame, Frame::Chat { client_id: Some(id), .. } if *id == client_id),
+    );
+}
+
+#[test]
+fn resuming_catches_up_each_room_from_its_own_last_message() {
+    let dir = temp_dir("resume-rooms");
+    let port = free_port();
+    let _server = start_server(&dir, port, &[]);
+    let mut last_ids = BTreeMap::new();
+
+    let (alice, mut alice_reader) = connect(port);
+    let token = login(&alice, &mut alice_reader, "alice");
+    let create = Frame::CreateRoom {
+        room: "#side".to_string(),
+    };
+    write_frame(&mut &alice, &create).unwrap();
+    expect(
+        &mut alice_reader,
+        &mut last_ids,
+        |frame| matches!(frame, Frame::RoomJoined { room } if room == "#side"),
+    );
+
+    let (bob, mut bob_reader) = connect(port);
+    login(&bob, &mut bob_reader, "bob");
+    let join = Frame::JoinRoom {
+        room: "#side".to_string(),
+    };
+    write_frame(&mut &bob, &join).unwrap();
+    expect(
+        &mut bob_reader,
+        &mut BTreeMap::new(),
+        |frame| matches!(frame, Frame::RoomJoined { room } if room == "#side"),
+    );
+
+    say(&bob, &mut bob_reader, "#lobby", "lobby 1", 1);
+    say(&bob, &mut bob_reader, "#side", "side 1", 2);
+    let mut ids = Vec::new();
+    for text in ["lobby 1", "side 1"] {
+        let chat = expect(
+            &mut alice_reader,
+            &mut last_ids,
+            |frame| matches!(frame, Frame::Chat { text: said, .. } if said == text),
+        );
+        let Frame::Chat { id, .. } = chat else {
+            unreachable!()
+        };
+        ids.push(id);
+    }
+    // Rooms aren't delivered in order relative to each other, so the lobby
+    // message could still have been on its way when the one in #side arrived
+    assert!(ids[0] < ids[1]);
+    last_ids.insert("#lobby".to_string(), ids[0] - 1);
+
+    // Alice loses her connection and misses a message in each room
+    alice.shutdown(Shutdown::Both).unwrap();
+    say(&bob, &mut bob_reader, "#lobby", "lobby 2", 3);
+    say(&bob, &mut bob_reader, "#side", "side 2", 4);
+
+    let (alice, mut alice_reader) = connect(port);
+    write_frame(&mut &alice, &Frame::Resume { token, last_ids }).unwrap();
+    expect(&mut alice_reader, &mut BTreeMap::new(), |frame| {
+        matches!(frame, Frame::LoginAccepted { .. })
+    });
+
+    let mut replayed = BTreeMap::new();
+    while replayed.len() < 2 {
+        let frame = expect(&mut alice_reader, &mut BTreeMap::new(), |frame| {
+            matches!(frame, Frame::History { .. })
+        });
+        let Frame::History { room, messages } = frame else {
+            unreachable!()
+        };
+        let texts: Vec<String> = messages.into_iter().map(|message| message.text).collect();
+        replayed.insert(room, texts);
+    }
+    assert_eq!(replayed["#lobby"], ["lobby 1", "lobby 2"]);
+    assert_eq!(replayed["#side"], ["side 2"]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
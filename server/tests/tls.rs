@@ -0,0 +1,124 @@
+// Runs the server binary with TLS enabled and talks to it over encrypted
+// connections, using certificates generated for each test.
+
+mod common;
+
+use common::{expect, framed, free_port, login, start_server, tcp_connect, temp_dir};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use std::{fs, path::Path, sync::Arc, time::Duration};
+use tcptalk_protocol::{
+    Frame, FrameReader, Stream, TlsStream,
+    rustls::{
+        ClientConfig, ClientConnection, RootCertStore,
+        pki_types::{CertificateDer, ServerName, pem::PemObject},
+    },
+    stream::fingerprint,
+    write_frame,
+};
+
+// Connects over TLS, trusting only the certificates in `ca`
+fn connect(port: u16, ca: &Path) -> (Stream, FrameReader<Stream>) {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let conn = ClientConnection::new(Arc::new(config), server_name).unwrap();
+
+    let socket = tcp_connect(port, Duration::from_secs(5));
+    framed(Stream::Tls(TlsStream::handshake(socket, conn).unwrap()))
+}
+
+#[test]
+fn chat_over_tls_with_ca_signed_certificate() {
+    let dir = temp_dir("tls-ca");
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&server_key, &ca)
+        .unwrap();
+
+    fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    fs::write(dir.join("tcptalk-cert.pem"), server_cert.pem()).unwrap();
+    fs::write(dir.join("tcptalk-key.pem"), server_key.serialize_pem()).unwrap();
+
+    let port = free_port();
+    let _server = start_server(&dir, port, &["--tls"]);
+
+    let (alice, mut alice_reader) = connect(port, &dir.join("ca.pem"));
+    login(&alice, &mut alice_reader, "alice");
+    expect(&mut alice_reader, |frame| {
+        matches!(frame, Frame::RoomJoined { .. })
+    });
+
+    let (bob, mut bob_reader) = connect(port, &dir.join("ca.pem"));
+    login(&bob, &mut bob_reader, "bob");
+    expect(&mut alice_reader, |frame| {
+        matches!(frame, Frame::Join { .. })
+    });
+
+    let say = Frame::Say {
+        room: "#lobby".to_string(),
+        text: "hello over tls".to_string(),
+        client_id: None,
+        action: false,
+    };
+    write_frame(&mut &bob, &say).unwrap();
+
+    let chat = expect(&mut alice_reader, |frame| {
+        matches!(frame, Frame::Chat { .. })
+    });
+    let Frame::Chat { author, text, .. } = chat else {
+        unreachable!()
+    };
+    assert_eq!(author, "bob");
+    assert_eq!(text, "hello over tls");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn self_signed_certificate_is_generated_on_first_run() {
+    let dir = temp_dir("tls-self-signed");
+
+    let port = free_port();
+    let server = start_server(&dir, port, &["--tls"]);
+
+    let cert_path = dir.join("tcptalk-cert.pem");
+    assert!(cert_path.exists());
+    assert!(dir.join("tcptalk-key.pem").exists());
+
+    // The generated certificate is for "localhost", so it can vouch for itself
+    let (stream, mut reader) = connect(port, &cert_path);
+    let Stream::Tls(tls) = &stream else {
+        unreachable!()
+    };
+    let generated = CertificateDer::from_pem_file(&cert_path).unwrap();
+    assert_eq!(
+        fingerprint(&tls.peer_certificate().unwrap()),
+        fingerprint(&generated)
+    );
+    login(&stream, &mut reader, "alice");
+
+    // A restart reuses the certificate instead of generating a new one
+    drop(server);
+    let _server = start_server(&dir, port, &["--tls"]);
+    let (stream, _reader) = connect(port, &cert_path);
+    let Stream::Tls(tls) = &stream else {
+        unreachable!()
+    };
+    assert_eq!(
+        fingerprint(&tls.peer_certificate().unwrap()),
+        fingerprint(&generated)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
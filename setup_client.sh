@@ -25,7 +25,7 @@ echo "To make this permanent, add this line to your ~/.bashrc or ~/.zshrc:"
 echo "export PATH=\"\$PATH:$SCRIPT_DIR/client\""
 echo ""
 echo "Usage:"
-echo "  tcptalk [username] [ip_address] [-p port]"
+echo "  tcptalk [username] [host] [-p port]"
 echo ""
 echo "Examples:"
 echo "  tcptalk alice                    # Connect to 0.0.0.0:2133"